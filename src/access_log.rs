@@ -0,0 +1,64 @@
+// Access log: one structured line per completed voice request, separate from the debug log
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+static ACCESS_LOG: OnceLock<Mutex<File>> = OnceLock::new();
+
+// Requests recorded since startup, numbering each line
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+// A completed request as it appears in the access log
+pub struct AccessEntry<'a> {
+    pub text: &'a str,
+    // None when the request failed before the cache was checked
+    pub cache_hit: Option<bool>,
+    pub provider: &'a str,
+    // "ok", "placeholder", "draft" or "error:<kind>"
+    pub status: &'a str,
+    pub latency: Duration,
+    pub bytes: Option<u64>,
+}
+
+// Open the access log for appending; until this is called nothing is recorded
+pub fn init(path: &Path) -> Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .context(format!("Failed to open access log {}", path.display()))?;
+    let _ = ACCESS_LOG.set(Mutex::new(file));
+    Ok(())
+}
+
+// Append one line for a completed request
+pub fn record(entry: &AccessEntry) {
+    let Some(log) = ACCESS_LOG.get() else {
+        return;
+    };
+
+    let cache = match entry.cache_hit {
+        Some(true) => "hit",
+        Some(false) => "miss",
+        None => "-",
+    };
+    let bytes = entry.bytes.map_or_else(|| "-".to_string(), |bytes| bytes.to_string());
+    let line = format!(
+        "[{}] id={} text_len={} cache={} provider={} status={} latency_ms={} bytes={}\n",
+        chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+        NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+        entry.text.chars().count(),
+        cache,
+        entry.provider,
+        entry.status,
+        entry.latency.as_millis(),
+        bytes,
+    );
+
+    let mut file = log.lock().unwrap();
+    let _ = file.write_all(line.as_bytes());
+}
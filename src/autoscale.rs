@@ -0,0 +1,71 @@
+// Concurrency auto-tuning: grow or shrink the generation semaphore based on recent latency
+use crate::common::{log_message, GeneralConfig};
+use crate::stats;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+// How often the controller looks at the latencies recorded since its last check
+const ADJUST_INTERVAL: Duration = Duration::from_secs(10);
+
+// Start the controller, which adjusts `semaphore` (currently holding `initial` permits)
+// by one permit per interval within autoscale_min/max_concurrency
+pub fn spawn(semaphore: Arc<Semaphore>, initial: usize, config: &GeneralConfig) {
+    let target_ms = config.autoscale_target_latency_ms;
+    let min = config.autoscale_min_concurrency.max(1);
+    let max = config.autoscale_max_concurrency.max(min);
+
+    log_message(&format!(
+        "Autoscaling concurrency between {} and {} with a p90 latency target of {}ms",
+        min, max, target_ms
+    ));
+
+    tokio::spawn(async move {
+        let mut limit = initial;
+        let mut seen = stats::STATS.latencies_recorded();
+        let mut interval = tokio::time::interval(ADJUST_INTERVAL);
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            // Only generations finished since the last check say anything about the current limit
+            let recorded = stats::STATS.latencies_recorded();
+            let new_samples = (recorded - seen) as usize;
+            seen = recorded;
+            let Some(p90) = stats::STATS.recent_latency_p90(new_samples) else {
+                continue;
+            };
+
+            if p90 > target_ms && limit > min {
+                limit -= 1;
+                shrink(&semaphore);
+                log_message(&format!(
+                    "Autoscale: p90 latency {}ms over target, concurrency lowered to {}",
+                    p90, limit
+                ));
+            } else if p90 < target_ms && limit < max && semaphore.available_permits() == 0 {
+                // Raising the limit only helps when every permit is in use
+                limit += 1;
+                semaphore.add_permits(1);
+                log_message(&format!(
+                    "Autoscale: p90 latency {}ms under target, concurrency raised to {}",
+                    p90, limit
+                ));
+            }
+        }
+    });
+}
+
+// Take one permit out of circulation, waiting for a running generation to return it if none is free
+fn shrink(semaphore: &Arc<Semaphore>) {
+    if semaphore.forget_permits(1) == 1 {
+        return;
+    }
+    let semaphore = semaphore.clone();
+    tokio::spawn(async move {
+        if let Ok(permit) = semaphore.acquire_owned().await {
+            permit.forget();
+        }
+    });
+}
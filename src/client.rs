@@ -1,30 +1,34 @@
 use anyhow::{Context, Result};
-use clap::Parser;
-use config::{Config, File as ConfigFile};
-use std::path::PathBuf;
+use base64::Engine;
+use clap::{ArgGroup, Parser};
+use futures_util::stream::{self, StreamExt};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use tokio::fs;
-use tokio::net::TcpStream;
-use tokio::io::AsyncWriteExt;
-use md5;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
 // Import only what we need
 #[path = "common.rs"]
 mod common_mod;
 use common_mod::{
-    log_message, init_logger,
-    GeneralConfig, VoiceRequest, RequestType
+    log_message, set_log_level, init_logger, flush_logger, disable_console_logging, find_cached_file, first_writable_dir, generate_idempotency_key,
+    build_config, load_general_config, connect_to_server, generate_cache_filename, generate_ref_cache_filename, variant_cache_text, read_text_list,
+    load_cache_index, CACHE_INDEX_FILENAME, Connection, ErrorKind, GeneralConfig, VoiceRequest, VoiceResponse, RequestType, StreamFrame, WireFormat
 };
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(group(ArgGroup::new("report").args(["diff_cache", "list_cache"])))]
 struct Args {
     /// Text to be converted to speech
-    #[arg(short, long)]
-    text: String,
+    #[arg(short, long, required_unless_present_any = ["warm", "prewarm", "reload_config", "diff_cache", "status", "list_cache"])]
+    text: Option<String>,
 
     /// Output WAV file path
-    #[arg(short, long)]
-    output: PathBuf,
+    #[arg(short, long, required_unless_present_any = ["warm", "evict", "cancel", "prewarm", "reload_config", "print_cache_path", "diff_cache", "status", "list_cache"])]
+    output: Option<PathBuf>,
 
     /// Cache directory for pre-generated voices (can also be set in config)
     #[arg(short = 'c', long)]
@@ -37,23 +41,153 @@ struct Args {
     /// Log file path (can also be set in config)
     #[arg(short = 'g', long)]
     log: Option<PathBuf>,
+
+    /// Host name or IP address of the server, when it runs on another machine (can also be set in config as server_host)
+    #[arg(long)]
+    host: Option<String>,
+
+    /// Reference audio clip to send with the request instead of the configured one
+    #[arg(long)]
+    ref_file: Option<PathBuf>,
+
+    /// Text that may be spoken next, prefetched by the server (repeat for each branch)
+    #[arg(long = "next-text")]
+    next_texts: Vec<String>,
+
+    /// Exit without contacting the server if the output file already holds audio
+    #[arg(long)]
+    skip_if_output_exists: bool,
+
+    /// Wait for the server to generate the voice, then copy it to the output
+    #[arg(long)]
+    wait: bool,
+
+    /// Without --wait, read the server's acknowledgment to confirm it accepted the request
+    #[arg(long)]
+    ack: bool,
+
+    /// On a cache miss, write the voice to the output as the server generates it
+    #[arg(long, conflicts_with_all = ["wait", "ack", "evict"])]
+    stream: bool,
+
+    /// Named [[profiles]] parameter set to generate with
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Character voice from [voices.<id>] to speak the line with
+    #[arg(long = "voice")]
+    voice_id: Option<String>,
+
+    /// Ask GPT-SoVITS for streaming output, overriding tts.streaming_mode
+    #[arg(long, conflicts_with = "no_streaming")]
+    streaming: bool,
+
+    /// Ask GPT-SoVITS for non-streaming output, overriding tts.streaming_mode
+    #[arg(long)]
+    no_streaming: bool,
+
+    /// Delete the cached voice for --text instead of generating it
+    #[arg(long, conflicts_with_all = ["output", "wait"])]
+    evict: bool,
+
+    /// Stop the server's in-progress generation for --text, e.g. when the player skipped the line
+    #[arg(long, conflicts_with_all = ["output", "wait", "evict", "stream"])]
+    cancel: bool,
+
+    /// Generate every missing line of this text list, blocking until done
+    #[arg(long, conflicts_with_all = ["text", "output"])]
+    warm: Option<PathBuf>,
+
+    /// Give up warming after this many seconds
+    #[arg(long, requires = "warm")]
+    timeout: Option<u64>,
+
+    /// Make the server load its TTS model now, so the first line isn't slow
+    #[arg(long, conflicts_with_all = ["text", "output", "warm", "evict"])]
+    prewarm: bool,
+
+    /// Make the server re-read its config file and rebuild its provider, like SIGHUP on Unix
+    #[arg(long, conflicts_with_all = ["text", "output", "warm", "prewarm", "evict"])]
+    reload_config: bool,
+
+    /// Print what the server is doing (in-progress generations, loaded text lists, concurrency) as JSON and exit;
+    /// with --text, first_missing_index counts from that line instead of the top of the text list
+    #[arg(long, conflicts_with_all = ["output", "warm", "prewarm", "evict"])]
+    status: bool,
+
+    /// Print where the voice for --text is (or would be) cached and exit, without contacting the server
+    #[arg(long, conflicts_with_all = ["output", "wait", "evict", "warm", "prewarm"])]
+    print_cache_path: bool,
+
+    /// Compare the voices two cache directories hold for each line of a text list
+    #[arg(long, num_args = 2, value_names = ["DIR_A", "DIR_B"], conflicts_with_all = ["text", "output", "warm", "prewarm"])]
+    diff_cache: Option<Vec<PathBuf>>,
+
+    /// Print which text each cached voice says, from the cache directories' cache_index.json, and exit
+    #[arg(long, conflicts_with_all = ["text", "output", "warm", "prewarm", "status", "diff_cache"])]
+    list_cache: bool,
+
+    /// Text list for --diff-cache (defaults to the configured text_list_path)
+    #[arg(long, requires = "diff_cache")]
+    text_list: Option<PathBuf>,
+
+    /// Print the --diff-cache or --list-cache report as JSON instead of a table
+    #[arg(long, requires = "report")]
+    json: bool,
+}
+
+impl Args {
+    // The streaming_mode override requested on the command line, if any
+    fn streaming(&self) -> Option<bool> {
+        match (self.streaming, self.no_streaming) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        }
+    }
+}
+
+// How a single line of a warmed text list ended up
+enum WarmOutcome {
+    Cached,
+    Generated,
+    Failed,
 }
 
+// Anything at or below the size of a bare WAV header holds no audio
+const MIN_OUTPUT_BYTES: u64 = 44;
+
+// Wait before the first retry of a failed copy to the output, doubled for each further retry
+const DELIVERY_RETRY_DELAY: Duration = Duration::from_millis(100);
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    let result = run().await;
+    
+    // Lines still queued for the log file would be lost on exit
+    flush_logger();
+    result
+}
+
+async fn run() -> Result<()> {
     // Parse command line arguments
     let args = Args::parse();
     
+    // The printed path or report must be the only thing on stdout
+    if args.print_cache_path || args.diff_cache.is_some() || args.status || args.list_cache {
+        disable_console_logging();
+    }
+    
     // Load configuration
-    let config = Config::builder()
-        .add_source(ConfigFile::from(args.config.clone()))
-        .build()
-        .context("Failed to load configuration")?;
+    let config = build_config(&args.config)?;
 
     // Read general configuration
-    let general_config: GeneralConfig = config
-        .get("general")
-        .context("Failed to parse general configuration")?;
+    let mut general_config = load_general_config(&config)?;
+    if let Some(host) = &args.host {
+        general_config.server_host = host.clone();
+    }
+    general_config.validate()?;
+    set_log_level(general_config.log_level);
 
     // Set up logger if specified
     let log_path = args.log.clone().or_else(|| {
@@ -65,87 +199,772 @@ async fn main() -> Result<()> {
     });
     
     if let Some(log_path) = &log_path {
-        init_logger(log_path)?;
+        init_logger(log_path, general_config.max_log_bytes, general_config.log_keep_files)?;
     }
     
     log_message("Starting krkr-tts client");
     
-    // Use cache directory from config if not specified
-    let cache_dir = args.cache_dir.clone().or_else(|| {
-        if !general_config.cache_dir.is_empty() {
-            Some(PathBuf::from(&general_config.cache_dir))
-        } else {
-            None
-        }
-    });
+    // Use cache directories from config if not specified
+    let cache_dirs = match &args.cache_dir {
+        Some(dir) => vec![dir.clone()],
+        None => general_config.cache_dir.paths(),
+    };
     
-    // If cache dir is specified, check for existing voice file
-    if let Some(cache_dir) = &cache_dir {
-        // Create a unique filename based on the text content
-        let text_hash = format!("{:x}", md5::compute(&args.text));
-        let voice_filename = format!("{}.wav", text_hash);
-        let cached_path = cache_dir.join(&voice_filename);
-        
-        // If voice exists in cache, copy it
-        if cached_path.exists() {
-            log_message(&format!("Found cached voice at {}", cached_path.display()));
-            
-            // Create output directory if it doesn't exist
-            if let Some(parent) = args.output.parent() {
-                fs::create_dir_all(parent)
-                    .await
-                    .context("Failed to create output directory")?;
-            }
-            
-            // Copy the cached file to the output location
-            fs::copy(&cached_path, &args.output)
+    // Warming a whole text list replaces the single-line flow
+    if let Some(text_list_path) = &args.warm {
+        return warm_text_list(text_list_path, &args, &general_config, &cache_dirs).await;
+    }
+    
+    if args.prewarm {
+        return prewarm_provider(&args, &general_config).await;
+    }
+    
+    if args.reload_config {
+        return reload_server_config(&args, &general_config).await;
+    }
+    
+    if args.status {
+        return print_server_status(&args, &general_config).await;
+    }
+    
+    if let Some(dirs) = &args.diff_cache {
+        return diff_caches(&dirs[0], &dirs[1], &args, &general_config).await;
+    }
+    
+    if args.list_cache {
+        return list_cache(&cache_dirs, args.json).await;
+    }
+    
+    // clap requires --text unless --warm, --prewarm or --reload-config is given
+    let text = args.text.clone().context("--text is required")?;
+    
+    // Eviction and cancelling go straight to the server, they need no output
+    if args.evict {
+        return evict_cached_voice(text, &args, &general_config).await;
+    }
+    if args.cancel {
+        return cancel_generation(text, &args, &general_config).await;
+    }
+    
+    if args.print_cache_path {
+        return print_cache_path(&text, &args, &general_config, &cache_dirs).await;
+    }
+    
+    // clap requires --output unless --warm, --evict, --cancel, --prewarm or --print-cache-path is given
+    let output = args.output.clone().context("--output is required")?;
+    
+    // An output left over from a previous run needs neither the cache nor the server
+    if args.skip_if_output_exists
+        && let Ok(metadata) = fs::metadata(&output).await
+        && metadata.len() > MIN_OUTPUT_BYTES
+    {
+        log_message(&format!("Output already exists, skipping: {}", output.display()));
+        return Ok(());
+    }
+    
+    // Read the reference clip to attach, if any
+    let ref_audio = match &args.ref_file {
+        Some(ref_file) => Some(
+            fs::read(ref_file)
                 .await
-                .context("Failed to copy cached voice file")?;
-            
+                .context("Failed to read reference audio file")?,
+        ),
+        None => None,
+    };
+    
+    // Check the cache directories in order for an existing voice file
+    let streaming = args.streaming();
+    let voice_filename = voice_cache_filename(&text, &args, &general_config, ref_audio.as_deref());
+    
+    // If voice exists in cache, copy it
+    let cache_hit = match find_cached_file(&cache_dirs, &voice_filename) {
+        Some(cached_path) => {
+            log_message(&format!("Found cached voice at {}", cached_path.display()));
+            copy_to_output(&cached_path, &output, &general_config).await?;
             log_message("Voice file copied from cache");
+            true
         }
-    }
+        None => false,
+    };
+    
+    // Create request, waiting only when the voice still has to be generated
+    let mut request = VoiceRequest {
+        request_type: RequestType::GenerateVoice,
+        text,
+        output_path: output.clone(),
+        cache_dir: args.cache_dir,
+        config_path: args.config,
+        idempotency_key: None,
+        ref_audio_base64: ref_audio
+            .map(|ref_audio| base64::engine::general_purpose::STANDARD.encode(ref_audio)),
+        next_texts: args.next_texts,
+        wait: args.wait && !cache_hit,
+        profile: args.profile,
+        streaming,
+        voice_id: args.voice_id,
+        stream: args.stream && !cache_hit,
+    };
+    request.idempotency_key = Some(generate_idempotency_key(&request));
     
     log_message("Sending generation request to server");
     
-    // Send generation request to server
-    send_generation_request(
-        &general_config.server_port,
-        args.text,
-        args.output,
-        cache_dir,
-        args.config,
-    ).await?;
+    if request.stream {
+        return receive_stream(&general_config, &request, &output).await;
+    }
     
-    log_message("Generation request sent to server");
+    // Send generation request to server
+    match send_request(&general_config, &request, args.ack).await? {
+        // An acknowledgment only says where the voice will be, it isn't there yet
+        Some(response) if response.success && !request.wait => {
+            let cache_path = response.cache_path.unwrap_or_default();
+            log_message(&format!("Server {} the request, voice will be at {}", response.message, cache_path.display()));
+        }
+        Some(response) if response.success => {
+            let cache_path = response.cache_path
+                .context("Server response is missing the cache path")?;
+            copy_to_output(&cache_path, &output, &general_config).await?;
+            log_message(&format!("Voice file copied from {}", cache_path.display()));
+        }
+        Some(response) => {
+            let kind = response.error_kind.unwrap_or(ErrorKind::Other);
+            anyhow::bail!("Server failed to generate voice ({}): {}", kind, response.message);
+        }
+        None => {
+            log_message("Generation request sent to server");
+        }
+    }
     
     Ok(())
 }
 
-// Function to send a voice generation request to the server
-async fn send_generation_request(
-    server_port: &u16,
-    text: String,
-    output_path: PathBuf,
-    cache_dir: Option<PathBuf>,
-    config_path: PathBuf,
+// Ask the server to generate every line of a text list missing from the cache,
+// a few at a time, and report how many were cached, generated or failed
+async fn warm_text_list(
+    text_list_path: &Path,
+    args: &Args,
+    general_config: &GeneralConfig,
+    cache_dirs: &[PathBuf],
 ) -> Result<()> {
-    // Create request
+    let content = read_text_list(text_list_path, &general_config.text_list_encoding).await?;
+    
+    // Lines that normalize to the same speech share one cache file
+    let mut seen = HashSet::new();
+    let lines: Vec<String> = content
+        .iter()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .filter(|line| seen.insert(warm_cache_filename(line, args, general_config)))
+        .map(str::to_string)
+        .collect();
+    let total = lines.len();
+    
+    log_message(&format!("Warming {} lines from {}", total, text_list_path.display()));
+    
+    let done = AtomicUsize::new(0);
+    let warm_all = stream::iter(lines)
+        .map(|text| {
+            let done = &done;
+            async move {
+                let outcome = warm_line(&text, args, general_config, cache_dirs).await;
+                let position = done.fetch_add(1, Ordering::Relaxed) + 1;
+                if let WarmOutcome::Failed = outcome {
+                    log_message(&format!("[{}/{}] Failed: {}", position, total, text));
+                } else if position.is_multiple_of(50) || position == total {
+                    log_message(&format!("[{}/{}] Warmed", position, total));
+                }
+                outcome
+            }
+        })
+        .buffer_unordered(general_config.max_concurrent_tts.max(1))
+        .fold((0, 0, 0), |(cached, generated, failed), outcome| async move {
+            match outcome {
+                WarmOutcome::Cached => (cached + 1, generated, failed),
+                WarmOutcome::Generated => (cached, generated + 1, failed),
+                WarmOutcome::Failed => (cached, generated, failed + 1),
+            }
+        });
+    
+    let (cached, generated, failed) = match args.timeout {
+        Some(secs) => tokio::time::timeout(Duration::from_secs(secs), warm_all)
+            .await
+            .map_err(|_| anyhow::anyhow!(
+                "Timed out after {}s with {} of {} lines warmed",
+                secs, done.load(Ordering::Relaxed), total
+            ))?,
+        None => warm_all.await,
+    };
+    
+    log_message(&format!(
+        "Warming finished: {} cached, {} generated, {} failed",
+        cached, generated, failed
+    ));
+    
+    if failed > 0 {
+        anyhow::bail!("{} of {} lines failed to generate", failed, total);
+    }
+    Ok(())
+}
+
+// Cache filename of a line under the requested profile, streaming mode, voice and reference clip,
+// hashing the same normalized text the server generates from
+fn voice_cache_filename(text: &str, args: &Args, general_config: &GeneralConfig, ref_audio: Option<&[u8]>) -> String {
+    let speech_text = general_config.text_pipeline.apply(text);
+    let key_text = variant_cache_text(
+        &speech_text,
+        args.profile.as_deref(),
+        args.streaming(),
+        args.voice_id.as_deref(),
+        general_config,
+    );
+    match ref_audio {
+        Some(ref_audio) => generate_ref_cache_filename(&key_text, ref_audio, general_config),
+        None => generate_cache_filename(&key_text, general_config),
+    }
+}
+
+// Cache filename of a warmed line, which never carries a reference clip
+fn warm_cache_filename(text: &str, args: &Args, general_config: &GeneralConfig) -> String {
+    voice_cache_filename(text, args, general_config, None)
+}
+
+// Print the full path of the cached voice for a line: the existing file if any directory holds it,
+// otherwise where the server would write it
+async fn print_cache_path(text: &str, args: &Args, general_config: &GeneralConfig, cache_dirs: &[PathBuf]) -> Result<()> {
+    let ref_audio = match &args.ref_file {
+        Some(ref_file) => Some(
+            fs::read(ref_file)
+                .await
+                .context("Failed to read reference audio file")?,
+        ),
+        None => None,
+    };
+    let voice_filename = voice_cache_filename(text, args, general_config, ref_audio.as_deref());
+    
+    let path = match find_cached_file(cache_dirs, &voice_filename) {
+        Some(cached_path) => cached_path,
+        None => first_writable_dir(cache_dirs)
+            .or_else(|| cache_dirs.first().cloned())
+            .context("No cache directory specified")?
+            .join(&voice_filename),
+    };
+    println!("{}", path.display());
+    Ok(())
+}
+
+// How a line's voice compares between two cache directories
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CacheDiff {
+    Same,
+    Differs,
+    OnlyA,
+    OnlyB,
+    Missing,
+}
+
+impl CacheDiff {
+    fn label(&self) -> &'static str {
+        match self {
+            CacheDiff::Same => "same",
+            CacheDiff::Differs => "differs",
+            CacheDiff::OnlyA => "only_a",
+            CacheDiff::OnlyB => "only_b",
+            CacheDiff::Missing => "missing",
+        }
+    }
+}
+
+// Report for each line of a text list whether two caches hold the same voice for it,
+// comparing sizes first and contents only when the sizes match
+async fn diff_caches(dir_a: &Path, dir_b: &Path, args: &Args, general_config: &GeneralConfig) -> Result<()> {
+    let text_list_path = match &args.text_list {
+        Some(path) => path.clone(),
+        None if !general_config.text_list_path.is_empty() => PathBuf::from(&general_config.text_list_path),
+        None => anyhow::bail!("--diff-cache needs --text-list or a configured text_list_path"),
+    };
+    let content = read_text_list(&text_list_path, &general_config.text_list_encoding).await?;
+    
+    let mut seen = HashSet::new();
+    let mut rows = Vec::new();
+    for (index, line) in content.iter().enumerate() {
+        let text = line.trim();
+        let voice_filename = warm_cache_filename(text, args, general_config);
+        if text.is_empty() || !seen.insert(voice_filename.clone()) {
+            continue;
+        }
+        
+        let a = fs::read(dir_a.join(&voice_filename)).await.ok();
+        let b = fs::read(dir_b.join(&voice_filename)).await.ok();
+        let diff = match (&a, &b) {
+            (Some(a), Some(b)) if a.len() == b.len() && md5::compute(a) == md5::compute(b) => CacheDiff::Same,
+            (Some(_), Some(_)) => CacheDiff::Differs,
+            (Some(_), None) => CacheDiff::OnlyA,
+            (None, Some(_)) => CacheDiff::OnlyB,
+            (None, None) => CacheDiff::Missing,
+        };
+        let size = |voice: &Option<Vec<u8>>| voice.as_ref().map(|voice| voice.len());
+        rows.push((index + 1, text.to_string(), diff, size(&a), size(&b)));
+    }
+    
+    if args.json {
+        let report: Vec<_> = rows
+            .iter()
+            .map(|(line, text, diff, size_a, size_b)| serde_json::json!({
+                "line": line,
+                "text": text,
+                "status": diff.label(),
+                "size_a": size_a,
+                "size_b": size_b,
+            }))
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    
+    let size = |size: &Option<usize>| size.map_or_else(|| "-".to_string(), |size| size.to_string());
+    println!("{:>6}  {:<8}  {:>10}  {:>10}  text", "line", "status", "size_a", "size_b");
+    for (line, text, diff, size_a, size_b) in &rows {
+        println!("{:>6}  {:<8}  {:>10}  {:>10}  {}", line, diff.label(), size(size_a), size(size_b), text);
+    }
+    let count = |kind: CacheDiff| rows.iter().filter(|row| row.2 == kind).count();
+    println!(
+        "{} lines: {} same, {} differ, {} only in A, {} only in B, {} in neither",
+        rows.len(),
+        count(CacheDiff::Same),
+        count(CacheDiff::Differs),
+        count(CacheDiff::OnlyA),
+        count(CacheDiff::OnlyB),
+        count(CacheDiff::Missing),
+    );
+    Ok(())
+}
+
+// Print the text behind each voice recorded in the cache directories' indexes,
+// leaving out entries whose file has since been deleted by hand
+async fn list_cache(cache_dirs: &[PathBuf], json: bool) -> Result<()> {
+    let mut rows = Vec::new();
+    for cache_dir in cache_dirs {
+        let index = load_cache_index(cache_dir).await?;
+        for (filename, entry) in index {
+            if cache_dir.join(&filename).is_file() {
+                rows.push((cache_dir.clone(), filename, entry));
+            }
+        }
+    }
+    
+    if json {
+        let report: Vec<_> = rows
+            .iter()
+            .map(|(cache_dir, filename, entry)| serde_json::json!({
+                "cache_dir": cache_dir,
+                "file": filename,
+                "text": entry.text,
+                "voice_id": entry.voice_id,
+                "profile": entry.profile,
+                "params_hash": entry.params_hash,
+            }))
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    
+    println!("{:<40}  {:<12}  {:<12}  text", "file", "voice", "profile");
+    for (_, filename, entry) in &rows {
+        println!(
+            "{:<40}  {:<12}  {:<12}  {}",
+            filename,
+            entry.voice_id.as_deref().unwrap_or("-"),
+            entry.profile.as_deref().unwrap_or("-"),
+            entry.text,
+        );
+    }
+    println!(
+        "{} voices indexed in {} cache directories ({})",
+        rows.len(),
+        cache_dirs.len(),
+        CACHE_INDEX_FILENAME,
+    );
+    Ok(())
+}
+
+// Generate one text list line on the server unless it is already cached
+async fn warm_line(
+    text: &str,
+    args: &Args,
+    general_config: &GeneralConfig,
+    cache_dirs: &[PathBuf],
+) -> WarmOutcome {
+    let voice_filename = warm_cache_filename(text, args, general_config);
+    if find_cached_file(cache_dirs, &voice_filename).is_some() {
+        return WarmOutcome::Cached;
+    }
+    
     let request = VoiceRequest {
         request_type: RequestType::GenerateVoice,
-        text: text.clone(),
-        output_path: output_path.clone(),
-        cache_dir: cache_dir.clone(),
-        config_path,
+        text: text.to_string(),
+        output_path: PathBuf::new(),
+        cache_dir: args.cache_dir.clone(),
+        config_path: args.config.clone(),
+        idempotency_key: None,
+        ref_audio_base64: None,
+        next_texts: Vec::new(),
+        wait: true,
+        profile: args.profile.clone(),
+        streaming: args.streaming(),
+        voice_id: args.voice_id.clone(),
+        stream: false,
+    };
+    
+    match send_request(general_config, &request, false).await {
+        // Placeholders aren't cache hits, so they still count as failures
+        Ok(Some(response)) if response.success && response.message != "placeholder" => WarmOutcome::Generated,
+        Ok(Some(response)) => {
+            let kind = response.error_kind.unwrap_or(ErrorKind::Other);
+            log_message(&format!("Server failed to generate voice ({}): {}", kind, response.message));
+            WarmOutcome::Failed
+        }
+        Ok(None) => WarmOutcome::Failed,
+        Err(e) => {
+            log_message(&format!("Error sending request: {}", e));
+            WarmOutcome::Failed
+        }
+    }
+}
+
+// Ask the server to delete the cached voice for a text
+async fn evict_cached_voice(text: String, args: &Args, general_config: &GeneralConfig) -> Result<()> {
+    let request = VoiceRequest {
+        request_type: RequestType::EvictCache { text: text.clone() },
+        text,
+        output_path: PathBuf::new(),
+        cache_dir: args.cache_dir.clone(),
+        config_path: args.config.clone(),
+        idempotency_key: None,
+        ref_audio_base64: None,
+        next_texts: Vec::new(),
+        // The server always replies to evictions
+        wait: true,
+        profile: args.profile.clone(),
+        streaming: args.streaming(),
+        voice_id: args.voice_id.clone(),
+        stream: false,
+    };
+    
+    let response = send_request(general_config, &request, false)
+        .await?
+        .context("Server did not reply to the eviction")?;
+    
+    match response.cache_path {
+        Some(cache_path) if response.success => {
+            log_message(&format!("{}, including {}", response.message, cache_path.display()));
+        }
+        _ => log_message(&format!("Nothing evicted: {}", response.message)),
+    }
+    Ok(())
+}
+
+// Ask the server to stop generating the voice for a text, if it still is
+async fn cancel_generation(text: String, args: &Args, general_config: &GeneralConfig) -> Result<()> {
+    let request = VoiceRequest {
+        request_type: RequestType::Cancel { text: text.clone() },
+        text,
+        output_path: PathBuf::new(),
+        cache_dir: args.cache_dir.clone(),
+        config_path: args.config.clone(),
+        idempotency_key: None,
+        ref_audio_base64: None,
+        next_texts: Vec::new(),
+        // The server always replies to cancellations
+        wait: true,
+        profile: args.profile.clone(),
+        streaming: args.streaming(),
+        voice_id: args.voice_id.clone(),
+        stream: false,
+    };
+    
+    let response = send_request(general_config, &request, false)
+        .await?
+        .context("Server did not reply to the cancellation")?;
+    
+    if response.success {
+        log_message("Cancelled the in-progress generation");
+    } else {
+        log_message(&format!("Nothing cancelled: {}", response.message));
+    }
+    Ok(())
+}
+
+// Ask the server to load its TTS model, waiting until it has
+async fn prewarm_provider(args: &Args, general_config: &GeneralConfig) -> Result<()> {
+    let request = VoiceRequest {
+        request_type: RequestType::PrewarmProvider,
+        text: String::new(),
+        output_path: PathBuf::new(),
+        cache_dir: None,
+        config_path: args.config.clone(),
+        idempotency_key: None,
+        ref_audio_base64: None,
+        next_texts: Vec::new(),
+        // The server always replies to prewarming
+        wait: true,
+        profile: None,
+        streaming: None,
+        voice_id: None,
+        stream: false,
+    };
+    
+    log_message("Asking the server to prewarm its provider");
+    let response = send_request(general_config, &request, false)
+        .await?
+        .context("Server did not reply to the prewarm request")?;
+    
+    if !response.success {
+        let kind = response.error_kind.unwrap_or(ErrorKind::Other);
+        anyhow::bail!("Server failed to prewarm ({}): {}", kind, response.message);
+    }
+    log_message(&response.message);
+    Ok(())
+}
+
+// Ask the server to re-read its own config file, waiting until it has
+async fn reload_server_config(args: &Args, general_config: &GeneralConfig) -> Result<()> {
+    let request = VoiceRequest {
+        request_type: RequestType::ReloadConfig,
+        text: String::new(),
+        output_path: PathBuf::new(),
+        cache_dir: None,
+        config_path: args.config.clone(),
+        idempotency_key: None,
+        ref_audio_base64: None,
+        next_texts: Vec::new(),
+        // The server always replies to reload requests
+        wait: true,
+        profile: None,
+        streaming: None,
+        voice_id: None,
+        stream: false,
     };
     
-    // Connect to server using TCP
-    let mut conn = TcpStream::connect(format!("127.0.0.1:{}", server_port))
+    let response = send_request(general_config, &request, false)
+        .await?
+        .context("Server did not reply to the reload request")?;
+    
+    if !response.success {
+        anyhow::bail!("Server failed to reload its configuration: {}", response.message);
+    }
+    log_message(&response.message);
+    Ok(())
+}
+
+// Ask the server what it is doing and print its reply as pretty JSON
+async fn print_server_status(args: &Args, general_config: &GeneralConfig) -> Result<()> {
+    let request = VoiceRequest {
+        request_type: RequestType::QueryStatus,
+        text: args.text.clone().unwrap_or_default(),
+        output_path: PathBuf::new(),
+        cache_dir: args.cache_dir.clone(),
+        config_path: args.config.clone(),
+        idempotency_key: None,
+        ref_audio_base64: None,
+        next_texts: Vec::new(),
+        // The server always replies to status queries
+        wait: true,
+        profile: args.profile.clone(),
+        streaming: args.streaming(),
+        voice_id: args.voice_id.clone(),
+        stream: false,
+    };
+    
+    let response = send_request(general_config, &request, false)
+        .await?
+        .context("Server did not reply to the status query")?;
+    if !response.success {
+        anyhow::bail!("Server failed to report its status: {}", response.message);
+    }
+    let status: serde_json::Value = serde_json::from_str(&response.message)
+        .context("Server sent a malformed status")?;
+    println!("{}", serde_json::to_string_pretty(&status)?);
+    Ok(())
+}
+
+// Copy a voice file to the output location, creating its directory if needed,
+// and with fsync_output flushing it to disk before the game goes looking for it
+async fn copy_to_output(voice_path: &Path, output_path: &Path, general_config: &GeneralConfig) -> Result<()> {
+    if let Some(parent) = output_path.parent() {
+        fs::create_dir_all(parent)
+            .await
+            .context("Failed to create output directory")?;
+    }
+    
+    // Copying a file onto itself would truncate it, and the output already is the voice
+    if is_same_file(voice_path, output_path).await {
+        log_message(&format!("Output is the cached voice itself, skipping copy: {}", output_path.display()));
+        return Ok(());
+    }
+    
+    // Files locked by scanners on Windows usually free up within moments
+    let mut delay = DELIVERY_RETRY_DELAY;
+    let mut attempt = 0;
+    while let Err(e) = fs::copy(voice_path, output_path).await {
+        if attempt >= general_config.delivery_copy_retries {
+            return Err(anyhow::Error::new(e).context(format!(
+                "Failed to copy voice file after {} attempts",
+                attempt + 1
+            )));
+        }
+        attempt += 1;
+        log_message(&format!(
+            "Copy to {} failed ({}), retrying in {}ms",
+            output_path.display(), e, delay.as_millis()
+        ));
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+    
+    if general_config.fsync_output {
+        fs::File::open(output_path)
+            .await
+            .context("Failed to open output for syncing")?
+            .sync_all()
+            .await
+            .context("Failed to sync output file")?;
+    }
+    Ok(())
+}
+
+// Check whether two existing paths resolve to the same file
+async fn is_same_file(voice_path: &Path, output_path: &Path) -> bool {
+    match (fs::canonicalize(voice_path).await, fs::canonicalize(output_path).await) {
+        (Ok(voice_path), Ok(output_path)) => voice_path == output_path,
+        _ => false,
+    }
+}
+
+// Function to send a request to the server, returning its response if one is expected:
+// the result when waiting, or with `ack` the acknowledgment the server sends before generating
+async fn send_request(general_config: &GeneralConfig, request: &VoiceRequest, ack: bool) -> Result<Option<VoiceResponse>> {
+    let (mut conn, wire_format) = open_request(general_config, request).await?;
+    
+    if request.wait {
+        log_message("Waiting for the server to generate the voice");
+    } else if ack {
+        log_message("Waiting for the server to accept the request");
+    } else {
+        // Done - request sent, client can exit immediately
+        log_message("Request sent to server, exiting");
+        return Ok(None);
+    }
+    
+    // Read the length-prefixed response, giving up after wait_timeout_ms (0 waits forever)
+    let read_response = async {
+        let response_data = read_message(&mut conn).await?;
+        wire_format.decode(&response_data)
+            .context("Failed to deserialize response")
+    };
+    if general_config.wait_timeout_ms == 0 {
+        return read_response.await.map(Some);
+    }
+    
+    let wait_timeout = Duration::from_millis(general_config.wait_timeout_ms);
+    match tokio::time::timeout(wait_timeout, read_response).await {
+        Ok(response) => response.map(Some),
+        // The generation may still finish on the server, this client just stops waiting for it
+        Err(_) => Ok(Some(VoiceResponse {
+            success: false,
+            message: format!("No reply from the server within {}ms", general_config.wait_timeout_ms),
+            cache_path: None,
+            error_kind: Some(ErrorKind::Timeout),
+        })),
+    }
+}
+
+// Write the voice to the output as the server streams it, starting over when it says so.
+// wait_timeout_ms limits the wait for each frame rather than the whole voice; on any
+// failure the output is removed so the game never plays a fragment
+async fn receive_stream(general_config: &GeneralConfig, request: &VoiceRequest, output: &Path) -> Result<()> {
+    let (mut conn, wire_format) = open_request(general_config, request).await?;
+    log_message("Streaming the voice from the server");
+    
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)
+            .await
+            .context("Failed to create output directory")?;
+    }
+    let mut file = fs::File::create(output)
         .await
-        .context("Failed to connect to TTS server. Make sure the server is running.")?;
+        .context("Failed to create output file")?;
+    
+    let mut received = 0;
+    let result = loop {
+        let frame = async {
+            let frame_data = read_message(&mut conn).await?;
+            StreamFrame::decode(&frame_data, wire_format)
+        };
+        let frame = if general_config.wait_timeout_ms == 0 {
+            frame.await
+        } else {
+            let wait_timeout = Duration::from_millis(general_config.wait_timeout_ms);
+            tokio::time::timeout(wait_timeout, frame).await.unwrap_or_else(|_| {
+                Err(anyhow::anyhow!("No data from the server within {}ms", general_config.wait_timeout_ms))
+            })
+        };
+        match frame {
+            Ok(StreamFrame::Audio(audio)) => {
+                if let Err(e) = file.write_all(&audio).await {
+                    break Err(anyhow::Error::new(e).context("Failed to write output file"));
+                }
+                received += audio.len();
+            }
+            Ok(StreamFrame::Restart) => {
+                log_message("Server is resending the voice");
+                let restarted = async {
+                    file.set_len(0).await?;
+                    file.rewind().await
+                };
+                if let Err(e) = restarted.await {
+                    break Err(anyhow::Error::new(e).context("Failed to truncate output file"));
+                }
+                received = 0;
+            }
+            Ok(StreamFrame::End(response)) if response.success => break Ok(response),
+            Ok(StreamFrame::End(response)) => {
+                let kind = response.error_kind.unwrap_or(ErrorKind::Other);
+                break Err(anyhow::anyhow!("Server failed to generate voice ({}): {}", kind, response.message));
+            }
+            Err(e) => break Err(e.context("Voice stream from the server broke off")),
+        }
+    };
+    
+    let result = match result {
+        Ok(response) => match file.flush().await {
+            Ok(()) if general_config.fsync_output => file.sync_all().await.map(|_| response),
+            Ok(()) => Ok(response),
+            Err(e) => Err(e),
+        }
+        .context("Failed to write output file"),
+        Err(e) => Err(e),
+    };
+    drop(file);
+    match result {
+        Ok(response) => {
+            let cache_path = response.cache_path.unwrap_or_default();
+            log_message(&format!("Streamed {} bytes to the output, cached at {}", received, cache_path.display()));
+            Ok(())
+        }
+        Err(e) => {
+            let _ = fs::remove_file(output).await;
+            Err(e)
+        }
+    }
+}
+
+// Connect to the server and send it a request, returning the connection for its reply
+async fn open_request(general_config: &GeneralConfig, request: &VoiceRequest) -> Result<(Connection, WireFormat)> {
+    // Connect to server over the configured transport
+    let mut conn = connect_to_server(general_config).await?;
     
     // Serialize request
-    let request_data = serde_json::to_vec(&request)
+    let wire_format = WireFormat::from_name(&general_config.wire_format)?;
+    let request_data = wire_format.encode(request)
         .context("Failed to serialize request")?;
     
     // Send request length first (4 bytes)
@@ -156,8 +975,16 @@ async fn send_generation_request(
     // Send request data
     conn.write_all(&request_data).await
         .context("Failed to send request data")?;
-    
-    // Done - request sent, client can exit immediately
-    log_message("Request sent to server, exiting");
-    Ok(())
-} 
\ No newline at end of file
+    Ok((conn, wire_format))
+}
+
+// Read one length-prefixed message from the server
+async fn read_message(conn: &mut Connection) -> Result<Vec<u8>> {
+    let mut len_bytes = [0u8; 4];
+    conn.read_exact(&mut len_bytes).await
+        .context("Failed to read response length")?;
+    let mut response_data = vec![0u8; u32::from_le_bytes(len_bytes) as usize];
+    conn.read_exact(&mut response_data).await
+        .context("Failed to read response data")?;
+    Ok(response_data)
+}
@@ -1,41 +1,161 @@
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
-use chrono;
 use std::fs::{File, OpenOptions};
-use std::io::Write;
-use std::sync::Mutex;
-use md5;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{mpsc, OnceLock};
+use unicode_normalization::UnicodeNormalization;
 
-// Global logger instance
-lazy_static::lazy_static! {
-    static ref LOGGER: Mutex<Option<File>> = Mutex::new(None);
+// Work for the background log writer
+enum LogCommand {
+    Line(String),
+    /// Acknowledged once every line queued before it has been written
+    Flush(mpsc::Sender<()>),
+}
+
+// Queue feeding the background log writer, set once the log file is open
+static LOGGER: OnceLock<mpsc::Sender<LogCommand>> = OnceLock::new();
+
+// Whether log lines are echoed to stdout
+static CONSOLE_LOGGING: AtomicBool = AtomicBool::new(true);
+
+/// Severity of a log line, most severe first
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    /// Step-by-step detail, including full provider requests with their prompt text
+    Debug,
+}
+
+// Least severe level that is still logged
+static MAX_LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+// Log only lines at `level` or more severe from now on
+#[allow(dead_code)]
+pub fn set_log_level(level: LogLevel) {
+    MAX_LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+// Stop echoing log lines to stdout, for commands whose output is meant to be parsed
+#[allow(dead_code)]
+pub fn disable_console_logging() {
+    CONSOLE_LOGGING.store(false, Ordering::Relaxed);
 }
 
-// Initialize logger with a file
-pub fn init_logger(log_path: &Path) -> Result<()> {
-    let file = OpenOptions::new()
+// Initialize logger with a file, written by a dedicated thread so logging never blocks on disk.
+// Once a line would take the file past `max_bytes` (0 = never), it is rotated: renamed to
+// "<log>.1", older ones shifting to ".2" and so on, keeping at most `keep_files` of them.
+// All lines pass through the one writer thread, so concurrent tasks can't rotate twice.
+pub fn init_logger(log_path: &Path, max_bytes: u64, keep_files: usize) -> Result<()> {
+    let mut file = open_log_file(log_path)?;
+    let mut size = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+    let log_path = log_path.to_path_buf();
+    
+    let (sender, receiver) = mpsc::channel();
+    LOGGER
+        .set(sender)
+        .map_err(|_| anyhow::anyhow!("Logger is already initialized"))?;
+    
+    std::thread::Builder::new()
+        .name("log-writer".to_string())
+        .spawn(move || {
+            for command in receiver {
+                match command {
+                    LogCommand::Line(line) => {
+                        let line_bytes = line.len() as u64 + 1;
+                        if max_bytes > 0 && size > 0 && size + line_bytes > max_bytes {
+                            let _ = file.flush();
+                            match rotate_log_file(&log_path, keep_files) {
+                                Ok(rotated) => {
+                                    file = rotated;
+                                    size = 0;
+                                }
+                                Err(e) => eprintln!("Failed to rotate log file {}: {}", log_path.display(), e),
+                            }
+                        }
+                        if writeln!(file, "{}", line).is_ok() {
+                            size += line_bytes;
+                        }
+                    }
+                    LogCommand::Flush(done) => {
+                        let _ = file.flush();
+                        let _ = done.send(());
+                    }
+                }
+            }
+        })
+        .context("Failed to start log writer thread")?;
+    Ok(())
+}
+
+fn open_log_file(log_path: &Path) -> Result<File> {
+    OpenOptions::new()
         .create(true)
         .append(true)
         .open(log_path)
-        .context("Failed to open log file")?;
-    
-    let mut logger = LOGGER.lock().unwrap();
-    *logger = Some(file);
-    Ok(())
+        .context("Failed to open log file")
 }
 
-// Log a message to stdout and optionally to the file
+// Move the log aside as "<log>.1", shifting older rotations up and dropping the oldest,
+// and open a fresh one in its place
+fn rotate_log_file(log_path: &Path, keep_files: usize) -> Result<File> {
+    let rotated = |index: usize| PathBuf::from(format!("{}.{}", log_path.display(), index));
+    if keep_files == 0 {
+        std::fs::remove_file(log_path)?;
+    } else {
+        let _ = std::fs::remove_file(rotated(keep_files));
+        for index in (1..keep_files).rev() {
+            let _ = std::fs::rename(rotated(index), rotated(index + 1));
+        }
+        std::fs::rename(log_path, rotated(1))?;
+    }
+    open_log_file(log_path)
+}
+
+// Log an info message to stdout and optionally to the file
 pub fn log_message(message: &str) {
+    log_level(LogLevel::Info, message);
+}
+
+// Log a message at a level, dropped when the configured log_level is less verbose.
+// Info lines are written as before, the others are tagged with their level.
+pub fn log_level(level: LogLevel, message: &str) {
+    if level as u8 > MAX_LOG_LEVEL.load(Ordering::Relaxed) {
+        return;
+    }
     let timestamp = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
-    let formatted_message = format!("[{}] {}", timestamp, message);
+    let formatted_message = match level {
+        LogLevel::Info => format!("[{}] {}", timestamp, message),
+        LogLevel::Error => format!("[{}] [ERROR] {}", timestamp, message),
+        LogLevel::Warn => format!("[{}] [WARN] {}", timestamp, message),
+        LogLevel::Debug => format!("[{}] [DEBUG] {}", timestamp, message),
+    };
     
     // Print to stdout
-    println!("{}", formatted_message);
+    if CONSOLE_LOGGING.load(Ordering::Relaxed) {
+        println!("{}", formatted_message);
+    }
     
-    // Write to log file if logger is initialized
-    if let Some(file) = &mut *LOGGER.lock().unwrap() {
-        let _ = writeln!(file, "{}", formatted_message);
+    // Hand the line to the log writer if logger is initialized
+    if let Some(logger) = LOGGER.get() {
+        let _ = logger.send(LogCommand::Line(formatted_message));
+    }
+}
+
+// Wait until everything logged so far is in the log file, call before exiting
+pub fn flush_logger() {
+    if let Some(logger) = LOGGER.get() {
+        let (done, flushed) = mpsc::channel();
+        if logger.send(LogCommand::Flush(done)).is_ok() {
+            let _ = flushed.recv();
+        }
     }
 }
 
@@ -82,6 +202,25 @@ impl TextSplitMethod {
         }
     }
 
+    // Convert a configured split method (name or API value) to the API value
+    pub fn convert(value: &str) -> Result<&'static str> {
+        Self::from_api_value(value)
+            .map(|method| method.to_api_value())
+            .context(format!("Invalid text split method: {}", value))
+    }
+
+    // Convert a split method given at request time, warning and using the
+    // already-validated fallback instead of failing the request
+    pub fn convert_or(value: &str, fallback: &str) -> String {
+        match Self::convert(value) {
+            Ok(api_value) => api_value.to_string(),
+            Err(e) => {
+                log_message(&format!("Warning: {}, falling back to {}", e, fallback));
+                fallback.to_string()
+            }
+        }
+    }
+
     pub fn description(&self) -> &'static str {
         match self {
             TextSplitMethod::NoSplit => "不切分文本",
@@ -96,7 +235,7 @@ impl TextSplitMethod {
 
 // Configuration structs
 #[allow(dead_code)]
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct GptSoVitsConfig {
     pub base_url: String,
     pub method: String,
@@ -119,28 +258,1278 @@ pub struct GptSoVitsConfig {
     pub repetition_penalty: f32,
     pub media_type: String,
     pub aux_ref_audio_paths: Vec<String>,
+    /// File whose contents replace `prompt_text` (e.g. a transcript next to the reference audio)
+    #[serde(default)]
+    pub prompt_text_file: Option<String>,
 }
 
 #[allow(dead_code)]
-#[derive(Debug, Deserialize, Clone)]
+impl GptSoVitsConfig {
+    // Check the request parameters GPT-SoVITS would otherwise reject on every line, and that
+    // local reference clips exist, reporting every problem at once. An empty ref_audio_path
+    // is left to the provider, which knows whether allow_empty_ref permits it.
+    pub fn validate(&self, section: &str) -> Result<()> {
+        let mut problems = Vec::new();
+        match reqwest::Url::parse(&self.base_url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            Ok(url) => problems.push(format!("base_url must be an http(s) URL, got scheme {}", url.scheme())),
+            Err(e) => problems.push(format!("base_url {:?} is not a valid URL: {}", self.base_url, e)),
+        }
+        if !["GET", "POST"].contains(&self.method.to_uppercase().as_str()) {
+            problems.push(format!("method must be GET or POST, got {:?}", self.method));
+        }
+        if TextSplitMethod::from_api_value(&self.text_split_method).is_none() {
+            problems.push(format!("Invalid text split method: {}", self.text_split_method));
+        }
+        if self.speed_factor <= 0.0 {
+            problems.push(format!("speed_factor must be above 0, got {}", self.speed_factor));
+        }
+        if self.temperature <= 0.0 {
+            problems.push(format!("temperature must be above 0, got {}", self.temperature));
+        }
+        if self.top_k < 1 {
+            problems.push(format!("top_k must be at least 1, got {}", self.top_k));
+        }
+        if !(0.0..=1.0).contains(&self.top_p) {
+            problems.push(format!("top_p must be between 0 and 1, got {}", self.top_p));
+        }
+        if self.batch_size < 1 {
+            problems.push(format!("batch_size must be at least 1, got {}", self.batch_size));
+        }
+        if !(0.0..=1.0).contains(&self.batch_threshold) {
+            problems.push(format!("batch_threshold must be between 0 and 1, got {}", self.batch_threshold));
+        }
+        if self.fragment_interval < 0.0 {
+            problems.push(format!("fragment_interval must not be negative, got {}", self.fragment_interval));
+        }
+        if self.repetition_penalty <= 0.0 {
+            problems.push(format!("repetition_penalty must be above 0, got {}", self.repetition_penalty));
+        }
+        let references = std::iter::once(&self.ref_audio_path).chain(&self.aux_ref_audio_paths);
+        for reference in references.filter(|path| !path.trim().is_empty() && !path.contains("://")) {
+            if !Path::new(reference).exists() {
+                problems.push(format!("Reference audio does not exist: {}", reference));
+            }
+        }
+        config_problems(section, problems)
+    }
+
+    // Read prompt_text from prompt_text_file if one is configured
+    pub fn load_prompt_text_file(&mut self) -> Result<()> {
+        let Some(path) = self.prompt_text_file.as_deref().filter(|path| !path.is_empty()) else {
+            return Ok(());
+        };
+
+        if !Path::new(path).exists() {
+            anyhow::bail!("Prompt text file does not exist: {}", path);
+        }
+
+        let prompt_text = std::fs::read_to_string(path)
+            .context(format!("Failed to read prompt text file: {}", path))?;
+        self.prompt_text = prompt_text.trim().to_string();
+
+        log_message(&format!(
+            "Loaded prompt text from {} ({} chars)",
+            path,
+            self.prompt_text.chars().count()
+        ));
+        Ok(())
+    }
+
+    // Use the transcript next to the reference clip ({ref_audio_path}.txt) as prompt_text,
+    // returning whether there was one
+    pub fn load_ref_transcript(&mut self) -> Result<bool> {
+        if self.ref_audio_path.is_empty() || self.ref_audio_path.contains("://") {
+            return Ok(false);
+        }
+        let path = format!("{}.txt", self.ref_audio_path);
+        if !Path::new(&path).exists() {
+            return Ok(false);
+        }
+
+        let prompt_text = std::fs::read_to_string(&path)
+            .context(format!("Failed to read reference transcript: {}", path))?;
+        self.prompt_text = prompt_text.trim().to_string();
+
+        log_message(&format!(
+            "Using reference transcript {} as prompt text ({} chars)",
+            path,
+            self.prompt_text.chars().count()
+        ));
+        Ok(true)
+    }
+}
+
+/// A `[voices.<id>]` entry: a character voice chosen per request with `voice_id`,
+/// replacing these `[tts]` fields while every other parameter stays the same
+#[allow(dead_code)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct VoiceConfig {
+    pub ref_audio_path: Option<String>,
+    pub prompt_text: Option<String>,
+    pub prompt_lang: Option<String>,
+    pub speed_factor: Option<f32>,
+}
+
+#[allow(dead_code)]
+impl VoiceConfig {
+    // The GPT-SoVITS parameters for this voice on top of a base (or profile) config
+    pub fn apply(&self, base: &GptSoVitsConfig) -> GptSoVitsConfig {
+        let mut config = base.clone();
+        if let Some(ref_audio_path) = &self.ref_audio_path {
+            config.ref_audio_path = ref_audio_path.clone();
+        }
+        if let Some(prompt_text) = &self.prompt_text {
+            config.prompt_text = prompt_text.clone();
+        }
+        if let Some(prompt_lang) = &self.prompt_lang {
+            config.prompt_lang = prompt_lang.clone();
+        }
+        if let Some(speed_factor) = self.speed_factor {
+            config.speed_factor = speed_factor;
+        }
+        config
+    }
+}
+
+/// `[tts_remote]` settings: delegate generation to another krkr-tts server
+#[allow(dead_code)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RemoteKrkrConfig {
+    /// Host of the upstream krkr-tts server
+    pub host: String,
+    /// `server_port` the upstream server's TCP protocol listens on
+    pub port: u16,
+    /// Config file the upstream generates with, as a path on its machine; its
+    /// default `config/default.toml` when unset
+    #[serde(default)]
+    pub config_path: Option<PathBuf>,
+}
+
+/// `[tts_google]` settings: Google Cloud Text-to-Speech
+#[allow(dead_code)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct GoogleTtsConfig {
+    /// API key, sent as the `key` query parameter
+    pub api_key: String,
+    /// OAuth access token, sent as a bearer token instead of `api_key`
+    pub access_token: String,
+    /// BCP-47 language of the voice, e.g. "ja-JP"
+    pub language_code: String,
+    /// Voice name, e.g. "ja-JP-Neural2-B" (empty lets Google pick one for the language)
+    pub voice_name: String,
+    /// `audioEncoding` of the result: LINEAR16 (WAV), MP3 or OGG_OPUS
+    pub audio_encoding: String,
+    /// Send text as SSML instead of plain text
+    pub ssml: bool,
+    /// API endpoint, for proxies
+    pub base_url: String,
+}
+
+impl Default for GoogleTtsConfig {
+    fn default() -> Self {
+        Self {
+            api_key: String::new(),
+            access_token: String::new(),
+            language_code: "ja-JP".to_string(),
+            voice_name: String::new(),
+            audio_encoding: "LINEAR16".to_string(),
+            ssml: false,
+            base_url: "https://texttospeech.googleapis.com".to_string(),
+        }
+    }
+}
+
+/// `[tts_voicevox]` settings: a local VOICEVOX engine
+#[allow(dead_code)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct VoiceVoxConfig {
+    /// Engine address
+    pub base_url: String,
+    /// Style id of the voice (see the engine's `/speakers`)
+    pub speaker: u32,
+    /// `speedScale` of the audio query, 1.0 is normal speed
+    pub speed_scale: f64,
+    /// `pitchScale` of the audio query, 0.0 is the voice's own pitch
+    pub pitch_scale: f64,
+}
+
+impl Default for VoiceVoxConfig {
+    fn default() -> Self {
+        Self {
+            base_url: "http://127.0.0.1:50021".to_string(),
+            speaker: 1,
+            speed_scale: 1.0,
+            pitch_scale: 0.0,
+        }
+    }
+}
+
+/// Cache directory setting: a single path or an ordered search list
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum CacheDirs {
+    Single(String),
+    List(Vec<String>),
+}
+
+impl CacheDirs {
+    /// Non-empty cache directories in lookup order
+    pub fn paths(&self) -> Vec<PathBuf> {
+        let dirs = match self {
+            CacheDirs::Single(dir) => std::slice::from_ref(dir),
+            CacheDirs::List(dirs) => dirs.as_slice(),
+        };
+        dirs.iter()
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .collect()
+    }
+
+    /// The same directories with `subdir` appended to each
+    pub fn with_subdir(&self, subdir: &str) -> CacheDirs {
+        let join = |dir: &String| {
+            if dir.is_empty() {
+                dir.clone()
+            } else {
+                Path::new(dir).join(subdir).to_string_lossy().to_string()
+            }
+        };
+        match self {
+            CacheDirs::Single(dir) => CacheDirs::Single(join(dir)),
+            CacheDirs::List(dirs) => CacheDirs::List(dirs.iter().map(join).collect()),
+        }
+    }
+}
+
+/// `[general]` settings. Every field is optional and falls back to the
+/// value from `Default`, so older configs keep working as options are added.
+#[allow(dead_code)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
 pub struct GeneralConfig {
-    /// Default cache directory for pre-generated voices
-    pub cache_dir: String,
+    /// Default cache directory for pre-generated voices, or a list of
+    /// directories searched in order (writes go to the first writable one).
+    /// Default: empty (no cache)
+    pub cache_dir: CacheDirs,
     
-    /// Default number of voices to pre-generate
+    /// Address the TCP and HTTP listeners bind to. Default: "127.0.0.1"
+    pub bind_address: String,
+
+    /// Allow a `bind_address` other than loopback, which exposes the server
+    /// (and any provider credentials it uses) to the network. Default: false
+    pub allow_public_bind: bool,
+
+    /// Host name or IP address the client connects to the server at, for a
+    /// server on another machine; empty derives it from `bind_address`. Default: empty
+    pub server_host: String,
+
+    /// How the client reaches the server's protocol listener: "tcp", or "uds"
+    /// for a Unix domain socket (a named pipe on Windows), which skips the
+    /// TCP stack for a client on the same machine. Default: "tcp"
+    pub transport: String,
+
+    /// Socket file (or `\\.\pipe\...` pipe name on Windows) for the "uds"
+    /// transport; empty uses `krkr-tts-<server_port>` in the temp directory,
+    /// or the pipe of that name. Default: empty
+    pub socket_path: String,
+    
+    /// TTS backend: `gpt_sovits` (`[tts]`), `remote` (`[tts_remote]`),
+    /// `google` (`[tts_google]`) or `voicevox` (`[tts_voicevox]`).
+    /// Default: empty (`remote` when a
+    /// `[tts_remote]` section exists, otherwise `gpt_sovits`)
+    pub provider: String,
+    
+    /// Provider (same names as `provider`) that answers cache misses first
+    /// with a quick draft, which the main provider then regenerates and
+    /// replaces in the cache. Prefetching always uses the main provider.
+    /// Default: empty (no drafts)
+    pub draft_provider: String,
+    
+    /// Default number of voices to pre-generate. Default: 5
     pub prefetch_count: usize,
     
-    /// Default log file path
+    /// Default log file path. Default: empty (console only)
     pub log_file: String,
+
+    /// Least severe log lines to write: "error", "warn", "info", or "debug"
+    /// for step-by-step detail including full provider requests. Default: "info"
+    pub log_level: LogLevel,
+
+    /// Size in bytes at which the log file is rotated to `<log>.1` (0 = never). Default: 10000000
+    pub max_log_bytes: u64,
+
+    /// Rotated log files kept beside the current one (`.1` newest). Default: 3
+    pub log_keep_files: usize,
     
-    /// Port for the TTS server to listen on
+    /// Access log path: one line per completed voice request with its
+    /// cache hit/miss, provider, status, latency and size, however verbose
+    /// the general log is. Default: empty (disabled)
+    pub access_log: String,
+    
+    /// Port for the TTS server to listen on. Default: 5656
     pub server_port: u16,
     
-    /// Maximum concurrent TTS requests
+    /// Maximum concurrent TTS requests. Default: 10
     pub max_concurrent_tts: usize,
     
-    /// Path to the text list file for prefetching
+    /// Character encoding of text list files: "utf-8", or "shift_jis" /
+    /// "cp932" for scenario text shipped by many Japanese games. Default: "utf-8"
+    pub text_list_encoding: String,
+
+    /// Path to the text list file for prefetching. Default: empty (no prefetch)
     pub text_list_path: String,
+
+    /// How long the server remembers request idempotency keys (0 disables). Default: 30
+    pub idempotency_ttl_secs: u64,
+
+    /// Port for the optional HTTP front-end. Default: unset (disabled)
+    pub http_port: Option<u16>,
+
+    /// Ordered text normalization steps applied before hashing and generation:
+    /// `strip_ruby`, `fullwidth_to_halfwidth`, `regex_replacements`. Default: none
+    pub text_transforms: Vec<String>,
+
+    /// Text is always trimmed before hashing and text list matching; this
+    /// also collapses runs of internal whitespace to a single space. Default: false
+    pub match_whitespace: bool,
+
+    /// Also bring text to Unicode NFC before hashing and text list matching,
+    /// so composed and decomposed spellings of a line share a voice. Default: false
+    pub unicode_nfc: bool,
+
+    /// Write a short silent WAV instead of failing when generation fails. Default: false
+    pub silence_fallback: bool,
+
+    /// Length of the silent fallback WAV in milliseconds. Default: 500
+    pub silence_duration_ms: u64,
+
+    /// Seconds allowed for reading a request's length prefix. Default: 5
+    pub read_header_timeout_secs: u64,
+
+    /// Seconds allowed for reading a request body, per started MiB. Default: 5
+    pub read_body_timeout_secs: u64,
+
+    /// Furthest number of lines prefetch looks ahead of the current one (0 = no limit). Default: 0
+    pub prefetch_scan_limit: usize,
+
+    /// Milliseconds each prefetch generation waits after finishing before its
+    /// slot takes the next line, to go easy on the API. Default: 200
+    pub prefetch_delay_ms: u64,
+
+    /// Lines a prefetch generates at once (0 counts as 1). Each also takes a
+    /// slot of `max_concurrent_tts`, so prefetch never runs past it. Default: 1
+    pub prefetch_concurrency: usize,
+
+    /// Fixed number of worker tasks draining a bounded generation queue
+    /// (0 = spawn a task per request). Default: 0
+    pub worker_tasks: usize,
+
+    /// Start GPT-SoVITS even when `tts.ref_audio_path` is empty, e.g. when every
+    /// request attaches its own reference clip. Default: false
+    pub allow_empty_ref: bool,
+
+    /// Config files clients may name in requests, besides the server's own
+    /// (empty allows any). Default: empty
+    pub allowed_config_paths: Vec<PathBuf>,
+
+    /// Text lists the server reads at startup instead of on first use. Default: none
+    pub preload_text_lists: Vec<String>,
+
+    /// Follow up to 10 HTTP redirects from providers, e.g. to a CDN serving
+    /// the audio (307/308 resend the request body). Default: true
+    pub follow_redirects: bool,
+
+    /// Directory that also receives every served voice as numbered slots
+    /// (`0001.wav`, ...) plus an `index.txt` to tail. Default: empty (disabled)
+    pub ring_buffer_dir: String,
+
+    /// Number of slots in the ring buffer before the oldest is overwritten. Default: 100
+    pub ring_buffer_size: usize,
+
+    /// Fingerprint each generated voice and warn when different texts come
+    /// out near-identical. Default: false
+    pub detect_duplicate_audio: bool,
+
+    /// Disable Nagle's algorithm on protocol connections so small framed
+    /// messages go out immediately. Default: true
+    pub tcp_nodelay: bool,
+
+    /// SO_SNDBUF for protocol sockets in bytes. Default: unset (OS default)
+    pub tcp_send_buffer_bytes: Option<u32>,
+
+    /// SO_RCVBUF for protocol sockets in bytes. Default: unset (OS default)
+    pub tcp_recv_buffer_bytes: Option<u32>,
+
+    /// Keep voices in a subdirectory of each cache directory named after a
+    /// short hash of the `[tts]`/`[tts_remote]`/`[tts_google]`/`[tts_voicevox]` settings, so
+    /// config presets with different generation parameters never share cached voices.
+    /// Not applied to a `--cache-dir` override. Default: false
+    pub auto_cache_subdir: bool,
+
+    /// Longest a single line may take to generate before the provider
+    /// request is aborted, its partial file removed and `silence_fallback`
+    /// (if enabled) kicks in, so a hung backend can't hold a concurrency
+    /// slot forever. Also accepted as `generation_timeout_secs`.
+    /// 0 disables the limit. Default: 120
+    #[serde(alias = "generation_timeout_secs")]
+    pub max_generation_secs: u64,
+
+    /// Adjust the number of concurrent generations at runtime instead of
+    /// keeping `max_concurrent_tts` fixed: raised while the p90 generation
+    /// latency stays under the target, lowered when it goes over. Default: false
+    pub autoscale_concurrency: bool,
+
+    /// p90 generation latency the autoscaler aims for. Default: 5000
+    pub autoscale_target_latency_ms: u64,
+
+    /// Lowest concurrency the autoscaler backs off to. Default: 1
+    pub autoscale_min_concurrency: usize,
+
+    /// Highest concurrency the autoscaler grows to. Default: 20
+    pub autoscale_max_concurrency: usize,
+
+    /// Separator for several utterances sent as one request (e.g. `"||"`).
+    /// Each utterance is generated and cached on its own, then joined into the
+    /// voice for the full text. Default: empty (disabled)
+    pub request_delimiter: String,
+
+    /// Silence between joined utterances. Default: 150
+    pub request_delimiter_gap_ms: u64,
+
+    /// Flush the voice copied to the client's `--output` to disk before
+    /// exiting, for network volumes where the game may otherwise read a
+    /// partial file. Default: false
+    pub fsync_output: bool,
+
+    /// Times the client retries copying a voice to `--output` when the copy
+    /// fails, e.g. while an antivirus scanner holds the file, waiting twice
+    /// as long before each attempt. Default: 3
+    pub delivery_copy_retries: u32,
+
+    /// Fail a generation whose response Content-Type doesn't match
+    /// `tts.media_type` instead of only logging a warning. Default: false
+    pub strict_content_type: bool,
+
+    /// Channels every generated voice is converted to: 1 downmixes stereo
+    /// to mono, 2 duplicates mono into stereo, 0 keeps what the provider
+    /// returned. Only 16-bit WAVs are converted. Default: 0
+    pub output_channels: u16,
+
+    /// Audio format voices are cached in, which is also their file extension:
+    /// "wav", or "ogg" (Vorbis), "opus" or "mp3", transcoded after generation
+    /// and needing ffmpeg on the server. Default: "wav"
+    pub output_format: String,
+
+    /// With a compressed `output_format`, also keep the generated WAV next to
+    /// each voice as `<hash>.src.wav`, evicted together with it. Default: false
+    pub keep_source_wav: bool,
+
+    /// GPT-SoVITS request field names: "v2" for the current api_v2.py,
+    /// "v1_legacy" for the original api.py (refer_wav_path, text_language,
+    /// ...), or "custom" to rename fields with `api_field_map`. Default: "v2"
+    pub api_schema: String,
+
+    /// With `api_schema = "custom"`, map of v2 field name -> name the fork
+    /// expects; a field mapped to "" is not sent. Default: empty
+    pub api_field_map: HashMap<String, String>,
+
+    /// Answer a line already served since the server started straight from
+    /// its file, without prefetching around it again. Default: false
+    pub skip_redelivery: bool,
+
+    /// Keep the audio received so far when the GPT-SoVITS stream breaks
+    /// mid-response, instead of failing the generation. Default: false
+    pub keep_partial_on_stream_error: bool,
+
+    /// Bytes that must have arrived for `keep_partial_on_stream_error` to
+    /// keep a partial voice. Default: 64000 (about 1s of 32kHz mono audio)
+    pub partial_min_bytes: u64,
+
+    /// Encoding the client sends requests in: "json", "json_pretty" or
+    /// "msgpack" (smaller for big requests). The server detects the format
+    /// of each request and replies in kind. Default: "json"
+    pub wire_format: String,
+
+    /// Milliseconds a client waits for the server's reply (`--wait`,
+    /// `--ack`, `--evict`, `--prewarm`) before failing with a timeout;
+    /// 0 waits forever. Default: 60000
+    pub wait_timeout_ms: u64,
+
+    /// Times a generation is retried after a connection error, timeout or
+    /// 5xx response from the provider; 4xx responses fail at once. Default: 2
+    pub max_retries: u32,
+
+    /// Wait before the first retry in milliseconds, doubled for each further
+    /// retry. Default: 500
+    pub retry_base_delay_ms: u64,
+
+    /// Seconds a stopping server (Ctrl+C or SIGTERM) waits for running
+    /// generations to finish before cancelling them. Default: 30
+    pub shutdown_timeout_secs: u64,
+
+    /// Cap on the total size of the voice files in the cache directory in
+    /// bytes. Whenever a new voice is generated past it, the least recently
+    /// used voices are deleted until the cache fits again; 0 means unlimited.
+    /// Default: 0
+    pub max_cache_bytes: u64,
+
+    /// Compiled `text_transforms`, filled in by `load_general_config`
+    #[serde(skip)]
+    pub text_pipeline: TextPipeline,
+
+    /// Hash of the voice-affecting `[tts]` fields, filled in by `load_general_config`
+    #[serde(skip)]
+    pub voice_params_hash: String,
+
+    /// Hash of each `[voices.<id>]` entry by id, filled in by `load_general_config`
+    #[serde(skip)]
+    pub voice_hashes: HashMap<String, String>,
+
+    /// Hash of each `[[profiles]]` entry by name, filled in by `load_general_config`
+    #[serde(skip)]
+    pub profile_hashes: HashMap<String, String>,
+}
+
+impl Default for GeneralConfig {
+    fn default() -> Self {
+        Self {
+            cache_dir: CacheDirs::Single(String::new()),
+            bind_address: "127.0.0.1".to_string(),
+            allow_public_bind: false,
+            server_host: String::new(),
+            provider: String::new(),
+            draft_provider: String::new(),
+            prefetch_count: 5,
+            log_file: String::new(),
+            log_level: LogLevel::Info,
+            max_log_bytes: 10_000_000,
+            log_keep_files: 3,
+            access_log: String::new(),
+            server_port: 5656,
+            max_concurrent_tts: 10,
+            text_list_path: String::new(),
+            text_list_encoding: "utf-8".to_string(),
+            idempotency_ttl_secs: 30,
+            http_port: None,
+            text_transforms: Vec::new(),
+            match_whitespace: false,
+            unicode_nfc: false,
+            silence_fallback: false,
+            silence_duration_ms: 500,
+            read_header_timeout_secs: 5,
+            read_body_timeout_secs: 5,
+            prefetch_scan_limit: 0,
+            prefetch_delay_ms: 200,
+            prefetch_concurrency: 1,
+            worker_tasks: 0,
+            allow_empty_ref: false,
+            allowed_config_paths: Vec::new(),
+            preload_text_lists: Vec::new(),
+            follow_redirects: true,
+            ring_buffer_dir: String::new(),
+            ring_buffer_size: 100,
+            detect_duplicate_audio: false,
+            tcp_nodelay: true,
+            transport: "tcp".to_string(),
+            socket_path: String::new(),
+            tcp_send_buffer_bytes: None,
+            tcp_recv_buffer_bytes: None,
+            auto_cache_subdir: false,
+            max_generation_secs: 120,
+            autoscale_concurrency: false,
+            autoscale_target_latency_ms: 5000,
+            autoscale_min_concurrency: 1,
+            autoscale_max_concurrency: 20,
+            request_delimiter: String::new(),
+            request_delimiter_gap_ms: 150,
+            fsync_output: false,
+            delivery_copy_retries: 3,
+            strict_content_type: false,
+            output_channels: 0,
+            output_format: "wav".to_string(),
+            keep_source_wav: false,
+            api_schema: "v2".to_string(),
+            api_field_map: HashMap::new(),
+            skip_redelivery: false,
+            keep_partial_on_stream_error: false,
+            partial_min_bytes: 64000,
+            wire_format: "json".to_string(),
+            wait_timeout_ms: 60000,
+            max_retries: 2,
+            retry_base_delay_ms: 500,
+            max_cache_bytes: 0,
+            shutdown_timeout_secs: 30,
+            text_pipeline: TextPipeline::default(),
+            voice_params_hash: String::new(),
+            voice_hashes: HashMap::new(),
+            profile_hashes: HashMap::new(),
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl GeneralConfig {
+    // Check the settings that would otherwise only fail once a request or listener uses
+    // them, reporting every problem at once
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        if self.max_concurrent_tts == 0 {
+            problems.push("max_concurrent_tts must be at least 1".to_string());
+        }
+        if self.output_channels > 2 {
+            problems.push(format!("output_channels must be 0 (as generated), 1 or 2, got {}", self.output_channels));
+        }
+        if let Err(e) = bind_ip(self) {
+            problems.push(e.to_string());
+        }
+        if let Err(e) = text_list_encoding(&self.text_list_encoding) {
+            problems.push(e.to_string());
+        }
+        if let Err(e) = WireFormat::from_name(&self.wire_format) {
+            problems.push(e.to_string());
+        }
+        if !["v2", "v1_legacy", "custom"].contains(&self.api_schema.as_str()) {
+            problems.push(format!("Unknown api_schema: {} (expected v2, v1_legacy or custom)", self.api_schema));
+        }
+        if self.http_port.is_some_and(|http_port| http_port == self.server_port) {
+            problems.push(format!("http_port and server_port are both {}", self.server_port));
+        }
+        if !self.ring_buffer_dir.is_empty() && self.ring_buffer_size == 0 {
+            problems.push("ring_buffer_size must be at least 1 when ring_buffer_dir is set".to_string());
+        }
+        if self.autoscale_concurrency {
+            if self.autoscale_min_concurrency == 0 {
+                problems.push("autoscale_min_concurrency must be at least 1".to_string());
+            }
+            if self.autoscale_min_concurrency > self.autoscale_max_concurrency {
+                problems.push(format!(
+                    "autoscale_min_concurrency ({}) is above autoscale_max_concurrency ({})",
+                    self.autoscale_min_concurrency, self.autoscale_max_concurrency
+                ));
+            }
+        }
+        if !self.request_delimiter.is_empty() && self.output_format != "wav" {
+            problems.push("request_delimiter joins utterances as WAV and needs output_format = \"wav\"".to_string());
+        }
+        config_problems("[general]", problems)
+    }
+}
+
+// One error listing every problem found in a config section, or Ok if there were none
+fn config_problems(section: &str, problems: Vec<String>) -> Result<()> {
+    if problems.is_empty() {
+        return Ok(());
+    }
+    anyhow::bail!(
+        "Invalid {} configuration:\n  - {}",
+        section,
+        problems.join("\n  - ")
+    )
+}
+
+/// Environment variable holding an inline TOML config, layered over the config file
+#[allow(dead_code)]
+pub const CONFIG_ENV_VAR: &str = "KRKR_TTS_CONFIG";
+
+// Build the configuration from a TOML file, or stdin when the path is "-",
+// overlaid with the inline config from KRKR_TTS_CONFIG if it is set
+#[allow(dead_code)]
+pub fn build_config(config_path: &Path) -> Result<config::Config> {
+    build_config_from(config_path, std::env::var(CONFIG_ENV_VAR).ok(), std::io::stdin())
+}
+
+// build_config with the inline config and stdin passed in
+#[allow(dead_code)]
+pub fn build_config_from(
+    config_path: &Path,
+    inline_config: Option<String>,
+    mut stdin: impl Read,
+) -> Result<config::Config> {
+    let mut builder = config::Config::builder();
+    
+    if config_path == Path::new("-") {
+        let mut toml = String::new();
+        stdin
+            .read_to_string(&mut toml)
+            .context("Failed to read configuration from stdin")?;
+        builder = builder.add_source(config::File::from_str(&toml, config::FileFormat::Toml));
+    } else {
+        // The file may be left out entirely when the environment supplies the config
+        builder = builder.add_source(
+            config::File::from(config_path.to_path_buf()).required(inline_config.is_none())
+        );
+    }
+    
+    if let Some(inline_config) = &inline_config {
+        builder = builder.add_source(config::File::from_str(inline_config, config::FileFormat::Toml));
+    }
+    
+    builder.build().context("Failed to load configuration")
+}
+
+// Parse general.bind_address
+#[allow(dead_code)]
+pub fn bind_ip(config: &GeneralConfig) -> Result<IpAddr> {
+    config
+        .bind_address
+        .parse()
+        .context(format!(
+            "Invalid bind_address: {} (expected an IP address such as 127.0.0.1 or 0.0.0.0)",
+            config.bind_address
+        ))
+}
+
+// Address a client reaches the server at: server_host when set, otherwise the bind
+// address, or loopback when the server listens on all interfaces
+#[allow(dead_code)]
+pub async fn server_connect_address(config: &GeneralConfig) -> Result<SocketAddr> {
+    if !config.server_host.is_empty() {
+        // An IP address or a host name such as localhost or a LAN machine's DNS name
+        return tokio::net::lookup_host((config.server_host.as_str(), config.server_port))
+            .await
+            .context(format!("Failed to resolve server_host: {}", config.server_host))?
+            .next()
+            .context(format!("server_host {} resolved to no address", config.server_host));
+    }
+    let ip = match bind_ip(config)? {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    Ok(SocketAddr::new(ip, config.server_port))
+}
+
+/// Transports the server's protocol can be reached over
+#[allow(dead_code)]
+pub const TRANSPORTS: [&str; 2] = ["tcp", "uds"];
+
+// Socket file or pipe name of the "uds" transport, with the per-port default filled in
+#[allow(dead_code)]
+pub fn local_socket_path(config: &GeneralConfig) -> String {
+    if !config.socket_path.is_empty() {
+        return config.socket_path.clone();
+    }
+    if cfg!(windows) {
+        format!(r"\\.\pipe\krkr-tts-{}", config.server_port)
+    } else {
+        std::env::temp_dir()
+            .join(format!("krkr-tts-{}.sock", config.server_port))
+            .to_string_lossy()
+            .to_string()
+    }
+}
+
+/// A protocol connection over whichever transport is configured. The framing is the
+/// same on all of them, so only connecting and accepting differ.
+#[allow(dead_code)]
+pub enum Connection {
+    Tcp(tokio::net::TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+    #[cfg(windows)]
+    PipeServer(tokio::net::windows::named_pipe::NamedPipeServer),
+    #[cfg(windows)]
+    PipeClient(tokio::net::windows::named_pipe::NamedPipeClient),
+}
+
+// Forward an I/O call to whichever stream a Connection holds
+macro_rules! with_stream {
+    ($connection:expr, $stream:ident => $call:expr) => {
+        match $connection.get_mut() {
+            Connection::Tcp($stream) => $call,
+            #[cfg(unix)]
+            Connection::Unix($stream) => $call,
+            #[cfg(windows)]
+            Connection::PipeServer($stream) => $call,
+            #[cfg(windows)]
+            Connection::PipeClient($stream) => $call,
+        }
+    };
+}
+
+impl tokio::io::AsyncRead for Connection {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        with_stream!(self, stream => std::pin::Pin::new(stream).poll_read(cx, buf))
+    }
+}
+
+impl tokio::io::AsyncWrite for Connection {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        with_stream!(self, stream => std::pin::Pin::new(stream).poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+        with_stream!(self, stream => std::pin::Pin::new(stream).poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+        with_stream!(self, stream => std::pin::Pin::new(stream).poll_shutdown(cx))
+    }
+}
+
+// Connect to the server over the configured transport
+#[allow(dead_code)]
+pub async fn connect_to_server(config: &GeneralConfig) -> Result<Connection> {
+    const NOT_RUNNING: &str = "Failed to connect to TTS server. Make sure the server is running.";
+    if config.transport == "uds" {
+        let path = local_socket_path(config);
+        #[cfg(unix)]
+        {
+            let stream = tokio::net::UnixStream::connect(&path)
+                .await
+                .context(format!("{} (socket {})", NOT_RUNNING, path))?;
+            return Ok(Connection::Unix(stream));
+        }
+        #[cfg(windows)]
+        {
+            return connect_pipe(&path).await.context(format!("{} (pipe {})", NOT_RUNNING, path));
+        }
+    }
+
+    let address = server_connect_address(config).await?;
+    let stream = tcp_socket(config, &address)?
+        .connect(address)
+        .await
+        .context(NOT_RUNNING)?;
+    stream.set_nodelay(config.tcp_nodelay)
+        .context("Failed to set TCP_NODELAY")?;
+    Ok(Connection::Tcp(stream))
+}
+
+// Open a named pipe, waiting while every instance the server has created is busy
+#[cfg(windows)]
+async fn connect_pipe(path: &str) -> Result<Connection> {
+    const ERROR_PIPE_BUSY: i32 = 231;
+    loop {
+        match tokio::net::windows::named_pipe::ClientOptions::new().open(path) {
+            Ok(pipe) => return Ok(Connection::PipeClient(pipe)),
+            Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) => {
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// Create a TCP socket for `address` with the configured buffer sizes, ready to bind or connect.
+// Accepted connections inherit the listening socket's buffer sizes.
+#[allow(dead_code)]
+pub fn tcp_socket(config: &GeneralConfig, address: &SocketAddr) -> Result<tokio::net::TcpSocket> {
+    let socket = if address.is_ipv4() {
+        tokio::net::TcpSocket::new_v4()
+    } else {
+        tokio::net::TcpSocket::new_v6()
+    }
+    .context("Failed to create TCP socket")?;
+    if let Some(size) = config.tcp_send_buffer_bytes {
+        socket.set_send_buffer_size(size).context("Failed to set TCP send buffer size")?;
+    }
+    if let Some(size) = config.tcp_recv_buffer_bytes {
+        socket.set_recv_buffer_size(size).context("Failed to set TCP receive buffer size")?;
+    }
+    Ok(socket)
+}
+
+// Read the [general] section along with the text transform pipeline it names
+#[allow(dead_code)]
+pub fn load_general_config(config: &config::Config) -> Result<GeneralConfig> {
+    let mut general_config: GeneralConfig = config
+        .get("general")
+        .context("Failed to parse general configuration")?;
+
+    let replacements: Vec<TextReplacement> = match config.get("text_replacements") {
+        Ok(replacements) => replacements,
+        Err(config::ConfigError::NotFound(_)) => Vec::new(),
+        Err(e) => return Err(e).context("Failed to parse text replacements"),
+    };
+
+    general_config.text_pipeline =
+        TextPipeline::new(
+            &general_config.text_transforms,
+            &replacements,
+            TextNormalization {
+                collapse_whitespace: general_config.match_whitespace,
+                nfc: general_config.unicode_nfc,
+            },
+        )?;
+
+    // The format is also the cache file extension, so client and server must spell it alike
+    general_config.output_format = general_config.output_format.to_ascii_lowercase();
+    if !OUTPUT_FORMATS.contains(&general_config.output_format.as_str()) {
+        anyhow::bail!(
+            "Unknown output_format: {} (expected wav, ogg, opus or mp3)",
+            general_config.output_format
+        );
+    }
+
+    general_config.transport = general_config.transport.to_ascii_lowercase();
+    if !TRANSPORTS.contains(&general_config.transport.as_str()) {
+        anyhow::bail!("Unknown transport: {} (expected tcp or uds)", general_config.transport);
+    }
+
+    general_config.voice_params_hash = voice_params_hash(config)?;
+    general_config.voice_hashes = voice_hashes(config)?;
+    general_config.profile_hashes = profile_hashes(config)?;
+
+    if general_config.auto_cache_subdir {
+        let subdir = generation_settings_hash(config)?;
+        general_config.cache_dir = general_config.cache_dir.with_subdir(&subdir);
+    }
+    Ok(general_config)
+}
+
+/// Formats voices can be cached in, each also used as the file extension
+#[allow(dead_code)]
+pub const OUTPUT_FORMATS: [&str; 4] = ["wav", "ogg", "opus", "mp3"];
+
+// [tts] fields that change how a line sounds, hashed into every cache filename
+const VOICE_PARAM_FIELDS: [&str; 11] = [
+    "ref_audio_path",
+    "aux_ref_audio_paths",
+    "prompt_text",
+    "prompt_text_file",
+    "prompt_lang",
+    "text_lang",
+    "speed_factor",
+    "temperature",
+    "top_k",
+    "top_p",
+    "seed",
+];
+
+// Short hash of the voice-affecting [tts] fields, with prompt_text resolved the way the
+// server resolves it so editing a transcript file regenerates the voices; empty without
+// a [tts] section
+fn voice_params_hash(config: &config::Config) -> Result<String> {
+    let tts = match config.get::<serde_json::Map<String, serde_json::Value>>("tts") {
+        Ok(tts) => tts,
+        Err(config::ConfigError::NotFound(_)) => return Ok(String::new()),
+        Err(e) => return Err(e).context("Failed to read [tts] settings"),
+    };
+    let mut params: serde_json::Map<String, serde_json::Value> = tts
+        .into_iter()
+        .filter(|(field, _)| VOICE_PARAM_FIELDS.contains(&field.as_str()))
+        .collect();
+    if let Some(prompt_text) = resolved_prompt_text(&params) {
+        params.remove("prompt_text_file");
+        params.insert("prompt_text".to_string(), serde_json::Value::String(prompt_text));
+    }
+    let digest = format!("{:x}", md5::compute(serde_json::Value::Object(params).to_string()));
+    Ok(digest[..8].to_string())
+}
+
+// The prompt text the server ends up sending: prompt_text_file's contents, else prompt_text,
+// else the {ref_audio_path}.txt transcript. None when a configured file can't be read here,
+// leaving the fields as written to be hashed.
+fn resolved_prompt_text(tts: &serde_json::Map<String, serde_json::Value>) -> Option<String> {
+    let field = |name: &str| tts.get(name).and_then(|value| value.as_str()).unwrap_or("");
+
+    let prompt_text_file = field("prompt_text_file");
+    let prompt_text = if prompt_text_file.is_empty() {
+        field("prompt_text").to_string()
+    } else {
+        std::fs::read_to_string(prompt_text_file).ok()?.trim().to_string()
+    };
+    if !prompt_text.is_empty() {
+        return Some(prompt_text);
+    }
+
+    let ref_audio_path = field("ref_audio_path");
+    if ref_audio_path.is_empty() || ref_audio_path.contains("://") {
+        return Some(prompt_text);
+    }
+    match std::fs::read_to_string(format!("{}.txt", ref_audio_path)) {
+        Ok(transcript) => Some(transcript.trim().to_string()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Some(prompt_text),
+        Err(_) => None,
+    }
+}
+
+// Short hash of each [voices.<id>] entry as written in the config, so changing a
+// character's voice regenerates only that character's lines
+fn voice_hashes(config: &config::Config) -> Result<HashMap<String, String>> {
+    let voices = match config.get::<serde_json::Map<String, serde_json::Value>>("voices") {
+        Ok(voices) => voices,
+        Err(config::ConfigError::NotFound(_)) => return Ok(HashMap::new()),
+        Err(e) => return Err(e).context("Failed to read [voices] settings"),
+    };
+    Ok(voices
+        .into_iter()
+        .map(|(id, params)| {
+            let digest = format!("{:x}", md5::compute(params.to_string()));
+            (id, digest[..8].to_string())
+        })
+        .collect())
+}
+
+// Short hash of each [[profiles]] entry as written in the config, so editing a profile
+// regenerates the lines voiced with it. The base [tts] fields it overrides are already
+// in voice_params_hash, so together they cover the resolved profile.
+fn profile_hashes(config: &config::Config) -> Result<HashMap<String, String>> {
+    let entries = match config.get::<Vec<serde_json::Map<String, serde_json::Value>>>("profiles") {
+        Ok(entries) => entries,
+        Err(config::ConfigError::NotFound(_)) => return Ok(HashMap::new()),
+        Err(e) => return Err(e).context("Failed to read [[profiles]] settings"),
+    };
+    Ok(entries
+        .into_iter()
+        .filter_map(|entry| {
+            let name = entry.get("name")?.as_str()?.to_string();
+            let digest = format!("{:x}", md5::compute(serde_json::Value::Object(entry).to_string()));
+            Some((name, digest[..8].to_string()))
+        })
+        .collect())
+}
+
+// Short hash of the generation settings, naming the cache subdirectory for auto_cache_subdir.
+// Keys serialize in sorted order, so client and server agree whatever the source layout.
+fn generation_settings_hash(config: &config::Config) -> Result<String> {
+    let mut settings = serde_json::Map::new();
+    for section in ["tts", "tts_remote", "tts_google", "tts_voicevox", "voicevox"] {
+        match config.get::<serde_json::Value>(section) {
+            Ok(value) => {
+                settings.insert(section.to_string(), value);
+            }
+            Err(config::ConfigError::NotFound(_)) => {}
+            Err(e) => return Err(e).context(format!("Failed to read [{}] settings", section)),
+        }
+    }
+    let digest = format!("{:x}", md5::compute(serde_json::Value::Object(settings).to_string()));
+    Ok(digest[..8].to_string())
+}
+
+/// One entry of the `[[text_replacements]]` table
+#[derive(Debug, Deserialize, Clone)]
+pub struct TextReplacement {
+    pub pattern: String,
+    pub replacement: String,
+}
+
+#[derive(Debug, Clone)]
+enum TextTransform {
+    StripRuby,
+    FullwidthToHalfwidth,
+    RegexReplacements(Vec<(regex::Regex, String)>),
+}
+
+impl TextTransform {
+    fn apply(&self, text: &str) -> String {
+        match self {
+            TextTransform::StripRuby => strip_ruby(text),
+            TextTransform::FullwidthToHalfwidth => fullwidth_to_halfwidth(text),
+            TextTransform::RegexReplacements(replacements) => {
+                replacements.iter().fold(text.to_string(), |text, (regex, replacement)| {
+                    regex.replace_all(&text, replacement.as_str()).into_owned()
+                })
+            }
+        }
+    }
+}
+
+/// How `normalize_text` evens out spellings of a line that should share a voice
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct TextNormalization {
+    /// Squeeze internal whitespace runs to a single space (`match_whitespace`)
+    pub collapse_whitespace: bool,
+    /// Compose to Unicode NFC (`unicode_nfc`)
+    pub nfc: bool,
+}
+
+/// Ordered list of text transforms, applied to request text before hashing,
+/// followed by `normalize_text`
+#[derive(Debug, Clone, Default)]
+pub struct TextPipeline {
+    transforms: Vec<TextTransform>,
+    normalization: TextNormalization,
+}
+
+impl TextPipeline {
+    pub fn new(names: &[String], replacements: &[TextReplacement], normalization: TextNormalization) -> Result<Self> {
+        let mut transforms = Vec::new();
+        for name in names {
+            let transform = match name.as_str() {
+                "strip_ruby" => TextTransform::StripRuby,
+                "fullwidth_to_halfwidth" => TextTransform::FullwidthToHalfwidth,
+                "regex_replacements" => {
+                    let mut compiled = Vec::new();
+                    for replacement in replacements {
+                        let regex = regex::Regex::new(&replacement.pattern).context(format!(
+                            "Invalid text replacement pattern: {}",
+                            replacement.pattern
+                        ))?;
+                        compiled.push((regex, replacement.replacement.clone()));
+                    }
+                    TextTransform::RegexReplacements(compiled)
+                }
+                _ => anyhow::bail!("Unknown text transform: {}", name),
+            };
+            transforms.push(transform);
+        }
+        Ok(Self { transforms, normalization })
+    }
+
+    #[allow(dead_code)]
+    pub fn apply(&self, text: &str) -> String {
+        let text = self.transforms
+            .iter()
+            .fold(text.to_string(), |text, transform| transform.apply(&text));
+        normalize_text(&text, self.normalization)
+    }
+
+    // The normalization ending the pipeline, for matching raw lines against the text list
+    #[allow(dead_code)]
+    pub fn normalization(&self) -> TextNormalization {
+        self.normalization
+    }
+}
+
+// The one normalization every line goes through before it is hashed into a cache filename
+// or looked up in a text list: trimmed, optionally NFC-composed and whitespace-collapsed
+#[allow(dead_code)]
+pub fn normalize_text(text: &str, normalization: TextNormalization) -> String {
+    let text = if normalization.nfc {
+        text.nfc().collect::<String>()
+    } else {
+        text.to_string()
+    };
+    if normalization.collapse_whitespace {
+        text.split_whitespace().collect::<Vec<_>>().join(" ")
+    } else {
+        text.trim().to_string()
+    }
+}
+
+lazy_static::lazy_static! {
+    // ｜漢字《かんじ》 or 漢字《かんじ》 style ruby annotations
+    static ref RUBY_ANNOTATION: regex::Regex = regex::Regex::new(r"[|｜]?([^|｜《》]*)《[^》]*》").unwrap();
+    // krkr [ruby text="..."] tags
+    static ref RUBY_TAG: regex::Regex = regex::Regex::new(r"\[ruby[^\]]*\]").unwrap();
+}
+
+// Remove ruby annotations, keeping only the base text
+fn strip_ruby(text: &str) -> String {
+    let text = RUBY_TAG.replace_all(text, "");
+    RUBY_ANNOTATION.replace_all(&text, "$1").into_owned()
+}
+
+// Convert fullwidth ASCII variants and the ideographic space to halfwidth
+fn fullwidth_to_halfwidth(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+            '\u{3000}' => ' ',
+            _ => c,
+        })
+        .collect()
+}
+
+// Look up a cached file across the cache directories, first match wins
+#[allow(dead_code)]
+pub fn find_cached_file(cache_dirs: &[PathBuf], filename: &str) -> Option<PathBuf> {
+    cache_dirs
+        .iter()
+        .map(|dir| dir.join(filename))
+        .find(|path| path.exists())
+}
+
+/// Sidecar file in each cache directory mapping voice filenames back to what they say
+#[allow(dead_code)]
+pub const CACHE_INDEX_FILENAME: &str = "cache_index.json";
+
+/// What a cached voice was generated from, as recorded in `cache_index.json`
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheIndexEntry {
+    /// The line as requested, before text transforms
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voice_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// `voice_params_hash` of the config the voice was generated with
+    #[serde(default)]
+    pub params_hash: String,
+}
+
+/// Map of voice filename -> entry, sorted so the file diffs cleanly
+#[allow(dead_code)]
+pub type CacheIndex = BTreeMap<String, CacheIndexEntry>;
+
+// Serializes read-modify-write cycles on cache indexes between this process's tasks
+#[allow(dead_code)]
+static CACHE_INDEX_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+// Read a cache directory's index, empty when it has none yet
+#[allow(dead_code)]
+pub async fn load_cache_index(cache_dir: &Path) -> Result<CacheIndex> {
+    let path = cache_dir.join(CACHE_INDEX_FILENAME);
+    match tokio::fs::read(&path).await {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .context(format!("Failed to parse cache index {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(CacheIndex::new()),
+        Err(e) => Err(anyhow::Error::new(e).context(format!("Failed to read cache index {}", path.display()))),
+    }
+}
+
+// Apply a change to a cache directory's index, one task at a time so concurrent
+// generations never drop each other's entries. The new index is written beside the
+// old one and renamed over it; an index that no longer parses is started over.
+#[allow(dead_code)]
+pub async fn update_cache_index(cache_dir: &Path, change: impl FnOnce(&mut CacheIndex)) -> Result<()> {
+    let _guard = CACHE_INDEX_LOCK.lock().await;
+    let mut index = match load_cache_index(cache_dir).await {
+        Ok(index) => index,
+        Err(e) if e.is::<serde_json::Error>() || e.chain().any(|cause| cause.is::<serde_json::Error>()) => {
+            log_level(LogLevel::Warn, &format!("{}, starting a new cache index", e));
+            CacheIndex::new()
+        }
+        Err(e) => return Err(e),
+    };
+    change(&mut index);
+
+    let path = cache_dir.join(CACHE_INDEX_FILENAME);
+    let temp_path = cache_dir.join(format!("{}.part", CACHE_INDEX_FILENAME));
+    tokio::fs::write(&temp_path, serde_json::to_vec_pretty(&index)?)
+        .await
+        .context(format!("Failed to write cache index {}", temp_path.display()))?;
+    tokio::fs::rename(&temp_path, &path)
+        .await
+        .context(format!("Failed to replace cache index {}", path.display()))?;
+    Ok(())
+}
+
+// Find the first cache directory that can be created and written to
+#[allow(dead_code)]
+pub fn first_writable_dir(cache_dirs: &[PathBuf]) -> Option<PathBuf> {
+    cache_dirs.iter().find(|dir| is_writable_dir(dir)).cloned()
+}
+
+fn is_writable_dir(dir: &Path) -> bool {
+    if std::fs::create_dir_all(dir).is_err() {
+        return false;
+    }
+
+    // Probe with a throwaway file, since permission bits alone don't cover read-only mounts
+    let probe = dir.join(format!(".krkr-tts-probe-{}", std::process::id()));
+    match File::create(&probe) {
+        Ok(_) => {
+            let _ = std::fs::remove_file(&probe);
+            true
+        }
+        Err(_) => false,
+    }
 }
 
 // Calculate a stable identifier for a text list file
@@ -161,34 +1550,125 @@ pub fn voice_cache_filename(text_list_path: &Path, line_number: usize) -> String
     format!("{}_{}.wav", text_list_id, line_number)
 }
 
-// Find line position in text list - used by client
+// Look up the encoding named by text_list_encoding. Besides the standard labels,
+// "cp932" is accepted as the Windows name for Shift-JIS.
 #[allow(dead_code)]
-pub async fn find_position_in_text_list(text_list_path: &PathBuf, target_text: &str) -> Result<usize> {
-    use tokio::io::{AsyncBufReadExt, BufReader};
-    use tokio::fs::File;
-    
-    let file = File::open(text_list_path)
+pub fn text_list_encoding(name: &str) -> Result<&'static encoding_rs::Encoding> {
+    if name.eq_ignore_ascii_case("cp932") {
+        return Ok(encoding_rs::SHIFT_JIS);
+    }
+    encoding_rs::Encoding::for_label(name.trim().as_bytes())
+        .context(format!("Unknown text_list_encoding: {} (expected utf-8, shift_jis or cp932)", name))
+}
+
+// Read a text list's lines, decoding the file from the given encoding
+#[allow(dead_code)]
+pub async fn read_text_list(text_list_path: &Path, encoding: &str) -> Result<Vec<String>> {
+    let encoding = text_list_encoding(encoding)?;
+    let bytes = tokio::fs::read(text_list_path)
         .await
-        .context("Failed to open text list file")?;
-    let reader = BufReader::new(file);
-    let mut lines = reader.lines();
+        .context(format!("Failed to open text list file: {}", text_list_path.display()))?;
     
-    let mut position = 0;
-    while let Some(line) = lines.next_line().await? {
-        if line.trim() == target_text {
-            return Ok(position);
-        }
-        position += 1;
+    let (content, _, had_errors) = encoding.decode(&bytes);
+    if had_errors {
+        log_message(&format!(
+            "Warning: text list {} is not valid {}, some characters could not be decoded",
+            text_list_path.display(),
+            encoding.name()
+        ));
     }
+    Ok(content.lines().map(str::to_string).collect())
+}
+
+// Find line position in text list - used by client
+#[allow(dead_code)]
+pub async fn find_position_in_text_list(
+    text_list_path: &Path,
+    target_text: &str,
+    encoding: &str,
+    normalization: TextNormalization,
+) -> Result<usize> {
+    let lines = read_text_list(text_list_path, encoding).await?;
+    let target_text = normalize_text(target_text, normalization);
     
     // If text not found, return the position at end of file
-    Ok(position)
+    Ok(lines
+        .iter()
+        .position(|line| normalize_text(line, normalization) == target_text)
+        .unwrap_or(lines.len()))
+}
+
+// Encoding of a framed request or response payload
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    Json,
+    /// Indented JSON, easier to read in a packet capture
+    JsonPretty,
+    /// MessagePack with field names, much smaller for big requests
+    MessagePack,
+}
+
+#[allow(dead_code)]
+impl WireFormat {
+    // Parse the `wire_format` config value
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "json" => Ok(WireFormat::Json),
+            "json_pretty" => Ok(WireFormat::JsonPretty),
+            "msgpack" => Ok(WireFormat::MessagePack),
+            _ => anyhow::bail!("Invalid wire_format: {} (expected json, json_pretty or msgpack)", name),
+        }
+    }
+
+    // Tell the format of a received payload: JSON objects open with '{' (after any
+    // whitespace), which no MessagePack map starts with
+    pub fn detect(payload: &[u8]) -> Self {
+        match payload.iter().find(|byte| !byte.is_ascii_whitespace()) {
+            Some(b'{') => WireFormat::Json,
+            _ => WireFormat::MessagePack,
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            WireFormat::Json => serde_json::to_vec(value).context("Failed to serialize JSON payload"),
+            WireFormat::JsonPretty => serde_json::to_vec_pretty(value).context("Failed to serialize JSON payload"),
+            // Named fields, so #[serde(default)] fields stay optional as in JSON
+            WireFormat::MessagePack => rmp_serde::to_vec_named(value).context("Failed to serialize MessagePack payload"),
+        }
+    }
+
+    pub fn decode<T: serde::de::DeserializeOwned>(&self, payload: &[u8]) -> Result<T> {
+        match self {
+            WireFormat::Json | WireFormat::JsonPretty => {
+                serde_json::from_slice(payload).context("Failed to deserialize JSON payload")
+            }
+            WireFormat::MessagePack => {
+                rmp_serde::from_slice(payload).context("Failed to deserialize MessagePack payload")
+            }
+        }
+    }
 }
 
 // Communication structures
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RequestType {
     GenerateVoice,
+    /// Abort the in-progress generation of this text's voice (honoring profile, streaming
+    /// and voice_id), replying whether one was running
+    Cancel { text: String },
+    /// Delete the cached voice for this text, replying with what was removed
+    EvictCache { text: String },
+    /// Make the provider load its models without caching anything, replying when done
+    PrewarmProvider,
+    /// Reply with a JSON snapshot of in-progress generations, loaded text lists,
+    /// concurrency and the first uncached text list line from `text` on (the top of the
+    /// list when empty) in the response message
+    QueryStatus,
+    /// Re-read the server's own config file and rebuild its provider, replying with the
+    /// changed fields; what SIGHUP does on Unix
+    ReloadConfig,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -198,6 +1678,101 @@ pub struct VoiceRequest {
     pub output_path: PathBuf,
     pub cache_dir: Option<PathBuf>,
     pub config_path: PathBuf,
+    /// Hash of the request contents, lets the server drop retried duplicates
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// Base64-encoded reference clip to use instead of the configured one
+    #[serde(default)]
+    pub ref_audio_base64: Option<String>,
+    /// Lines that may follow this one (e.g. every choice branch), prefetched
+    /// instead of guessing from the text list
+    #[serde(default)]
+    pub next_texts: Vec<String>,
+    /// Generate before replying with a `VoiceResponse` on the same connection
+    #[serde(default)]
+    pub wait: bool,
+    /// Named `[[profiles]]` parameter set to generate with instead of the base `[tts]` one
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Overrides `tts.streaming_mode` for this request
+    #[serde(default)]
+    pub streaming: Option<bool>,
+    /// Character voice from `[voices.<id>]` to speak with instead of the `[tts]` one
+    #[serde(default)]
+    pub voice_id: Option<String>,
+    /// Generate before replying, sending the audio back in `StreamFrame`s as it arrives
+    #[serde(default)]
+    pub stream: bool,
+}
+
+/// One message of the reply to a `stream` request, each sent length-prefixed like a
+/// `VoiceResponse`: audio chunks in order, then a single `End` with the outcome
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum StreamFrame {
+    /// The next bytes of the voice
+    Audio(Vec<u8>),
+    /// Discard the audio sent so far, the whole voice follows again
+    Restart,
+    /// No more audio; where the voice was cached, or why it failed
+    End(VoiceResponse),
+}
+
+// Leading byte of each encoded StreamFrame
+const STREAM_FRAME_AUDIO: u8 = 0;
+const STREAM_FRAME_RESTART: u8 = 1;
+const STREAM_FRAME_END: u8 = 2;
+
+#[allow(dead_code)]
+impl StreamFrame {
+    // Audio goes out as raw bytes after the tag, the final response in the request's wire format
+    pub fn encode(&self, wire_format: WireFormat) -> Result<Vec<u8>> {
+        Ok(match self {
+            StreamFrame::Audio(audio) => [&[STREAM_FRAME_AUDIO][..], audio].concat(),
+            StreamFrame::Restart => vec![STREAM_FRAME_RESTART],
+            StreamFrame::End(response) => [vec![STREAM_FRAME_END], wire_format.encode(response)?].concat(),
+        })
+    }
+
+    pub fn decode(payload: &[u8], wire_format: WireFormat) -> Result<Self> {
+        match payload.split_first() {
+            Some((&STREAM_FRAME_AUDIO, audio)) => Ok(StreamFrame::Audio(audio.to_vec())),
+            Some((&STREAM_FRAME_RESTART, _)) => Ok(StreamFrame::Restart),
+            Some((&STREAM_FRAME_END, response)) => Ok(StreamFrame::End(wire_format.decode(response)?)),
+            Some((tag, _)) => anyhow::bail!("Unknown stream frame type {}", tag),
+            None => anyhow::bail!("Empty stream frame"),
+        }
+    }
+}
+
+/// Machine-readable category of a failed request, so callers can react without parsing messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The TTS provider could not be reached
+    Connection,
+    /// The TTS provider took too long to answer
+    Timeout,
+    /// The TTS provider rejected the request (4xx)
+    ProviderClient,
+    /// The TTS provider failed while handling the request (5xx)
+    ProviderServer,
+    /// Reading or writing the voice cache failed
+    CacheIo,
+    /// The requested config could not be loaded or isn't allowed
+    Config,
+    /// Any other failure
+    Other,
+}
+
+// Shown by the name it has on the wire, taken from serde so the two can't drift apart
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(name)) => f.write_str(&name),
+            _ => Err(std::fmt::Error),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -205,11 +1780,329 @@ pub struct VoiceResponse {
     pub success: bool,
     pub message: String,
     pub cache_path: Option<PathBuf>,
+    // Set on failures; absent from servers that predate it
+    #[serde(default)]
+    pub error_kind: Option<ErrorKind>,
+}
+
+// Generate a cache filename based on text content using MD5 hash. The text must have
+// been through TextPipeline::apply (which ends in normalize_text) so that client,
+// server and prefetch name the same line alike; variant suffixes are appended after
+#[allow(dead_code)]
+pub fn generate_cache_filename(text: &str, general_config: &GeneralConfig) -> String {
+    // Voices from different [tts] parameters must not share a file
+    let key_text = if general_config.voice_params_hash.is_empty() {
+        text.to_string()
+    } else {
+        format!("{}\nvoice:{}", text, general_config.voice_params_hash)
+    };
+    let text_hash = format!("{:x}", md5::compute(key_text.as_bytes()));
+    format!("{}.{}", text_hash, general_config.output_format)
+}
+
+// Generate a cache filename for text spoken with a one-off reference clip,
+// so it never collides with the configured voice
+#[allow(dead_code)]
+pub fn generate_ref_cache_filename(text: &str, ref_audio: &[u8], general_config: &GeneralConfig) -> String {
+    let ref_hash = md5::compute(ref_audio);
+    generate_cache_filename(&format!("{}\n{:x}", text, ref_hash), general_config)
 }
 
-// Generate a cache filename based on text content using MD5 hash
+// Text whose hash names a voice generated with per-request parameter changes,
+// so variants never share a cache entry with each other or the base config
 #[allow(dead_code)]
-pub fn generate_cache_filename(text: &str) -> String {
-    let text_hash = format!("{:x}", md5::compute(text.as_bytes()));
-    format!("{}.wav", text_hash)
-} 
\ No newline at end of file
+pub fn variant_cache_text(
+    text: &str,
+    profile: Option<&str>,
+    streaming: Option<bool>,
+    voice_id: Option<&str>,
+    general_config: &GeneralConfig,
+) -> String {
+    let mut key_text = text.to_string();
+    if let Some(profile) = profile {
+        let params_hash = general_config.profile_hashes.get(profile).map_or("", String::as_str);
+        key_text.push_str(&format!("\nprofile:{}:{}", profile, params_hash));
+    }
+    if let Some(voice_id) = voice_id {
+        let params_hash = general_config.voice_hashes.get(voice_id).map_or("", String::as_str);
+        key_text.push_str(&format!("\nvoice_id:{}:{}", voice_id, params_hash));
+    }
+    if let Some(streaming) = streaming {
+        key_text.push_str(&format!("\nstreaming:{}", streaming));
+    }
+    key_text
+}
+
+// Generate an idempotency key from the request text and every parameter that changes the
+// voice or where it goes, so only true retries of a request share a key
+#[allow(dead_code)]
+pub fn generate_idempotency_key(request: &VoiceRequest) -> String {
+    let mut context = md5::Context::new();
+    for part in [
+        request.text.as_str(),
+        &request.output_path.to_string_lossy(),
+        &request.cache_dir.as_ref().map(|dir| dir.to_string_lossy()).unwrap_or_default(),
+        &request.config_path.to_string_lossy(),
+        &format!("{:?}", request.profile),
+        &format!("{:?}", request.streaming),
+        &format!("{:?}", request.voice_id),
+        request.ref_audio_base64.as_deref().unwrap_or_default(),
+    ] {
+        context.consume(part.as_bytes());
+        // Separator so that adjacent fields can't run into each other
+        context.consume([0u8]);
+    }
+    format!("{:x}", context.compute())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Build a config from TOML text, the way a config file is read
+    fn config_from_toml(toml: &str) -> config::Config {
+        config::Config::builder()
+            .add_source(config::File::from_str(toml, config::FileFormat::Toml))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn minimal_general_config_fills_in_defaults() {
+        let config = config_from_toml("[general]\ncache_dir = \"voices\"\n");
+        let general_config = load_general_config(&config).unwrap();
+        let defaults = GeneralConfig::default();
+
+        assert_eq!(general_config.cache_dir.paths(), vec![PathBuf::from("voices")]);
+        assert_eq!(general_config.server_port, defaults.server_port);
+        assert_eq!(general_config.bind_address, defaults.bind_address);
+        assert_eq!(general_config.prefetch_count, defaults.prefetch_count);
+        assert_eq!(general_config.max_concurrent_tts, defaults.max_concurrent_tts);
+        assert_eq!(general_config.output_format, defaults.output_format);
+        assert_eq!(general_config.wire_format, defaults.wire_format);
+        assert_eq!(general_config.http_port, None);
+        assert!(general_config.validate().is_ok());
+    }
+
+    #[test]
+    fn empty_general_section_loads() {
+        let general_config = load_general_config(&config_from_toml("[general]\n")).unwrap();
+        assert_eq!(general_config.server_port, GeneralConfig::default().server_port);
+    }
+
+    #[test]
+    fn general_section_is_required() {
+        assert!(load_general_config(&config_from_toml("[tts]\nbase_url = \"http://127.0.0.1:9880/tts\"\n")).is_err());
+    }
+
+    // Write a config file unique to this test run, returning its path
+    fn write_config_file(name: &str, toml: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("krkr-tts-test-{}-{}.toml", std::process::id(), name));
+        std::fs::write(&path, toml).unwrap();
+        path
+    }
+
+    fn general_config_from(config_path: &Path, inline_config: Option<&str>, stdin: &str) -> GeneralConfig {
+        let config = build_config_from(config_path, inline_config.map(str::to_string), stdin.as_bytes()).unwrap();
+        load_general_config(&config).unwrap()
+    }
+
+    #[test]
+    fn config_is_read_from_the_file() {
+        let path = write_config_file("file", "[general]\nserver_port = 5000\n");
+        let general_config = general_config_from(&path, None, "");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(general_config.server_port, 5000);
+    }
+
+    #[test]
+    fn missing_file_is_an_error_without_inline_config() {
+        let path = std::env::temp_dir().join("krkr-tts-test-no-such-config.toml");
+        assert!(build_config_from(&path, None, "".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn inline_config_overrides_the_file() {
+        let path = write_config_file("layered", "[general]\nserver_port = 5000\nprefetch_count = 9\n");
+        let general_config = general_config_from(&path, Some("[general]\nserver_port = 6000\n"), "");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(general_config.server_port, 6000);
+        // Fields the inline config leaves out still come from the file
+        assert_eq!(general_config.prefetch_count, 9);
+    }
+
+    #[test]
+    fn inline_config_alone_needs_no_file() {
+        let path = std::env::temp_dir().join("krkr-tts-test-no-such-config.toml");
+        let general_config = general_config_from(&path, Some("[general]\nserver_port = 6000\n"), "");
+        assert_eq!(general_config.server_port, 6000);
+    }
+
+    #[test]
+    fn dash_reads_the_config_from_stdin() {
+        let general_config = general_config_from(Path::new("-"), None, "[general]\nserver_port = 7000\n");
+        assert_eq!(general_config.server_port, 7000);
+
+        let general_config = general_config_from(
+            Path::new("-"),
+            Some("[general]\nserver_port = 6000\n"),
+            "[general]\nserver_port = 7000\nprefetch_count = 9\n",
+        );
+        assert_eq!(general_config.server_port, 6000);
+        assert_eq!(general_config.prefetch_count, 9);
+    }
+
+    const COLLAPSE_WHITESPACE: TextNormalization = TextNormalization { collapse_whitespace: true, nfc: false };
+
+    #[test]
+    fn leading_and_trailing_whitespace_is_always_trimmed() {
+        assert_eq!(normalize_text("  こんにちは\t\n", TextNormalization::default()), "こんにちは");
+        assert_eq!(normalize_text("  こんにちは\t\n", COLLAPSE_WHITESPACE), "こんにちは");
+    }
+
+    #[test]
+    fn inner_whitespace_is_kept_without_match_whitespace() {
+        assert_eq!(normalize_text(" Hello  world ", TextNormalization::default()), "Hello  world");
+    }
+
+    #[test]
+    fn match_whitespace_collapses_doubled_spaces_and_tabs() {
+        assert_eq!(normalize_text(" Hello  world ", COLLAPSE_WHITESPACE), "Hello world");
+        assert_eq!(normalize_text("Hello\t \tworld", COLLAPSE_WHITESPACE), "Hello world");
+    }
+
+    #[test]
+    fn whitespace_variants_share_a_cache_file_with_match_whitespace() {
+        let general_config = load_general_config(&config_from_toml("[general]\nmatch_whitespace = true\n")).unwrap();
+        let filename = |text: &str| generate_cache_filename(&general_config.text_pipeline.apply(text), &general_config);
+        assert_eq!(filename("Hello world"), filename("  Hello   world\t"));
+
+        let general_config = load_general_config(&config_from_toml("[general]\n")).unwrap();
+        let filename = |text: &str| generate_cache_filename(&general_config.text_pipeline.apply(text), &general_config);
+        assert_eq!(filename("Hello world"), filename("  Hello world\t"));
+        assert_ne!(filename("Hello world"), filename("Hello  world"));
+    }
+
+    #[test]
+    fn editing_a_transcript_file_changes_the_cache_filename() {
+        let transcript = std::env::temp_dir().join(format!("krkr-tts-test-{}-prompt.txt", std::process::id()));
+        let toml = format!("[general]\n[tts]\nprompt_text_file = {:?}\n", transcript.to_str().unwrap());
+        let filename = || generate_cache_filename("こんにちは", &load_general_config(&config_from_toml(&toml)).unwrap());
+
+        std::fs::write(&transcript, "first take").unwrap();
+        let before = filename();
+        std::fs::write(&transcript, "second take").unwrap();
+        let after = filename();
+        std::fs::remove_file(&transcript).unwrap();
+        assert_ne!(before, after);
+    }
+
+    #[test]
+    fn editing_the_reference_transcript_changes_the_cache_filename() {
+        let ref_audio = std::env::temp_dir().join(format!("krkr-tts-test-{}-ref.wav", std::process::id()));
+        let transcript = PathBuf::from(format!("{}.txt", ref_audio.display()));
+        let toml = format!("[general]\n[tts]\nref_audio_path = {:?}\n", ref_audio.to_str().unwrap());
+        let filename = || generate_cache_filename("こんにちは", &load_general_config(&config_from_toml(&toml)).unwrap());
+
+        std::fs::write(&transcript, "first take").unwrap();
+        let before = filename();
+        std::fs::write(&transcript, "second take").unwrap();
+        let after = filename();
+        std::fs::remove_file(&transcript).unwrap();
+        assert_ne!(before, after);
+    }
+
+    const WIRE_FORMATS: [WireFormat; 3] = [WireFormat::Json, WireFormat::JsonPretty, WireFormat::MessagePack];
+
+    fn sample_request() -> VoiceRequest {
+        VoiceRequest {
+            request_type: RequestType::Cancel { text: "こんにちは".to_string() },
+            text: "こんにちは".to_string(),
+            output_path: PathBuf::from("voice/out.wav"),
+            cache_dir: Some(PathBuf::from("cache")),
+            config_path: PathBuf::from("config/default.toml"),
+            idempotency_key: Some("key".to_string()),
+            ref_audio_base64: None,
+            next_texts: vec!["次".to_string()],
+            wait: true,
+            profile: Some("fast".to_string()),
+            streaming: Some(false),
+            voice_id: None,
+            stream: false,
+        }
+    }
+
+    #[test]
+    fn wire_format_names_parse() {
+        assert_eq!(WireFormat::from_name("json").unwrap(), WireFormat::Json);
+        assert_eq!(WireFormat::from_name("json_pretty").unwrap(), WireFormat::JsonPretty);
+        assert_eq!(WireFormat::from_name("msgpack").unwrap(), WireFormat::MessagePack);
+        assert!(WireFormat::from_name("xml").is_err());
+    }
+
+    #[test]
+    fn requests_round_trip_in_every_wire_format() {
+        let request = sample_request();
+        for wire_format in WIRE_FORMATS {
+            let payload = wire_format.encode(&request).unwrap();
+            let decoded: VoiceRequest = wire_format.decode(&payload).unwrap();
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", request), "{:?}", wire_format);
+        }
+    }
+
+    #[test]
+    fn received_payloads_are_told_apart() {
+        let request = sample_request();
+        assert_eq!(WireFormat::detect(&WireFormat::Json.encode(&request).unwrap()), WireFormat::Json);
+        assert_eq!(WireFormat::detect(&WireFormat::JsonPretty.encode(&request).unwrap()), WireFormat::Json);
+        assert_eq!(WireFormat::detect(&WireFormat::MessagePack.encode(&request).unwrap()), WireFormat::MessagePack);
+    }
+
+    #[test]
+    fn omitted_optional_fields_decode_in_every_wire_format() {
+        let minimal = serde_json::json!({
+            "request_type": "GenerateVoice",
+            "text": "テスト",
+            "output_path": "out.wav",
+            "cache_dir": null,
+            "config_path": "config/default.toml",
+        });
+        for wire_format in WIRE_FORMATS {
+            let payload = wire_format.encode(&minimal).unwrap();
+            let decoded: VoiceRequest = wire_format.decode(&payload).unwrap();
+            assert_eq!(decoded.text, "テスト");
+            assert!(!decoded.wait && !decoded.stream && decoded.profile.is_none());
+        }
+    }
+
+    #[test]
+    fn stream_frames_round_trip_in_every_wire_format() {
+        for wire_format in WIRE_FORMATS {
+            let audio = StreamFrame::Audio(vec![1, 2, 3]).encode(wire_format).unwrap();
+            assert!(matches!(StreamFrame::decode(&audio, wire_format).unwrap(), StreamFrame::Audio(audio) if audio == [1, 2, 3]));
+
+            let restart = StreamFrame::Restart.encode(wire_format).unwrap();
+            assert!(matches!(StreamFrame::decode(&restart, wire_format).unwrap(), StreamFrame::Restart));
+
+            let end = StreamFrame::End(VoiceResponse {
+                success: false,
+                message: "failed".to_string(),
+                cache_path: None,
+                error_kind: Some(ErrorKind::Timeout),
+            }).encode(wire_format).unwrap();
+            let StreamFrame::End(response) = StreamFrame::decode(&end, wire_format).unwrap() else {
+                panic!("expected an end frame in {:?}", wire_format);
+            };
+            assert!(!response.success);
+            assert_eq!(response.message, "failed");
+            assert_eq!(response.error_kind, Some(ErrorKind::Timeout));
+        }
+    }
+
+    #[test]
+    fn error_kinds_display_their_wire_names() {
+        assert_eq!(ErrorKind::ProviderClient.to_string(), "provider_client");
+        assert_eq!(ErrorKind::CacheIo.to_string(), "cache_io");
+        assert_eq!(ErrorKind::Other.to_string(), "other");
+    }
+}
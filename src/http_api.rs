@@ -0,0 +1,153 @@
+use anyhow::{Context, Result};
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{header, HeaderName, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::{Mutex, Semaphore};
+use tokio_util::io::ReaderStream;
+
+use crate::common::{log_message, GeneralConfig};
+use crate::stats::{StatsSnapshot, STATS};
+use crate::wav;
+use crate::{load_or_get_config, process_voice_request, TtsProvider, VoiceJob, VoiceManager};
+
+// Shared server state handed to every HTTP request
+#[derive(Clone)]
+pub struct HttpState {
+    pub provider: Arc<dyn TtsProvider>,
+    pub config_cache: Arc<Mutex<HashMap<PathBuf, GeneralConfig>>>,
+    pub semaphore: Arc<Semaphore>,
+    pub voice_manager: Arc<Mutex<VoiceManager>>,
+    /// Config used for HTTP requests, since they don't name one themselves
+    pub config_path: PathBuf,
+}
+
+// JSON body of a POST /generate request
+#[derive(Debug, Deserialize)]
+struct GenerateRequest {
+    text: String,
+    #[serde(default)]
+    cache_dir: Option<PathBuf>,
+    /// Base64-encoded reference clip to use instead of the configured one
+    #[serde(default)]
+    ref_audio_base64: Option<String>,
+    /// Lines that may follow this one, prefetched instead of the text list lookahead
+    #[serde(default)]
+    next_texts: Vec<String>,
+    /// Named parameter profile to generate with
+    #[serde(default)]
+    profile: Option<String>,
+    /// Overrides the configured streaming_mode
+    #[serde(default)]
+    streaming: Option<bool>,
+    /// Character voice from `[voices.<id>]` to speak with
+    #[serde(default)]
+    voice_id: Option<String>,
+    /// Serve the voice from this many milliseconds in, e.g. to resume a line mid-way
+    #[serde(default)]
+    start_ms: Option<u64>,
+}
+
+// Run the HTTP front-end until it fails
+pub async fn serve(address: &str, state: HttpState) -> Result<()> {
+    let app = Router::new()
+        .route("/generate", post(generate))
+        .route("/stats", get(stats))
+        .with_state(state);
+
+    let listener = TcpListener::bind(address)
+        .await
+        .context(format!("Failed to bind HTTP server to {}", address))?;
+
+    log_message(&format!("HTTP server listening on {}", address));
+
+    axum::serve(listener, app)
+        .await
+        .context("HTTP server stopped unexpectedly")
+}
+
+// Report cache hit rate and recent generation latency
+async fn stats() -> Json<StatsSnapshot> {
+    Json(STATS.snapshot())
+}
+
+// Generate (or look up) a voice and stream the WAV back
+async fn generate(State(state): State<HttpState>, Json(request): Json<GenerateRequest>) -> Response {
+    log_message(&format!("Received HTTP request for text: {}", request.text));
+
+    match generate_voice(&state, request).await {
+        Ok(response) => response,
+        Err(e) => {
+            log_message(&format!("Error processing HTTP request: {}", e));
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
+
+// MIME type of a voice file, by the output_format extension it was cached with
+fn content_type(voice_path: &Path) -> &'static str {
+    match voice_path.extension().and_then(|extension| extension.to_str()) {
+        Some("ogg" | "opus") => "audio/ogg",
+        Some("mp3") => "audio/mpeg",
+        _ => "audio/wav",
+    }
+}
+
+async fn generate_voice(state: &HttpState, request: GenerateRequest) -> Result<Response> {
+    // Hold a permit for the whole generation, the caller is waiting on it
+    let _permit = state.semaphore.acquire().await?;
+
+    // The server's own config is always cached, so it never needs the allowlist
+    let general_config = load_or_get_config(&state.config_cache, &state.config_path, &[]).await?;
+
+    let start_ms = request.start_ms.filter(|&start_ms| start_ms > 0);
+    let voice = process_voice_request(
+        state.provider.clone(),
+        &general_config,
+        VoiceJob {
+            text: request.text,
+            cache_dir: request.cache_dir,
+            ref_audio_base64: request.ref_audio_base64,
+            next_texts: request.next_texts,
+            profile: request.profile,
+            streaming: request.streaming,
+            voice_id: request.voice_id,
+            audio_sink: None,
+        },
+        state.voice_manager.clone(),
+    ).await?;
+
+    // A seek needs the whole clip to rewrite its header, anything else streams from disk
+    let body = match start_ms {
+        Some(start_ms) => {
+            let audio = tokio::fs::read(&voice.path)
+                .await
+                .context(format!("Failed to read cached voice {}", voice.path.display()))?;
+            let seeked = wav::seek(&audio, start_ms)
+                .context("start_ms needs the voice to be a PCM WAV")?;
+            Body::from(seeked)
+        }
+        None => {
+            let file = tokio::fs::File::open(&voice.path)
+                .await
+                .context(format!("Failed to open cached voice {}", voice.path.display()))?;
+            Body::from_stream(ReaderStream::new(file))
+        }
+    };
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type(&voice.path)),
+            (HeaderName::from_static("x-placeholder"), if voice.placeholder { "true" } else { "false" }),
+            (HeaderName::from_static("x-draft"), if voice.draft { "true" } else { "false" }),
+        ],
+        body,
+    ).into_response())
+}
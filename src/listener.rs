@@ -0,0 +1,105 @@
+// Protocol listener over TCP or a local socket (a named pipe on Windows), handing out
+// connections that all speak the same length-prefixed framing
+use crate::common::{local_socket_path, log_message, tcp_socket, Connection, GeneralConfig};
+use anyhow::{Context, Result};
+use std::net::SocketAddr;
+
+pub enum Listener {
+    Tcp {
+        listener: tokio::net::TcpListener,
+        nodelay: bool,
+    },
+    #[cfg(unix)]
+    Unix {
+        listener: tokio::net::UnixListener,
+        path: std::path::PathBuf,
+    },
+    // Each pipe instance serves one client, so the next one is created before handing it out
+    #[cfg(windows)]
+    Pipe {
+        next: tokio::net::windows::named_pipe::NamedPipeServer,
+        path: String,
+    },
+}
+
+impl Listener {
+    // Listen over the configured transport, on `address` for TCP
+    pub async fn bind(config: &GeneralConfig, address: SocketAddr) -> Result<Self> {
+        if config.transport == "uds" {
+            return Self::bind_local(&local_socket_path(config)).await;
+        }
+
+        // Accepted connections inherit the listening socket's buffer sizes
+        let socket = tcp_socket(config, &address)?;
+        socket.set_reuseaddr(true)?;
+        socket.bind(address)
+            .context(format!("Failed to bind to {}", address))?;
+        let listener = socket.listen(1024)
+            .context(format!("Failed to listen on {}", address))?;
+        log_message(&format!("Server listening on {}", address));
+        Ok(Listener::Tcp { listener, nodelay: config.tcp_nodelay })
+    }
+
+    // A socket file left by a server that didn't stop cleanly is replaced, but one
+    // that still answers belongs to a running server and is left alone
+    #[cfg(unix)]
+    async fn bind_local(path: &str) -> Result<Self> {
+        let path = std::path::PathBuf::from(path);
+        if path.exists() {
+            if tokio::net::UnixStream::connect(&path).await.is_ok() {
+                anyhow::bail!("Another server is already listening on {}", path.display());
+            }
+            std::fs::remove_file(&path)
+                .context(format!("Failed to remove stale socket {}", path.display()))?;
+        }
+        let listener = tokio::net::UnixListener::bind(&path)
+            .context(format!("Failed to listen on {}", path.display()))?;
+        log_message(&format!("Server listening on {}", path.display()));
+        Ok(Listener::Unix { listener, path })
+    }
+
+    #[cfg(windows)]
+    async fn bind_local(path: &str) -> Result<Self> {
+        let next = tokio::net::windows::named_pipe::ServerOptions::new()
+            .first_pipe_instance(true)
+            .create(path)
+            .context(format!("Failed to create pipe {} (is another server using it?)", path))?;
+        log_message(&format!("Server listening on {}", path));
+        Ok(Listener::Pipe { next, path: path.to_string() })
+    }
+
+    // Wait for the next client, returning its connection and a description for the log
+    pub async fn accept(&mut self) -> Result<(Connection, String)> {
+        match self {
+            Listener::Tcp { listener, nodelay } => {
+                let (socket, addr) = listener.accept().await?;
+                if let Err(e) = socket.set_nodelay(*nodelay) {
+                    log_message(&format!("Failed to set TCP_NODELAY for {}: {}", addr, e));
+                }
+                Ok((Connection::Tcp(socket), addr.to_string()))
+            }
+            #[cfg(unix)]
+            Listener::Unix { listener, path } => {
+                let (socket, _) = listener.accept().await?;
+                Ok((Connection::Unix(socket), path.display().to_string()))
+            }
+            #[cfg(windows)]
+            Listener::Pipe { next, path } => {
+                next.connect().await?;
+                let replacement = tokio::net::windows::named_pipe::ServerOptions::new().create(&*path)?;
+                let connected = std::mem::replace(next, replacement);
+                Ok((Connection::PipeServer(connected), path.clone()))
+            }
+        }
+    }
+}
+
+// Remove the socket file on shutdown, so clients fail fast instead of finding a dead socket
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix { path, .. } = self {
+            let _ = std::fs::remove_file(&*path);
+        }
+    }
+}
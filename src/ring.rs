@@ -0,0 +1,48 @@
+// Ring buffer delivery: served voices copied into numbered slots for streaming consumers
+use anyhow::{Context, Result};
+use std::path::Path;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+// Index file consumers tail to learn which slot holds the newest voice
+const INDEX_FILENAME: &str = "index.txt";
+
+lazy_static::lazy_static! {
+    // Voices published since startup, held across the copy so slots and index lines stay in order
+    static ref SEQUENCE: Mutex<u64> = Mutex::new(0);
+}
+
+// Copy a voice into the next slot (0001.wav, 0002.wav, ..., keeping the voice's extension),
+// overwriting the oldest once the buffer is full, then append "<slot file>\t<sequence>\t<text>"
+// to the index
+pub async fn publish(ring_dir: &Path, ring_size: usize, voice_path: &Path, text: &str) -> Result<()> {
+    let mut sequence = SEQUENCE.lock().await;
+
+    fs::create_dir_all(ring_dir)
+        .await
+        .context(format!("Failed to create ring buffer directory {}", ring_dir.display()))?;
+
+    let slot = *sequence % ring_size.max(1) as u64 + 1;
+    let extension = voice_path.extension().and_then(|extension| extension.to_str()).unwrap_or("wav");
+    let slot_filename = format!("{:04}.{}", slot, extension);
+    fs::copy(voice_path, ring_dir.join(&slot_filename))
+        .await
+        .context("Failed to copy voice into ring buffer")?;
+
+    // One line per voice, so keep the text on a single line
+    let line = format!("{}\t{}\t{}\n", slot_filename, *sequence + 1, text.replace(['\r', '\n'], " "));
+    let mut index = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(ring_dir.join(INDEX_FILENAME))
+        .await
+        .context("Failed to open ring buffer index")?;
+    index
+        .write_all(line.as_bytes())
+        .await
+        .context("Failed to append to ring buffer index")?;
+
+    *sequence += 1;
+    Ok(())
+}
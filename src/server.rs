@@ -1,22 +1,35 @@
 use anyhow::{Context, Result};
 use async_trait::async_trait;
+use base64::Engine;
 use clap::Parser;
-use config::{Config, File as ConfigFile};
+use config::Config;
+use futures_util::stream::FuturesUnordered;
 use futures_util::StreamExt;
 use reqwest::Client;
-use serde::Serialize;
-use std::path::PathBuf;
-use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::collections::{HashMap, HashSet};
 use tokio::fs::{self, File as TokioFile};
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{Semaphore, Mutex};
-use tokio::time::{sleep, Duration};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, Mutex, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{sleep, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+mod access_log;
+mod autoscale;
 mod common;
+mod http_api;
+mod listener;
+mod ring;
+mod stats;
+mod transcode;
+mod wav;
 use common::*;
+use listener::Listener;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// Configuration file path
@@ -34,6 +47,22 @@ struct Args {
     /// Number of concurrent TTS requests
     #[arg(short = 'c', long)]
     concurrency: Option<usize>,
+
+    /// Address to listen on (can also be set in config as bind_address)
+    #[arg(long)]
+    bind: Option<String>,
+
+    /// Print the fully resolved configuration as JSON and exit
+    #[arg(long)]
+    print_config: bool,
+
+    /// Log step-by-step detail, including full provider requests (log_level = "debug")
+    #[arg(short = 'v', long)]
+    verbose: bool,
+
+    /// Generate every line of this text list that isn't cached yet, then exit
+    #[arg(long, value_name = "TEXT_LIST")]
+    warm: Option<PathBuf>,
 }
 
 #[derive(Debug, Serialize)]
@@ -69,13 +98,228 @@ struct VoiceManager {
     in_progress: HashMap<String, HashSet<usize>>,
     // Text lists that have been loaded in memory
     loaded_text_lists: HashMap<String, Vec<String>>,
+    // Map of (text_list_path, normalization) -> normalized line -> first position, for O(1) lookups
+    text_list_indexes: HashMap<(String, TextNormalization), HashMap<String, usize>>,
+    // Map of idempotency key -> time first seen
+    recent_requests: HashMap<String, Instant>,
+    // Map of voice hash -> id and cancellation token of its in-flight generation
+    cancel_tokens: HashMap<String, (u64, CancellationToken)>,
+    // Id given to the next generation registered in cancel_tokens
+    next_generation_id: u64,
+    // Map of voice hash -> requests waiting for its running generation to finish
+    generation_waiters: HashMap<String, Arc<Notify>>,
+    // Text and fingerprint of the most recently generated voice
+    last_fingerprint: Option<(String, wav::Fingerprint)>,
+    // Map of running text-list prefetch -> the start position it should prefetch from next
+    prefetch_cursors: HashMap<PrefetchKey, usize>,
+    // Map of voice hash -> path it was served from since startup, for skip_redelivery
+    delivered: HashMap<String, PathBuf>,
+    // Set once the server is stopping, so no new generation starts
+    shutting_down: bool,
 }
 
+// What the server is doing right now, sent as JSON in reply to a status query
+#[derive(Debug, Serialize)]
+struct ServerStatus {
+    // Map of text list path or cache directory -> generations in progress for it
+    in_progress: HashMap<String, usize>,
+    // Generations running right now, including prefetches and draft upgrades
+    in_flight: usize,
+    loaded_text_lists: usize,
+    // Text-list prefetches currently running
+    active_prefetches: usize,
+    concurrency: usize,
+    available_permits: usize,
+    autoscale: bool,
+    // First text list line from the queried one on whose voice isn't cached yet;
+    // null without a text list or once every line is cached
+    first_missing_index: Option<usize>,
+}
+
+// Identifies a text-list prefetch: text list path, cache directories and the voice variant suffix
+type PrefetchKey = (String, Vec<PathBuf>, String);
+
 impl VoiceManager {
     fn new() -> Self {
         Self {
             in_progress: HashMap::new(),
             loaded_text_lists: HashMap::new(),
+            text_list_indexes: HashMap::new(),
+            recent_requests: HashMap::new(),
+            cancel_tokens: HashMap::new(),
+            next_generation_id: 0,
+            generation_waiters: HashMap::new(),
+            last_fingerprint: None,
+            prefetch_cursors: HashMap::new(),
+            delivered: HashMap::new(),
+            shutting_down: false,
+        }
+    }
+
+    // Remember that a voice was served, so skip_redelivery can answer repeats directly
+    fn mark_delivered(&mut self, voice_filename: &str, path: &Path) {
+        self.delivered.insert(voice_hash(voice_filename), path.to_path_buf());
+    }
+
+    // Where a voice was served from earlier this run, if that file is still there
+    fn delivered_path(&self, voice_filename: &str) -> Option<PathBuf> {
+        self.delivered
+            .get(&voice_hash(voice_filename))
+            .filter(|path| path.exists())
+            .cloned()
+    }
+
+    // Start a prefetch from `start_position` unless one is already running for the key,
+    // in which case only move its cursor. Returns whether the caller should run it.
+    fn claim_prefetch(&mut self, key: &PrefetchKey, start_position: usize) -> bool {
+        match self.prefetch_cursors.get_mut(key) {
+            Some(cursor) => {
+                *cursor = start_position;
+                false
+            }
+            None => {
+                self.prefetch_cursors.insert(key.clone(), start_position);
+                true
+            }
+        }
+    }
+
+    // After prefetching from `start_position`, get the position a later trigger moved
+    // the cursor to, or release the prefetch if nothing did
+    fn finish_prefetch(&mut self, key: &PrefetchKey, start_position: usize) -> Option<usize> {
+        match self.prefetch_cursors.get(key) {
+            Some(&cursor) if cursor != start_position => Some(cursor),
+            _ => {
+                self.prefetch_cursors.remove(key);
+                None
+            }
+        }
+    }
+
+    // Get the id and cancellation token for an in-flight generation, creating them unless
+    // a generation of the voice that wasn't cancelled is registered already. Once the server
+    // is stopping, new tokens come back already cancelled.
+    fn register_cancel_token(&mut self, voice_filename: &str) -> (u64, CancellationToken) {
+        let hash = voice_hash(voice_filename);
+        let registered = match self.cancel_tokens.get(&hash) {
+            Some(registered) if !registered.1.is_cancelled() => registered.clone(),
+            _ => {
+                self.next_generation_id += 1;
+                let registered = (self.next_generation_id, CancellationToken::new());
+                self.cancel_tokens.insert(hash, registered.clone());
+                registered
+            }
+        };
+        if self.shutting_down {
+            registered.1.cancel();
+        }
+        registered
+    }
+
+    // Number of generations running right now
+    fn in_flight_count(&self) -> usize {
+        self.cancel_tokens.len()
+    }
+
+    // Cancel every in-flight generation, returning how many weren't cancelled already.
+    // Each one removes its own token once it has stopped.
+    fn cancel_all(&mut self) -> usize {
+        self.cancel_tokens
+            .values()
+            .filter(|(_, token)| !token.is_cancelled())
+            .map(|(_, token)| token.cancel())
+            .count()
+    }
+
+    // Forget the cancellation token once a generation has finished, however it ended,
+    // and wake the requests waiting on it. Only the generation registered under `id`
+    // is removed, so a cancelled one finishing late can't remove its successor.
+    fn remove_cancel_token(&mut self, voice_filename: &str, id: u64) {
+        let hash = voice_hash(voice_filename);
+        if self.cancel_tokens.get(&hash).is_none_or(|(registered, _)| *registered != id) {
+            return;
+        }
+        self.cancel_tokens.remove(&hash);
+        if let Some(waiters) = self.generation_waiters.remove(&hash) {
+            waiters.notify_waiters();
+        }
+    }
+
+    // Notified when the running generation of a voice finishes, successfully or not
+    fn generation_finished(&mut self, voice_filename: &str) -> Arc<Notify> {
+        self.generation_waiters
+            .entry(voice_hash(voice_filename))
+            .or_default()
+            .clone()
+    }
+
+    // Cancel an in-flight generation, returning whether one was found. The token stays
+    // registered until the generation has stopped and removes it itself.
+    fn cancel(&mut self, voice_filename: &str) -> bool {
+        match self.cancel_tokens.get(&voice_hash(voice_filename)) {
+            Some((_, token)) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Remember a generated voice's fingerprint, returning the previous voice's text
+    // if that was different text yet sounded near-identical
+    fn record_fingerprint(&mut self, text: &str, fingerprint: wav::Fingerprint) -> Option<String> {
+        let duplicate = matches!(
+            &self.last_fingerprint,
+            Some((previous_text, previous)) if previous_text != text && previous.is_near(&fingerprint)
+        );
+        let previous = self.last_fingerprint.replace((text.to_string(), fingerprint));
+        previous.filter(|_| duplicate).map(|(previous_text, _)| previous_text)
+    }
+
+    // Check whether a cached voice file belongs to a generation still running
+    fn is_generating_file(&self, voice_path: &Path) -> bool {
+        let Some(voice_filename) = voice_path.file_name().map(|name| name.to_string_lossy()) else {
+            return false;
+        };
+        let cache_path_str = voice_path
+            .parent()
+            .map(|dir| dir.to_string_lossy().to_string())
+            .unwrap_or_default();
+        self.is_in_flight(&voice_filename) || self.is_generating(&cache_path_str, voice_id(&voice_filename))
+    }
+
+    // Check whether a voice is still being generated
+    fn is_in_flight(&self, voice_filename: &str) -> bool {
+        self.cancel_tokens.contains_key(&voice_hash(voice_filename))
+    }
+
+    // Record an idempotency key, returning how long ago it was first seen if within the TTL
+    fn check_recent_request(&mut self, key: &str, ttl: Duration) -> Option<Duration> {
+        let now = Instant::now();
+        self.recent_requests.retain(|_, seen| now.duration_since(*seen) < ttl);
+
+        if let Some(seen) = self.recent_requests.get(key) {
+            return Some(now.duration_since(*seen));
+        }
+
+        self.recent_requests.insert(key.to_string(), now);
+        None
+    }
+
+    // Snapshot the generation state for a status query; concurrency is filled in by the caller
+    fn status(&self) -> ServerStatus {
+        ServerStatus {
+            in_progress: self.in_progress
+                .iter()
+                .map(|(key, lines)| (key.clone(), lines.len()))
+                .collect(),
+            in_flight: self.in_flight_count(),
+            loaded_text_lists: self.loaded_text_lists.len(),
+            active_prefetches: self.prefetch_cursors.len(),
+            concurrency: 0,
+            available_permits: 0,
+            autoscale: false,
+            first_missing_index: None,
         }
     }
 
@@ -92,7 +336,7 @@ impl VoiceManager {
     fn mark_in_progress(&mut self, text_list_path: &str, line_number: usize) {
         self.in_progress
             .entry(text_list_path.to_string())
-            .or_insert_with(HashSet::new)
+            .or_default()
             .insert(line_number);
     }
 
@@ -103,232 +347,1331 @@ impl VoiceManager {
         }
     }
 
-    // Get or load text list
-    async fn get_text_list(&mut self, text_list_path: &str) -> Result<&Vec<String>> {
+    // Get or load text list, decoding it from text_list_encoding
+    async fn get_text_list(&mut self, text_list_path: &str, encoding: &str) -> Result<&Vec<String>> {
         if !self.loaded_text_lists.contains_key(text_list_path) {
             // Load text list from file
-            let file = TokioFile::open(text_list_path)
-                .await
-                .context(format!("Failed to open text list file: {}", text_list_path))?;
+            let text_list = read_text_list(Path::new(text_list_path), encoding).await?;
             
-            let reader = BufReader::new(file);
-            let mut lines = reader.lines();
-            let mut text_list = Vec::new();
-            
-            while let Some(line) = lines.next_line().await? {
-                text_list.push(line);
-            }
-            
-            self.loaded_text_lists.insert(text_list_path.to_string(), text_list);
+            self.preload(text_list_path, text_list);
         }
         
         Ok(self.loaded_text_lists.get(text_list_path).unwrap())
     }
+
+    // Install a text list under a path without reading it from disk, replacing any loaded one
+    fn preload(&mut self, text_list_path: &str, text_list: Vec<String>) {
+        self.text_list_indexes.retain(|(path, _), _| path != text_list_path);
+        self.loaded_text_lists.insert(text_list_path.to_string(), text_list);
+    }
+
+    // Find the first position of a line in a loaded text list, comparing both
+    // sides with the same normalize_text used for hashing
+    fn text_position(&mut self, text_list_path: &str, text: &str, normalization: TextNormalization) -> Option<usize> {
+        let text_list = self.loaded_text_lists.get(text_list_path)?;
+        let index = self.text_list_indexes
+            .entry((text_list_path.to_string(), normalization))
+            .or_insert_with(|| {
+                // Index the first occurrence of each line
+                let mut index = HashMap::new();
+                for (position, line) in text_list.iter().enumerate() {
+                    index.entry(normalize_text(line, normalization)).or_insert(position);
+                }
+                index
+            });
+        index.get(&normalize_text(text, normalization)).copied()
+    }
+}
+
+// Error returned when the disk runs out of space while writing generated audio
+#[derive(Debug)]
+struct DiskFullError {
+    path: PathBuf,
+}
+
+impl std::fmt::Display for DiskFullError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No space left on device while writing {}", self.path.display())
+    }
+}
+
+impl std::error::Error for DiskFullError {}
+
+// A single generation ran past max_generation_secs
+#[derive(Debug)]
+struct GenerationTimeoutError {
+    secs: u64,
+}
+
+impl std::fmt::Display for GenerationTimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Generation took longer than {}s", self.secs)
+    }
+}
+
+impl std::error::Error for GenerationTimeoutError {}
+
+// Non-success HTTP status from a TTS provider, kept typed so clients learn whose side failed
+#[derive(Debug)]
+struct ProviderHttpError {
+    provider: &'static str,
+    status: reqwest::StatusCode,
+    body: String,
+}
+
+impl std::fmt::Display for ProviderHttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} error ({}): {}", self.provider, self.status, self.body)
+    }
+}
+
+impl std::error::Error for ProviderHttpError {}
+
+// Failed request to an upstream krkr-tts server, keeping the category it reported
+#[derive(Debug)]
+struct UpstreamError {
+    kind: ErrorKind,
+    message: String,
+}
+
+impl std::fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Upstream krkr-tts error ({}): {}", self.kind, self.message)
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+// Categorize a failed request from the first recognizable error in its chain
+fn error_kind(error: &anyhow::Error) -> ErrorKind {
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<ProviderHttpError>() {
+            return if e.status.is_server_error() { ErrorKind::ProviderServer } else { ErrorKind::ProviderClient };
+        }
+        if let Some(e) = cause.downcast_ref::<UpstreamError>() {
+            return e.kind;
+        }
+        if cause.is::<GenerationTimeoutError>() {
+            return ErrorKind::Timeout;
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return if e.is_timeout() { ErrorKind::Timeout } else { ErrorKind::Connection };
+        }
+        if cause.is::<DiskFullError>() || cause.is::<std::io::Error>() {
+            return ErrorKind::CacheIo;
+        }
+        if cause.is::<config::ConfigError>() {
+            return ErrorKind::Config;
+        }
+    }
+    ErrorKind::Other
+}
+
+// Hash part of a voice filename, so both "<hash>" and "<hash>.wav" identify a voice
+fn voice_hash(voice_filename: &str) -> String {
+    Path::new(voice_filename)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+// Convert a voice filename to a numerical identifier for the in-memory tracking
+fn voice_id(voice_filename: &str) -> usize {
+    voice_filename.as_bytes().iter().map(|&b| b as usize).sum::<usize>()
+}
+
+// Run a generation that can be aborted through its cancellation token, or after
+// max_generation_secs (0 means no limit) when the provider stalls
+async fn generate_cancellable(
+    provider: &Arc<dyn TtsProvider>,
+    text: &str,
+    output_path: &Path,
+    overrides: &VoiceOverrides,
+    cancel_token: CancellationToken,
+    general_config: &GeneralConfig,
+) -> Result<()> {
+    let max_generation_secs = general_config.max_generation_secs;
+    let time_limit = async {
+        if max_generation_secs > 0 {
+            sleep(Duration::from_secs(max_generation_secs)).await;
+        } else {
+            std::future::pending::<()>().await;
+        }
+    };
+
+    // A generation cancelled while it waited for its turn never calls the provider
+    if cancel_token.is_cancelled() {
+        log_message(&format!("Generation cancelled before it started: {}", output_path.display()));
+        return Err(anyhow::anyhow!("Generation cancelled"));
+    }
+
+    // Voices cached in a compressed format are generated beside the cache path and only
+    // transcoded onto it, so the cache never holds WAV data under another extension
+    let transcoded = transcode::needs_transcoding(&general_config.output_format);
+    let generated_path = if transcoded {
+        source_path(output_path)
+    } else {
+        output_path.to_path_buf()
+    };
+
+    tokio::select! {
+        result = async {
+            let started = Instant::now();
+            generate_with_retries(provider, text, &generated_path, overrides, general_config).await?;
+            stats::STATS.record_latency(started.elapsed());
+            if general_config.output_channels > 0 {
+                convert_output_channels(&generated_path, general_config.output_channels).await?;
+            }
+            if transcoded {
+                transcode_voice(&generated_path, output_path, &general_config.output_format, general_config.keep_source_wav).await?;
+            }
+            Ok(())
+        } => result,
+        _ = cancel_token.cancelled() => {
+            // Dropping the generation future aborts the provider request mid-stream
+            remove_generation_files(output_path, &generated_path).await;
+            log_message(&format!("Generation cancelled: {}", output_path.display()));
+            Err(anyhow::anyhow!("Generation cancelled"))
+        }
+        _ = time_limit => {
+            remove_generation_files(output_path, &generated_path).await;
+            log_level(LogLevel::Warn, &format!(
+                "Generation exceeded {}s, aborted: {}",
+                max_generation_secs, output_path.display()
+            ));
+            Err(GenerationTimeoutError { secs: max_generation_secs }.into())
+        }
+    }
+}
+
+// Generate a voice, retrying up to max_retries times with exponential backoff on failures
+// that may clear up by themselves: connection errors, timeouts and 5xx responses
+async fn generate_with_retries(
+    provider: &Arc<dyn TtsProvider>,
+    text: &str,
+    output_path: &Path,
+    overrides: &VoiceOverrides,
+    general_config: &GeneralConfig,
+) -> Result<()> {
+    let mut delay = Duration::from_millis(general_config.retry_base_delay_ms);
+    let mut attempt = 0;
+    loop {
+        let Err(e) = provider.generate_speech(text, output_path, overrides).await else {
+            return Ok(());
+        };
+        let retryable = matches!(
+            error_kind(&e),
+            ErrorKind::Connection | ErrorKind::Timeout | ErrorKind::ProviderServer
+        );
+        if !retryable || attempt >= general_config.max_retries {
+            return Err(e);
+        }
+
+        // Never let the next attempt start from, or leave behind, a truncated voice
+        let _ = fs::remove_file(output_path).await;
+        attempt += 1;
+        log_level(LogLevel::Warn, &format!(
+            "Generation failed ({}), retry {}/{} in {}ms: {}",
+            e, attempt, general_config.max_retries, delay.as_millis(), text
+        ));
+        sleep(delay).await;
+        delay *= 2;
+    }
+}
+
+// Remove whatever an aborted generation left behind, finished or not
+async fn remove_generation_files(output_path: &Path, generated_path: &Path) {
+    for path in [output_path, generated_path] {
+        let _ = fs::remove_file(path).await;
+        let _ = fs::remove_file(partial_path(path)).await;
+    }
+}
+
+// Encode a generated voice into output_format onto its cache path, through a .part file
+// so readers never see a half-written one. The generated source is removed, unless
+// keep_source moves it next to the finished voice.
+async fn transcode_voice(source: &Path, output_path: &Path, format: &str, keep_source: bool) -> Result<()> {
+    let part_path = partial_path(output_path);
+    let result = transcode::transcode(source, &part_path, format).await;
+    if let Err(e) = result {
+        let _ = fs::remove_file(source).await;
+        let _ = fs::remove_file(&part_path).await;
+        return Err(e);
+    }
+    if let Err(e) = fs::rename(&part_path, output_path).await {
+        let _ = fs::remove_file(source).await;
+        let _ = fs::remove_file(&part_path).await;
+        return Err(anyhow::Error::new(e).context(format!("Failed to move {} into place", part_path.display())));
+    }
+    if !keep_source {
+        let _ = fs::remove_file(source).await;
+    } else if let Err(e) = fs::rename(source, kept_source_path(output_path)).await {
+        let _ = fs::remove_file(source).await;
+        log_level(LogLevel::Warn, &format!("Failed to keep source WAV of {}: {}", output_path.display(), e));
+    }
+    log_level(LogLevel::Debug, &format!("Transcoded voice to {}: {}", format, output_path.display()));
+    Ok(())
+}
+
+// Rewrite a generated voice with the configured number of channels, if it has a different number
+async fn convert_output_channels(output_path: &Path, channels: u16) -> Result<()> {
+    let audio = fs::read(output_path)
+        .await
+        .context("Failed to read generated voice")?;
+    let converted = wav::convert_channels(&audio, channels)
+        .context(format!("Failed to convert {} to {} channel(s)", output_path.display(), channels));
+    match converted {
+        Ok(Some(converted)) => {
+            write_file_atomically(output_path, &converted).await?;
+            log_level(LogLevel::Debug, &format!("Converted {} to {} channel(s)", output_path.display(), channels));
+            Ok(())
+        }
+        Ok(None) => Ok(()),
+        Err(e) => {
+            // A half-usable voice must not stay in the cache
+            let _ = fs::remove_file(output_path).await;
+            Err(e)
+        }
+    }
+}
+
+// Per-request inputs to process_voice_request
+#[derive(Debug, Default)]
+struct VoiceJob {
+    text: String,
+    cache_dir: Option<PathBuf>,
+    ref_audio_base64: Option<String>,
+    next_texts: Vec<String>,
+    profile: Option<String>,
+    streaming: Option<bool>,
+    voice_id: Option<String>,
+    // Receives the audio as the provider delivers it, for a --stream client
+    audio_sink: Option<AudioSink>,
+}
+
+impl From<VoiceRequest> for VoiceJob {
+    fn from(request: VoiceRequest) -> Self {
+        Self {
+            text: request.text,
+            cache_dir: request.cache_dir,
+            ref_audio_base64: request.ref_audio_base64,
+            next_texts: request.next_texts,
+            profile: request.profile,
+            streaming: request.streaming,
+            voice_id: request.voice_id,
+            audio_sink: None,
+        }
+    }
+}
+
+// Channel the chunks of a voice are copied to while it is written to the cache
+type AudioSink = mpsc::UnboundedSender<Vec<u8>>;
+
+// Voice produced for a request
+#[derive(Debug)]
+struct GeneratedVoice {
+    path: PathBuf,
+    // Silent stand-in written because generation failed
+    placeholder: bool,
+    // Quick voice from the draft provider, replaced in the cache once the main provider finishes
+    draft: bool,
+    // Served from the cache without generating
+    cached: bool,
+}
+
+// Per-request changes to the configured voice
+#[derive(Debug, Clone, Default)]
+struct VoiceOverrides {
+    ref_audio_path: Option<String>,
+    // Named parameter profile to use instead of the base config
+    profile: Option<String>,
+    // Replaces the configured streaming_mode
+    streaming: Option<bool>,
+    // Character voice from [voices] to speak with instead of the [tts] one
+    voice_id: Option<String>,
+    // Also send the audio here as it streams in, for providers that stream it
+    audio_sink: Option<AudioSink>,
+}
+
+impl VoiceOverrides {
+    // The overrides that change the generated audio, without the one-off reference clip
+    // or the requesting client's audio sink
+    fn for_job(job: &VoiceJob) -> Self {
+        Self {
+            ref_audio_path: None,
+            profile: job.profile.clone(),
+            streaming: job.streaming,
+            voice_id: job.voice_id.clone(),
+            audio_sink: None,
+        }
+    }
+
+    // Text whose hash names a voice generated with these overrides
+    fn cache_text(&self, text: &str, general_config: &GeneralConfig) -> String {
+        variant_cache_text(
+            text,
+            self.profile.as_deref(),
+            self.streaming,
+            self.voice_id.as_deref(),
+            general_config,
+        )
+    }
 }
 
+// Name of the provider the server runs, for the access log
+static PROVIDER_NAME: OnceLock<String> = OnceLock::new();
+
+// Fast provider that answers cache misses first when general.draft_provider is set,
+// replaced in place when the config is reloaded
+static DRAFT_PROVIDER: OnceLock<Arc<ReloadableProvider>> = OnceLock::new();
+
+// Permits shared by requests and prefetch, bounding how many generations run at once
+static GENERATION_SEMAPHORE: OnceLock<Arc<Semaphore>> = OnceLock::new();
+
+// Re-reads the server's config on SIGHUP or a ReloadConfig request
+static CONFIG_RELOADER: OnceLock<ConfigReloader> = OnceLock::new();
+
 #[async_trait]
 trait TtsProvider: Send + Sync {
-    async fn generate_speech(&self, text: &str, output_path: &PathBuf) -> Result<()>;
+    async fn generate_speech(&self, text: &str, output_path: &Path, overrides: &VoiceOverrides) -> Result<()>;
+
+    // Load whatever the provider needs so the first real line isn't slow; nothing by default
+    async fn prewarm(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
 struct GptSoVitsProvider {
     client: Client,
     config: GptSoVitsConfig,
+    // Base config with each [[profiles]] entry overlaid, by profile name
+    profiles: HashMap<String, GptSoVitsConfig>,
+    // Character voices from [voices], by id
+    voices: HashMap<String, VoiceConfig>,
+    // Fail instead of warning when the response Content-Type doesn't match media_type
+    strict_content_type: bool,
+    // Local copies of reference clips configured as URLs
+    remote_refs: RemoteRefAudio,
+    // Map of v2 request field -> name the configured api_schema sends it as
+    field_names: HashMap<String, String>,
+    // Least audio received before a broken stream that is kept rather than discarded
+    keep_partial_min_bytes: Option<u64>,
 }
 
 impl GptSoVitsProvider {
-    fn new(config: GptSoVitsConfig) -> Self {
-        log_message(&format!("Initializing GPT-SoVITS provider with config: {:?}", config));
-        Self {
-            client: Client::new(),
+    fn new(
+        config: GptSoVitsConfig,
+        profiles: HashMap<String, GptSoVitsConfig>,
+        voices: HashMap<String, VoiceConfig>,
+        client: Client,
+        general_config: &GeneralConfig,
+    ) -> Result<Self> {
+        log_level(LogLevel::Debug, &format!("Initializing GPT-SoVITS provider with config: {:?}", config));
+        
+        // Without a reference clip most GPT-SoVITS endpoints produce garbage instead of failing
+        if config.ref_audio_path.trim().is_empty() {
+            if !general_config.allow_empty_ref {
+                anyhow::bail!(
+                    "tts.ref_audio_path is empty; set it to a reference clip, \
+                     or set general.allow_empty_ref if every request sends its own"
+                );
+            }
+            log_level(LogLevel::Warn, "tts.ref_audio_path is empty, requests without a reference clip will likely fail");
+        }
+        
+        let field_names = api_field_names(general_config)?;
+        if !field_names.is_empty() {
+            log_level(LogLevel::Debug, &format!("Renaming GPT-SoVITS request fields for api_schema {}: {:?}", general_config.api_schema, field_names));
+        }
+        
+        Ok(Self {
+            field_names,
+            keep_partial_min_bytes: general_config
+                .keep_partial_on_stream_error
+                .then_some(general_config.partial_min_bytes),
+            remote_refs: RemoteRefAudio::new(client.clone()),
+            client,
             config,
+            profiles,
+            voices,
+            strict_content_type: general_config.strict_content_type,
+        })
+    }
+
+    // Download every reference clip configured as a URL, so the first request doesn't wait on it
+    async fn download_remote_refs(&self) -> Result<()> {
+        for config in std::iter::once(&self.config).chain(self.profiles.values()) {
+            for reference in std::iter::once(&config.ref_audio_path).chain(&config.aux_ref_audio_paths) {
+                self.remote_refs.local_path(reference).await?;
+            }
+        }
+        for reference in self.voices.values().filter_map(|voice| voice.ref_audio_path.as_ref()) {
+            self.remote_refs.local_path(reference).await?;
         }
+        Ok(())
     }
 
-    async fn execute_tts(&self, text: &str, output_path: &PathBuf) -> Result<()> {
-        log_message(&format!("Generating speech for text: {}", text));
-        log_message(&format!("Output path: {}", output_path.display()));
+    async fn execute_tts(&self, text: &str, output_path: &Path, overrides: &VoiceOverrides) -> Result<()> {
+        log_level(LogLevel::Debug, &format!("Generating speech for text: {}", text));
+        log_level(LogLevel::Debug, &format!("Output path: {}", output_path.display()));
+
+        // A named profile replaces the base parameters for this request
+        let config = match &overrides.profile {
+            Some(profile) => self.profiles
+                .get(profile)
+                .context(format!("Unknown profile: {}", profile))?,
+            None => &self.config,
+        };
+
+        // A character voice swaps the reference clip and its prompt on top of that
+        let voice_config;
+        let config = match &overrides.voice_id {
+            Some(voice_id) => {
+                voice_config = self.voices
+                    .get(voice_id)
+                    .context(format!("Unknown voice: {}", voice_id))?
+                    .apply(config);
+                &voice_config
+            }
+            None => config,
+        };
+
+        // GPT-SoVITS needs local paths, so URLs are swapped for their downloaded copies
+        let ref_audio_path = match &overrides.ref_audio_path {
+            Some(path) => path.clone(),
+            None => self.remote_refs.local_path(&config.ref_audio_path).await?,
+        };
+        let mut aux_ref_audio_paths = Vec::with_capacity(config.aux_ref_audio_paths.len());
+        for reference in &config.aux_ref_audio_paths {
+            aux_ref_audio_paths.push(self.remote_refs.local_path(reference).await?);
+        }
 
         let request = GptSoVitsRequest {
             text: text.to_string(),
-            text_lang: self.config.text_lang.clone(),
-            ref_audio_path: self.config.ref_audio_path.clone(),
-            aux_ref_audio_paths: self.config.aux_ref_audio_paths.clone(),
-            prompt_text: self.config.prompt_text.clone(),
-            prompt_lang: self.config.prompt_lang.clone(),
-            top_k: self.config.top_k,
-            top_p: self.config.top_p,
-            temperature: self.config.temperature,
-            text_split_method: self.config.text_split_method.clone(),
-            batch_size: self.config.batch_size,
-            batch_threshold: self.config.batch_threshold,
-            split_bucket: self.config.split_bucket,
-            speed_factor: self.config.speed_factor,
-            fragment_interval: self.config.fragment_interval,
-            streaming_mode: self.config.streaming_mode,
-            seed: self.config.seed,
-            parallel_infer: self.config.parallel_infer,
-            repetition_penalty: self.config.repetition_penalty,
-            media_type: self.config.media_type.clone(),
-        };
-
-        log_message(&format!("Sending request to API: {:?}", request));
-
-        let response = if self.config.method.to_uppercase() == "GET" {
-            log_message("Using GET method for API request");
+            text_lang: config.text_lang.clone(),
+            ref_audio_path,
+            aux_ref_audio_paths,
+            prompt_text: config.prompt_text.clone(),
+            prompt_lang: config.prompt_lang.clone(),
+            top_k: config.top_k,
+            top_p: config.top_p,
+            temperature: config.temperature,
+            text_split_method: config.text_split_method.clone(),
+            batch_size: config.batch_size,
+            batch_threshold: config.batch_threshold,
+            split_bucket: config.split_bucket,
+            speed_factor: config.speed_factor,
+            fragment_interval: config.fragment_interval,
+            streaming_mode: overrides.streaming.unwrap_or(config.streaming_mode),
+            seed: config.seed,
+            parallel_infer: config.parallel_infer,
+            repetition_penalty: config.repetition_penalty,
+            media_type: config.media_type.clone(),
+        };
+
+        log_level(LogLevel::Debug, &format!("Sending request to API: {:?}", request));
+        let body = shape_request(&request, &self.field_names)?;
+
+        let response = if config.method.to_uppercase() == "GET" {
+            log_level(LogLevel::Debug, "Using GET method for API request");
             self.client
-                .get(&self.config.base_url)
-                .query(&request)
+                .get(&config.base_url)
+                .query(&body)
                 .send()
                 .await?
         } else {
-            log_message("Using POST method for API request");
+            log_level(LogLevel::Debug, "Using POST method for API request");
             self.client
-                .post(&self.config.base_url)
-                .json(&request)
+                .post(&config.base_url)
+                .json(&body)
                 .send()
                 .await?
         };
 
-        if !response.status().is_success() {
+        // Includes unfollowed redirects, which have no body to explain them
+        let status = response.status();
+        if !status.is_success() {
             let error = response.text().await?;
-            log_message(&format!("API error: {}", error));
-            anyhow::bail!("GPT-SoVITS API error: {}", error);
-        }
-
-        log_message("API request successful, streaming response to file");
-
-        // Ensure the output directory exists
-        if let Some(parent) = output_path.parent() {
-            fs::create_dir_all(parent)
-                .await
-                .context("Failed to create output directory")?;
+            log_level(LogLevel::Error, &format!("API error ({}): {}", status, error));
+            return Err(ProviderHttpError { provider: "GPT-SoVITS API", status, body: error }.into());
         }
 
-        // Create output file
-        let mut file = TokioFile::create(output_path).await?;
-
-        // Stream the response to file
-        let mut stream = response.bytes_stream();
-        let mut total_bytes = 0;
-        while let Some(chunk) = stream.next().await {
-            let chunk = chunk?;
-            total_bytes += chunk.len();
-            file.write_all(&chunk).await?;
+        // A cheap check that the body is in the format the cache file will be treated as
+        if let Some(content_type) = response.headers().get(reqwest::header::CONTENT_TYPE)
+            && let Ok(content_type) = content_type.to_str()
+            && !content_type_matches(content_type, &config.media_type)
+        {
+            let message = format!(
+                "GPT-SoVITS API returned Content-Type {} but media_type is {}",
+                content_type, config.media_type
+            );
+            if self.strict_content_type {
+                anyhow::bail!(message);
+            }
+            log_level(LogLevel::Warn, &message);
         }
 
-        log_message(&format!("Successfully wrote {} bytes to {}", total_bytes, output_path.display()));
-        Ok(())
+        log_level(LogLevel::Debug, "API request successful, streaming response to file");
+        stream_response_to_file(response, output_path, self.keep_partial_min_bytes, overrides.audio_sink.as_ref()).await
     }
 }
 
-#[async_trait]
-impl TtsProvider for GptSoVitsProvider {
-    async fn generate_speech(&self, text: &str, output_path: &PathBuf) -> Result<()> {
-        self.execute_tts(text, output_path).await
-    }
+// Field names of the original GPT-SoVITS api.py that differ from the v2 API's
+const V1_LEGACY_FIELDS: [(&str, &str); 5] = [
+    ("ref_audio_path", "refer_wav_path"),
+    ("aux_ref_audio_paths", "inp_refs"),
+    ("text_lang", "text_language"),
+    ("prompt_lang", "prompt_language"),
+    ("speed_factor", "speed"),
+];
+
+// Field renames for the configured api_schema, empty for the v2 API the request is modelled on
+fn api_field_names(general_config: &GeneralConfig) -> Result<HashMap<String, String>> {
+    let renames: &[(&str, &str)] = match general_config.api_schema.as_str() {
+        "v2" => &[],
+        "v1_legacy" => &V1_LEGACY_FIELDS,
+        "custom" => return Ok(general_config.api_field_map.clone()),
+        schema => anyhow::bail!("Unknown api_schema: {} (expected v2, v1_legacy or custom)", schema),
+    };
+    Ok(renames
+        .iter()
+        .map(|(field, name)| (field.to_string(), name.to_string()))
+        .collect())
 }
 
-// Function to handle prefetch operations
-async fn prefetch_voices(
-    provider: Arc<dyn TtsProvider>,
-    text_list_path: PathBuf,
-    cache_dir: PathBuf,
-    prefetch_count: usize,
-    start_position: usize,
-    voice_manager: Arc<Mutex<VoiceManager>>,
-) -> Result<()> {
-    log_message(&format!("Starting prefetch operation:"));
-    log_message(&format!("  Text list: {}", text_list_path.display()));
-    log_message(&format!("  Cache dir: {}", cache_dir.display()));
-    log_message(&format!("  Prefetch count: {}", prefetch_count));
-    log_message(&format!("  Start position: {}", start_position));
-
-    // Ensure cache directory exists
-    fs::create_dir_all(&cache_dir)
-        .await
-        .context("Failed to create cache directory")?;
+// Build the request body a GPT-SoVITS fork expects: each renamed field is sent under its
+// new name, and fields renamed to "" are left out
+fn shape_request(
+    request: &GptSoVitsRequest,
+    field_names: &HashMap<String, String>,
+) -> Result<serde_json::Map<String, serde_json::Value>> {
+    // Through text rather than to_value, which widens f32s to values like 0.30000001192092896
+    let serde_json::Value::Object(fields) = serde_json::from_str(&serde_json::to_string(request)?)? else {
+        anyhow::bail!("GPT-SoVITS request did not serialize to an object");
+    };
+    Ok(fields
+        .into_iter()
+        .filter_map(|(field, value)| match field_names.get(&field) {
+            Some(name) if name.is_empty() => None,
+            Some(name) => Some((name.clone(), value)),
+            None => Some((field, value)),
+        })
+        .collect())
+}
 
-    log_message("Cache directory created/verified");
+// How long a downloaded reference clip is used before asking the server whether it changed
+const REF_AUDIO_RECHECK: Duration = Duration::from_secs(60);
 
-    // Get text list path as string for the manager
-    let text_list_path_str = text_list_path.to_string_lossy().to_string();
+// Reference clips given as http(s) URLs, downloaded to temporary files and revalidated by ETag
+struct RemoteRefAudio {
+    client: Client,
+    // Map of URL -> downloaded copy
+    downloads: Mutex<HashMap<String, RemoteRefDownload>>,
+}
 
-    // Get text list from voice manager
-    let text_list = {
-        let mut manager = voice_manager.lock().await;
-        manager.get_text_list(&text_list_path_str).await?.clone()
-    };
+struct RemoteRefDownload {
+    path: PathBuf,
+    etag: Option<String>,
+    checked: Instant,
+}
 
-    // Generate the next prefetch_count voices
-    let mut count = 0;
-    let mut generated_count = 0;
-    let mut current_line = start_position;
-    
-    while current_line < text_list.len() && count < prefetch_count {
-        let text = &text_list[current_line];
-        
-        if text.trim().is_empty() {
-            log_message(&format!("Skipping empty line at position {}", current_line));
-            current_line += 1;
-            continue;
+impl RemoteRefAudio {
+    fn new(client: Client) -> Self {
+        Self {
+            client,
+            downloads: Mutex::new(HashMap::new()),
         }
+    }
 
-        // Create a unique filename based on the text content using MD5
-        let voice_filename = generate_cache_filename(text);
-        let output_path = cache_dir.join(&voice_filename);
-
-        // Skip if already exists
-        if output_path.exists() {
-            log_message(&format!("Skipping existing voice for line {}: {}", current_line, text));
-            current_line += 1;
-            count += 1;
-            continue;
+    // Local path for a reference clip: URLs are downloaded (or revalidated), anything else passes through
+    async fn local_path(&self, reference: &str) -> Result<String> {
+        if !reference.starts_with("http://") && !reference.starts_with("https://") {
+            return Ok(reference.to_string());
         }
 
-        // Check if already being processed
-        let is_in_progress = {
-            let manager = voice_manager.lock().await;
-            manager.is_generating(&text_list_path_str, current_line)
-        };
+        let mut downloads = self.downloads.lock().await;
+        let previous = downloads.get(reference);
+        if let Some(download) = previous
+            && download.checked.elapsed() < REF_AUDIO_RECHECK
+        {
+            return Ok(download.path.to_string_lossy().to_string());
+        }
 
-        if is_in_progress {
-            log_message(&format!("Skipping in-progress voice for line {}: {}", current_line, text));
-            current_line += 1;
-            count += 1;
-            continue;
+        let mut request = self.client.get(reference);
+        if let Some(etag) = previous.and_then(|download| download.etag.as_ref()) {
+            request = request.header(reqwest::header::IF_NONE_MATCH, etag);
         }
+        let response = match request.send().await {
+            Ok(response) => response,
+            // Keep using the last copy while the asset server is unreachable
+            Err(e) if previous.is_some() => {
+                log_level(LogLevel::Warn, &format!("Failed to revalidate reference audio {}, using the cached copy: {}", reference, e));
+                let download = downloads.get_mut(reference).unwrap();
+                download.checked = Instant::now();
+                return Ok(download.path.to_string_lossy().to_string());
+            }
+            Err(e) => return Err(anyhow::Error::new(e).context(format!("Failed to download reference audio {}", reference))),
+        };
 
-        // Mark as in progress
+        let status = response.status();
+        if status == reqwest::StatusCode::NOT_MODIFIED
+            && let Some(download) = downloads.get_mut(reference)
         {
-            let mut manager = voice_manager.lock().await;
-            manager.mark_in_progress(&text_list_path_str, current_line);
+            download.checked = Instant::now();
+            return Ok(download.path.to_string_lossy().to_string());
+        }
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(ProviderHttpError { provider: "Reference audio server", status, body: error }.into());
         }
 
-        // Generate voice
-        log_message(&format!("Pre-generating voice for line {}: {}", current_line, text));
-        match provider.generate_speech(text, &output_path).await {
-            Ok(_) => {
-                log_message(&format!("Successfully pre-generated voice for line {}: {}", current_line, text));
-                count += 1;
-                generated_count += 1;
-            }
+        let etag = response
+            .headers()
+            .get(reqwest::header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(str::to_string);
+        let audio = response.bytes().await.context("Failed to download reference audio")?;
+        let path = std::env::temp_dir().join(format!(
+            "krkr-tts-ref-{:x}.wav",
+            md5::compute(reference.as_bytes())
+        ));
+        write_file_atomically(&path, &audio).await?;
+        log_message(&format!("Downloaded reference audio {} to {}", reference, path.display()));
+
+        let local_path = path.to_string_lossy().to_string();
+        downloads.insert(reference.to_string(), RemoteRefDownload {
+            path,
+            etag,
+            checked: Instant::now(),
+        });
+        Ok(local_path)
+    }
+}
+
+// Whether a response Content-Type fits the configured media_type (GPT-SoVITS sends "audio/<media_type>")
+fn content_type_matches(content_type: &str, media_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    match media_type.to_ascii_lowercase().as_str() {
+        "wav" => matches!(mime.as_str(), "audio/wav" | "audio/x-wav" | "audio/wave" | "audio/vnd.wave"),
+        "raw" => matches!(mime.as_str(), "audio/raw" | "application/octet-stream"),
+        other => mime == format!("audio/{}", other),
+    }
+}
+
+// Stream an HTTP response body into a file, removing the file again if writing fails.
+// If the stream itself breaks after at least `keep_partial_min_bytes` (when set), the
+// audio so far is kept instead. Each chunk written is also copied to `audio_sink`.
+async fn stream_response_to_file(
+    response: reqwest::Response,
+    output_path: &Path,
+    keep_partial_min_bytes: Option<u64>,
+    audio_sink: Option<&AudioSink>,
+) -> Result<()> {
+    // Ensure the output directory exists
+    if let Some(parent) = output_path.parent() {
+        fs::create_dir_all(parent)
+            .await
+            .context("Failed to create output directory")?;
+    }
+
+    // Stream into a .part file next to the output, only renamed into place once complete,
+    // so an interrupted stream never leaves a truncated voice where the cache looks
+    let part_path = partial_path(output_path);
+    let mut file = TokioFile::create(&part_path).await?;
+
+    // Stream the response to file
+    let mut stream = response.bytes_stream();
+    let mut total_bytes = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
             Err(e) => {
-                log_message(&format!("Failed to pre-generate voice for line {}: {}", current_line, e));
+                let _ = file.flush().await;
+                drop(file);
+                if let Some(min_bytes) = keep_partial_min_bytes
+                    && total_bytes as u64 >= min_bytes
+                {
+                    return keep_partial_audio(&part_path, output_path, total_bytes, e).await;
+                }
+                let _ = fs::remove_file(&part_path).await;
+                return Err(anyhow::Error::new(e)
+                    .context(format!("Provider stream failed after {} bytes", total_bytes)));
+            }
+        };
+        total_bytes += chunk.len();
+        if let Err(e) = file.write_all(&chunk).await {
+            // Never leave a half-written file behind to poison the cache
+            drop(file);
+            let _ = fs::remove_file(&part_path).await;
+
+            if e.kind() == std::io::ErrorKind::StorageFull {
+                log_level(LogLevel::Error, &format!(
+                    "!!! DISK FULL while writing {} after {} bytes, partial file removed !!!",
+                    output_path.display(), total_bytes
+                ));
+                return Err(DiskFullError { path: output_path.to_path_buf() }.into());
             }
+            return Err(anyhow::Error::new(e)
+                .context(format!("Failed to write {}", output_path.display())));
+        }
+        // A client that stopped listening doesn't stop the voice from being cached
+        if let Some(audio_sink) = audio_sink {
+            let _ = audio_sink.send(chunk.to_vec());
         }
+    }
 
-        // Mark as completed
-        {
-            let mut manager = voice_manager.lock().await;
-            manager.mark_completed(&text_list_path_str, current_line);
+    if let Err(e) = file.flush().await {
+        drop(file);
+        let _ = fs::remove_file(&part_path).await;
+        return Err(anyhow::Error::new(e).context(format!("Failed to write {}", output_path.display())));
+    }
+    drop(file);
+    if let Err(e) = fs::rename(&part_path, output_path).await {
+        let _ = fs::remove_file(&part_path).await;
+        return Err(anyhow::Error::new(e).context(format!("Failed to move {} into place", part_path.display())));
+    }
+
+    log_level(LogLevel::Debug, &format!("Successfully wrote {} bytes to {}", total_bytes, output_path.display()));
+    Ok(())
+}
+
+// Keep the audio received before a provider stream broke, with its WAV header corrected
+// to the data that actually arrived
+async fn keep_partial_audio(part_path: &Path, output_path: &Path, total_bytes: usize, error: reqwest::Error) -> Result<()> {
+    let audio = fs::read(part_path).await;
+    let _ = fs::remove_file(part_path).await;
+    let audio = audio.context("Failed to read partial voice")?;
+    let finalized = wav::finalize_partial(&audio).unwrap_or(audio);
+    write_file_atomically(output_path, &finalized).await?;
+    log_level(LogLevel::Warn, &format!(
+        "provider stream failed after {} bytes ({}), keeping the partial voice {}",
+        total_bytes, error, output_path.display()
+    ));
+    Ok(())
+}
+
+#[async_trait]
+impl TtsProvider for GptSoVitsProvider {
+    async fn generate_speech(&self, text: &str, output_path: &Path, overrides: &VoiceOverrides) -> Result<()> {
+        self.execute_tts(text, output_path, overrides).await
+    }
+
+    // GPT-SoVITS loads its models on the first synthesis, so run a short throwaway one.
+    // The start of the reference transcript is known to be in the prompt language.
+    async fn prewarm(&self) -> Result<()> {
+        let text: String = self.config.prompt_text.chars().take(PREWARM_TEXT_CHARS).collect();
+        let text = if text.trim().is_empty() { "。".to_string() } else { text };
+
+        let output_path = std::env::temp_dir().join(format!("krkr-tts-prewarm-{}.wav", std::process::id()));
+        log_message(&format!("Prewarming GPT-SoVITS with: {}", text));
+        let result = self.execute_tts(&text, &output_path, &VoiceOverrides::default()).await;
+        let _ = fs::remove_file(&output_path).await;
+        result
+    }
+}
+
+// Length of the throwaway line synthesized to prewarm GPT-SoVITS
+const PREWARM_TEXT_CHARS: usize = 10;
+
+// Provider that delegates generation to another krkr-tts server, sending each request
+// over its length-prefixed TCP protocol in the configured wire_format
+struct RemoteKrkrProvider {
+    host: String,
+    port: u16,
+    config_path: PathBuf,
+    wire_format: WireFormat,
+    tcp_nodelay: bool,
+}
+
+impl RemoteKrkrProvider {
+    fn new(config: RemoteKrkrConfig, general_config: &GeneralConfig) -> Result<Self> {
+        log_message(&format!("Initializing remote krkr-tts provider: {}:{}", config.host, config.port));
+        Ok(Self {
+            host: config.host,
+            port: config.port,
+            config_path: config.config_path.unwrap_or_else(|| PathBuf::from("config/default.toml")),
+            wire_format: WireFormat::from_name(&general_config.wire_format)?,
+            tcp_nodelay: general_config.tcp_nodelay,
+        })
+    }
+
+    // Send a request to the upstream over its TCP protocol, returning the connection for the reply
+    async fn send_request(&self, request: &VoiceRequest) -> Result<tokio::net::TcpStream> {
+        let request_data = self.wire_format.encode(request)?;
+        let mut stream = tokio::net::TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .map_err(|e| UpstreamError {
+                kind: ErrorKind::Connection,
+                message: format!("Failed to connect to {}:{}: {}", self.host, self.port, e),
+            })?;
+        stream.set_nodelay(self.tcp_nodelay)?;
+        stream.write_all(&(request_data.len() as u32).to_le_bytes()).await?;
+        stream.write_all(&request_data).await?;
+        Ok(stream)
+    }
+}
+
+// Read one length-prefixed stream frame from the upstream
+async fn read_upstream_frame(stream: &mut tokio::net::TcpStream, wire_format: WireFormat) -> Result<StreamFrame> {
+    let mut len_bytes = [0u8; 4];
+    stream.read_exact(&mut len_bytes).await?;
+    let mut frame_data = vec![0u8; u32::from_le_bytes(len_bytes) as usize];
+    stream.read_exact(&mut frame_data).await?;
+    StreamFrame::decode(&frame_data, wire_format)
+}
+
+#[async_trait]
+impl TtsProvider for RemoteKrkrProvider {
+    async fn generate_speech(&self, text: &str, output_path: &Path, overrides: &VoiceOverrides) -> Result<()> {
+        log_level(LogLevel::Debug, &format!("Forwarding text to upstream server: {}", text));
+
+        // The upstream can't read our local files, so send the reference clip itself
+        let ref_audio_base64 = match &overrides.ref_audio_path {
+            Some(path) => Some(base64::engine::general_purpose::STANDARD.encode(
+                fs::read(path).await.context("Failed to read reference audio")?,
+            )),
+            None => None,
+        };
+
+        // Streamed, so the voice arrives inline on this connection instead of as an upstream path
+        let request = VoiceRequest {
+            request_type: RequestType::GenerateVoice,
+            text: text.to_string(),
+            output_path: PathBuf::new(),
+            cache_dir: None,
+            config_path: self.config_path.clone(),
+            idempotency_key: None,
+            ref_audio_base64,
+            next_texts: Vec::new(),
+            wait: false,
+            profile: overrides.profile.clone(),
+            streaming: overrides.streaming,
+            voice_id: overrides.voice_id.clone(),
+            stream: true,
+        };
+        let mut stream = self.send_request(&request).await?;
+
+        let mut audio = Vec::new();
+        loop {
+            let frame = read_upstream_frame(&mut stream, self.wire_format)
+                .await
+                .map_err(|e| UpstreamError {
+                    kind: ErrorKind::Connection,
+                    message: format!("Voice stream broke off after {} bytes: {:#}", audio.len(), e),
+                })?;
+            match frame {
+                StreamFrame::Audio(chunk) => {
+                    if let Some(audio_sink) = &overrides.audio_sink {
+                        let _ = audio_sink.send(chunk.clone());
+                    }
+                    audio.extend_from_slice(&chunk);
+                }
+                StreamFrame::Restart => audio.clear(),
+                StreamFrame::End(response) if !response.success => {
+                    let kind = response.error_kind.unwrap_or(ErrorKind::Other);
+                    log_level(LogLevel::Error, &format!("Upstream error ({}): {}", kind, response.message));
+                    return Err(UpstreamError { kind, message: response.message }.into());
+                }
+                // A silent stand-in from the upstream must not end up in our cache as the real voice
+                StreamFrame::End(response) if response.message == "placeholder" => {
+                    anyhow::bail!("Upstream krkr-tts server returned a silence placeholder");
+                }
+                StreamFrame::End(_) => break,
+            }
+        }
+
+        write_file_atomically(output_path, &audio).await
+    }
+}
+
+// Provider for Google Cloud Text-to-Speech
+struct GoogleTtsProvider {
+    client: Client,
+    config: GoogleTtsConfig,
+    synthesize_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GoogleSynthesizeResponse {
+    audio_content: String,
+}
+
+impl GoogleTtsProvider {
+    fn new(config: GoogleTtsConfig, client: Client) -> Result<Self> {
+        if config.api_key.is_empty() && config.access_token.is_empty() {
+            anyhow::bail!("[tts_google] needs an api_key or an access_token");
+        }
+
+        let synthesize_url = format!("{}/v1/text:synthesize", config.base_url.trim_end_matches('/'));
+        log_message(&format!(
+            "Initializing Google TTS provider: {} ({} {})",
+            synthesize_url, config.language_code, config.voice_name
+        ));
+        Ok(Self {
+            client,
+            config,
+            synthesize_url,
+        })
+    }
+}
+
+#[async_trait]
+impl TtsProvider for GoogleTtsProvider {
+    async fn generate_speech(&self, text: &str, output_path: &Path, _overrides: &VoiceOverrides) -> Result<()> {
+        log_level(LogLevel::Debug, &format!("Sending text to Google TTS: {}", text));
+
+        let input = if self.config.ssml {
+            serde_json::json!({ "ssml": text })
+        } else {
+            serde_json::json!({ "text": text })
+        };
+        let mut voice = serde_json::json!({ "languageCode": self.config.language_code });
+        if !self.config.voice_name.is_empty() {
+            voice["name"] = serde_json::json!(self.config.voice_name);
+        }
+        let body = serde_json::json!({
+            "input": input,
+            "voice": voice,
+            "audioConfig": { "audioEncoding": self.config.audio_encoding },
+        });
+
+        let mut request = self.client.post(&self.synthesize_url).json(&body);
+        if !self.config.access_token.is_empty() {
+            request = request.bearer_auth(&self.config.access_token);
+        } else {
+            request = request.query(&[("key", &self.config.api_key)]);
+        }
+        let response = request.send().await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error = response.text().await?;
+            log_level(LogLevel::Error, &format!("Google TTS error ({}): {}", status, error));
+            return Err(ProviderHttpError { provider: "Google TTS", status, body: error }.into());
+        }
+
+        let synthesized: GoogleSynthesizeResponse = response
+            .json()
+            .await
+            .context("Failed to parse Google TTS response")?;
+        let audio = base64::engine::general_purpose::STANDARD
+            .decode(&synthesized.audio_content)
+            .context("Failed to decode Google TTS audio")?;
+        if audio.is_empty() {
+            anyhow::bail!("Google TTS returned no audio");
+        }
+        if self.config.audio_encoding.eq_ignore_ascii_case("LINEAR16") && !audio.starts_with(b"RIFF") {
+            anyhow::bail!("Google TTS returned LINEAR16 audio without a WAV header");
+        }
+
+        write_file_atomically(output_path, &audio).await?;
+        log_level(LogLevel::Debug, &format!("Successfully wrote {} bytes to {}", audio.len(), output_path.display()));
+        Ok(())
+    }
+}
+
+struct VoiceVoxProvider {
+    client: Client,
+    config: VoiceVoxConfig,
+}
+
+impl VoiceVoxProvider {
+    fn new(config: VoiceVoxConfig, client: Client) -> Self {
+        log_message(&format!(
+            "Initializing VOICEVOX provider: {} (speaker {})",
+            config.base_url, config.speaker
+        ));
+        Self { client, config }
+    }
+}
+
+#[async_trait]
+impl TtsProvider for VoiceVoxProvider {
+    // VOICEVOX synthesizes in two steps: /audio_query turns text into an editable
+    // query, which /synthesis renders to a WAV
+    async fn generate_speech(&self, text: &str, output_path: &Path, overrides: &VoiceOverrides) -> Result<()> {
+        log_level(LogLevel::Debug, &format!("Sending text to VOICEVOX: {}", text));
+        let base_url = self.config.base_url.trim_end_matches('/');
+        let speaker = self.config.speaker.to_string();
+
+        let response = self.client
+            .post(format!("{}/audio_query", base_url))
+            .query(&[("text", text), ("speaker", &speaker)])
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let error = response.text().await?;
+            log_level(LogLevel::Error, &format!("VOICEVOX audio_query error ({}): {}", status, error));
+            return Err(ProviderHttpError { provider: "VOICEVOX", status, body: error }.into());
+        }
+        let mut query: serde_json::Value = response
+            .json()
+            .await
+            .context("Failed to parse VOICEVOX audio query")?;
+        query["speedScale"] = serde_json::json!(self.config.speed_scale);
+        query["pitchScale"] = serde_json::json!(self.config.pitch_scale);
+
+        let response = self.client
+            .post(format!("{}/synthesis", base_url))
+            .query(&[("speaker", &speaker)])
+            .json(&query)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let error = response.text().await?;
+            log_level(LogLevel::Error, &format!("VOICEVOX synthesis error ({}): {}", status, error));
+            return Err(ProviderHttpError { provider: "VOICEVOX", status, body: error }.into());
+        }
+
+        stream_response_to_file(response, output_path, None, overrides.audio_sink.as_ref()).await
+    }
+}
+
+// Provider that a config reload can replace. Each generation call runs on the provider
+// current when it started, so generations already running finish under the old config.
+struct ReloadableProvider {
+    current: std::sync::RwLock<Arc<dyn TtsProvider>>,
+}
+
+impl ReloadableProvider {
+    fn new(provider: Arc<dyn TtsProvider>) -> Self {
+        Self {
+            current: std::sync::RwLock::new(provider),
+        }
+    }
+
+    fn current(&self) -> Arc<dyn TtsProvider> {
+        self.current
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .clone()
+    }
+
+    fn replace(&self, provider: Arc<dyn TtsProvider>) {
+        *self.current
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner) = provider;
+    }
+}
+
+#[async_trait]
+impl TtsProvider for ReloadableProvider {
+    async fn generate_speech(&self, text: &str, output_path: &Path, overrides: &VoiceOverrides) -> Result<()> {
+        self.current().generate_speech(text, output_path, overrides).await
+    }
+
+    async fn prewarm(&self) -> Result<()> {
+        self.current().prewarm().await
+    }
+}
+
+// Temporary name a file is written under until it is complete: "<name>.part" beside it
+fn partial_path(path: &Path) -> PathBuf {
+    let mut part_name = path.as_os_str().to_os_string();
+    part_name.push(".part");
+    PathBuf::from(part_name)
+}
+
+// Where a voice cached in a compressed output_format is generated before transcoding
+fn source_path(path: &Path) -> PathBuf {
+    let mut source_name = path.as_os_str().to_os_string();
+    source_name.push(".source");
+    PathBuf::from(source_name)
+}
+
+// Where keep_source_wav keeps the WAV a voice was transcoded from: <hash>.src.wav beside
+// it, whether the voice is written to its cache path or to a draft's .upgrade file
+fn kept_source_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let hash = name.split('.').next().unwrap_or_default();
+    path.with_file_name(format!("{}.src.wav", hash))
+}
+
+// Write a complete file under a temporary name and rename it into place, so
+// a failed write never leaves a partial voice where the cache would find it
+async fn write_file_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .await
+            .context("Failed to create output directory")?;
+    }
+
+    let temp_path = partial_path(path);
+    if let Err(e) = fs::write(&temp_path, contents).await {
+        let _ = fs::remove_file(&temp_path).await;
+        if e.kind() == std::io::ErrorKind::StorageFull {
+            return Err(DiskFullError { path: path.to_path_buf() }.into());
         }
+        return Err(anyhow::Error::new(e).context(format!("Failed to write {}", temp_path.display())));
+    }
+    fs::rename(&temp_path, path)
+        .await
+        .context(format!("Failed to move {} into place", temp_path.display()))
+}
+
+// Function to handle prefetch operations
+async fn prefetch_voices(
+    provider: Arc<dyn TtsProvider>,
+    text_list_path: PathBuf,
+    cache_dirs: Vec<PathBuf>,
+    general_config: &GeneralConfig,
+    start_position: usize,
+    voice_manager: Arc<Mutex<VoiceManager>>,
+    overrides: VoiceOverrides,
+) -> Result<()> {
+    // New voices always go to the first writable cache directory
+    let cache_dir = first_writable_dir(&cache_dirs)
+        .context("No writable cache directory")?;
+    let prefetch_count = general_config.prefetch_count;
+
+    log_level(LogLevel::Debug, "Starting prefetch operation:");
+    log_level(LogLevel::Debug, &format!("  Text list: {}", text_list_path.display()));
+    log_level(LogLevel::Debug, &format!("  Cache dir: {}", cache_dir.display()));
+    log_level(LogLevel::Debug, &format!("  Prefetch count: {}", prefetch_count));
+    log_level(LogLevel::Debug, &format!("  Start position: {}", start_position));
+
+    // Get text list path as string for the manager
+    let text_list_path_str = text_list_path.to_string_lossy().to_string();
+
+    // Get text list from voice manager
+    let text_list = {
+        let mut manager = voice_manager.lock().await;
+        manager.get_text_list(&text_list_path_str, &general_config.text_list_encoding).await?.clone()
+    };
 
-        current_line += 1;
+    // Never look further ahead than the scan limit, however many lines were skipped
+    let scan_end = match general_config.prefetch_scan_limit {
+        0 => text_list.len(),
+        limit => text_list.len().min(start_position.saturating_add(limit)),
+    };
+
+    // Generate the next prefetch_count voices, up to prefetch_concurrency at a time.
+    // count includes generations still running; a failed one gives its place back.
+    let concurrency = general_config.prefetch_concurrency.max(1);
+    let delay = Duration::from_millis(general_config.prefetch_delay_ms);
+    let mut count = 0;
+    let mut generated_count = 0;
+    let mut current_line = start_position;
+    let mut running = FuturesUnordered::new();
+    
+    loop {
+        while running.len() < concurrency && current_line < scan_end && count < prefetch_count {
+            let line = current_line;
+            let text = &text_list[line];
+            current_line += 1;
+            
+            if text.trim().is_empty() {
+                log_level(LogLevel::Debug, &format!("Skipping empty line at position {}", line));
+                continue;
+            }
+
+            // Create a unique filename based on the normalized text content using MD5
+            let speech_text = general_config.text_pipeline.apply(text);
+            let voice_filename = generate_cache_filename(&overrides.cache_text(&speech_text, general_config), general_config);
+            let output_path = cache_dir.join(&voice_filename);
+
+            // Skip if already exists in any cache directory
+            if find_cached_file(&cache_dirs, &voice_filename).is_some() {
+                log_level(LogLevel::Debug, &format!("Skipping existing voice for line {}: {}", line, text));
+                count += 1;
+                continue;
+            }
+
+            // Check if already being processed, for this line or the same voice by another request
+            {
+                let manager = voice_manager.lock().await;
+                if manager.shutting_down {
+                    log_message("Server is shutting down, stopping prefetch");
+                    current_line = scan_end;
+                    break;
+                }
+                if manager.is_generating(&text_list_path_str, line) || manager.is_in_flight(&voice_filename) {
+                    log_level(LogLevel::Debug, &format!("Skipping in-progress voice for line {}: {}", line, text));
+                    count += 1;
+                    continue;
+                }
+            }
+            count += 1;
+
+            let provider = &provider;
+            let overrides = &overrides;
+            let voice_manager = &voice_manager;
+            let text_list_path_str = &text_list_path_str;
+            running.push(async move {
+                // Wait for a free generation slot, so requests for the current line aren't starved.
+                // The line is only marked as in progress once it has one: a request holding its
+                // own slot may wait on this generation, which must not need another slot then.
+                let permit = match GENERATION_SEMAPHORE.get() {
+                    Some(semaphore) => match semaphore.clone().acquire_owned().await {
+                        Ok(permit) => Some(permit),
+                        Err(_) => {
+                            log_level(LogLevel::Debug, &format!("Server is shutting down, dropping prefetch of line {}", line));
+                            return Some(false);
+                        }
+                    },
+                    None => None,
+                };
+                let (generation_id, cancel_token) = {
+                    let mut manager = voice_manager.lock().await;
+                    if manager.is_generating(text_list_path_str, line) || manager.is_in_flight(&voice_filename) {
+                        log_level(LogLevel::Debug, &format!("Skipping in-progress voice for line {}: {}", line, text));
+                        return None;
+                    }
+                    manager.mark_in_progress(text_list_path_str, line);
+                    manager.register_cancel_token(&voice_filename)
+                };
+
+                log_level(LogLevel::Debug, &format!("Pre-generating voice for line {}: {}", line, text));
+                let result = generate_cancellable(
+                    provider,
+                    &speech_text,
+                    &output_path,
+                    overrides,
+                    cancel_token,
+                    general_config,
+                ).await;
+                drop(permit);
+                let generated = match result {
+                    Ok(_) => {
+                        log_message(&format!("Successfully pre-generated voice for line {}: {}", line, text));
+                        index_voice(&output_path, text, overrides, general_config).await;
+                        if general_config.detect_duplicate_audio {
+                            check_duplicate_audio(voice_manager, &speech_text, &output_path).await;
+                        }
+                        true
+                    }
+                    Err(e) => {
+                        log_level(LogLevel::Error, &format!("Failed to pre-generate voice for line {}: {}", line, e));
+                        false
+                    }
+                };
+
+                // Mark as completed
+                {
+                    let mut manager = voice_manager.lock().await;
+                    manager.mark_completed(text_list_path_str, line);
+                    manager.remove_cancel_token(&voice_filename, generation_id);
+                }
+                
+                // Add a small delay before this slot takes the next line to avoid overloading the API
+                if !delay.is_zero() {
+                    log_level(LogLevel::Debug, &format!("Waiting {}ms before next request", delay.as_millis()));
+                    sleep(delay).await;
+                }
+                Some(generated)
+            });
+        }
         
-        // Add a small delay between requests to avoid overloading the API
-        log_message("Waiting 200ms before next request");
-        sleep(Duration::from_millis(200)).await;
+        // A line another generation took over in the meantime keeps its place
+        match running.next().await {
+            Some(Some(true)) => generated_count += 1,
+            Some(Some(false)) => count -= 1,
+            Some(None) => {}
+            None => break,
+        }
     }
 
     log_message(&format!("Pre-generation completed. Generated {} new voices.", generated_count));
@@ -337,305 +1680,2090 @@ async fn prefetch_voices(
 
 // Function to handle an incoming client connection
 async fn handle_client(
-    mut socket: TcpStream, 
+    mut socket: Connection, 
     config_cache: Arc<Mutex<HashMap<PathBuf, GeneralConfig>>>,
     provider: Arc<dyn TtsProvider>,
     semaphore: Arc<Semaphore>,
     voice_manager: Arc<Mutex<VoiceManager>>,
+    server_config: Arc<GeneralConfig>,
+    work_queue: Option<mpsc::Sender<QueuedJob>>,
 ) -> Result<()> {
     // Read message length (4 bytes)
     let mut len_bytes = [0u8; 4];
     
     // Use a timeout for reading the initial data
-    match tokio::time::timeout(Duration::from_secs(5), socket.read_exact(&mut len_bytes)).await {
+    let header_timeout = Duration::from_secs(server_config.read_header_timeout_secs);
+    match tokio::time::timeout(header_timeout, socket.read_exact(&mut len_bytes)).await {
         Ok(read_result) => {
             match read_result {
                 Ok(_) => {
                     // Successfully read length bytes
                 },
                 Err(e) => {
-                    log_message(&format!("Error reading request length: {}", e));
+                    log_level(LogLevel::Warn, &format!("Error reading request length: {}", e));
                     return Err(anyhow::anyhow!("Failed to read request length"));
                 }
             }
         },
         Err(_) => {
-            log_message("Timeout while reading request length");
+            log_level(LogLevel::Warn, "Timeout while reading request length");
             return Err(anyhow::anyhow!("Timeout while reading request length"));
         }
-    }
-    
-    let len = u32::from_le_bytes(len_bytes) as usize;
-    
-    // Read request data
-    let mut request_data = vec![0u8; len];
-    match tokio::time::timeout(Duration::from_secs(5), socket.read_exact(&mut request_data)).await {
-        Ok(read_result) => {
-            if let Err(e) = read_result {
-                log_message(&format!("Error reading request data: {}", e));
-                return Err(anyhow::anyhow!("Failed to read request data"));
+    }
+    
+    let len = u32::from_le_bytes(len_bytes) as usize;
+    
+    // Read request data, allowing the configured timeout per started MiB so large requests fit
+    let mut request_data = vec![0u8; len];
+    let body_timeout = Duration::from_secs(
+        server_config.read_body_timeout_secs * (len as u64).div_ceil(1024 * 1024).max(1)
+    );
+    match tokio::time::timeout(body_timeout, socket.read_exact(&mut request_data)).await {
+        Ok(read_result) => {
+            if let Err(e) = read_result {
+                log_level(LogLevel::Warn, &format!("Error reading request data: {}", e));
+                return Err(anyhow::anyhow!("Failed to read request data"));
+            }
+        },
+        Err(_) => {
+            log_level(LogLevel::Warn, "Timeout while reading request data");
+            return Err(anyhow::anyhow!("Timeout while reading request data"));
+        }
+    }
+    
+    // Deserialize request
+    // Replies go back in whatever format the request came in
+    let wire_format = WireFormat::detect(&request_data);
+    let request: VoiceRequest = match wire_format.decode(&request_data) {
+        Ok(req) => req,
+        Err(e) => {
+            log_level(LogLevel::Warn, &format!("Error deserializing request: {}", e));
+            return Err(anyhow::anyhow!("Failed to deserialize request"));
+        }
+    };
+    
+    // Cancelling only stops a running generation, so it doesn't wait for a generation permit.
+    // A voice that already finished, or was never requested, is left alone.
+    if let RequestType::Cancel { text } = &request.request_type {
+        let response = match load_or_get_config(
+            &config_cache,
+            &request.config_path,
+            &server_config.allowed_config_paths,
+        ).await {
+            Ok(general_config) => {
+                let job = VoiceJob {
+                    text: text.clone(),
+                    cache_dir: request.cache_dir.clone(),
+                    ref_audio_base64: request.ref_audio_base64.clone(),
+                    profile: request.profile.clone(),
+                    streaming: request.streaming,
+                    voice_id: request.voice_id.clone(),
+                    ..VoiceJob::default()
+                };
+                match decode_ref_audio(&job) {
+                    Ok(ref_audio) => {
+                        let voice_filename = job_voice_filename(&general_config, &job, ref_audio.as_deref());
+                        let cancelled = voice_manager.lock().await.cancel(&voice_filename);
+                        let message = if cancelled {
+                            log_message(&format!("Cancelled in-progress generation for: {}", text));
+                            "Cancelled"
+                        } else {
+                            log_level(LogLevel::Debug, &format!("No in-progress generation to cancel for: {}", text));
+                            "No in-progress generation"
+                        };
+                        VoiceResponse {
+                            success: cancelled,
+                            message: message.to_string(),
+                            cache_path: None,
+                            error_kind: None,
+                        }
+                    }
+                    Err(e) => VoiceResponse {
+                        success: false,
+                        message: e.to_string(),
+                        cache_path: None,
+                        error_kind: Some(error_kind(&e)),
+                    },
+                }
+            }
+            Err(e) => VoiceResponse {
+                success: false,
+                message: e.to_string(),
+                cache_path: None,
+                error_kind: Some(ErrorKind::Config),
+            },
+        };
+        return send_response(&mut socket, wire_format, &response).await;
+    }
+    
+    // Prewarming runs a generation, so it waits its turn and is time-limited like one, and always replies
+    if let RequestType::PrewarmProvider = &request.request_type {
+        let _permit = semaphore.acquire().await?;
+        let prewarm_limit = Duration::from_secs(server_config.max_generation_secs);
+        let prewarm = async {
+            if prewarm_limit.is_zero() {
+                return provider.prewarm().await;
+            }
+            tokio::time::timeout(prewarm_limit, provider.prewarm())
+                .await
+                .unwrap_or_else(|_| Err(GenerationTimeoutError { secs: server_config.max_generation_secs }.into()))
+        };
+        let response = match prewarm.await {
+            Ok(()) => VoiceResponse {
+                success: true,
+                message: "Provider is warm".to_string(),
+                cache_path: None,
+                error_kind: None,
+            },
+            Err(e) => {
+                log_level(LogLevel::Error, &format!("Error prewarming provider: {}", e));
+                VoiceResponse {
+                    success: false,
+                    message: e.to_string(),
+                    cache_path: None,
+                    error_kind: Some(error_kind(&e)),
+                }
+            }
+        };
+        return send_response(&mut socket, wire_format, &response).await;
+    }
+    
+    // Reloading only reads the server's own config file, so it doesn't wait for a generation permit
+    if let RequestType::ReloadConfig = &request.request_type {
+        let response = match reload_config().await {
+            Ok(changes) => VoiceResponse {
+                success: true,
+                message: if changes.is_empty() {
+                    "Configuration reloaded, nothing changed".to_string()
+                } else {
+                    format!("Configuration reloaded, changed: {}", changes.join("; "))
+                },
+                cache_path: None,
+                error_kind: None,
+            },
+            Err(e) => VoiceResponse {
+                success: false,
+                message: e.to_string(),
+                cache_path: None,
+                error_kind: Some(ErrorKind::Config),
+            },
+        };
+        return send_response(&mut socket, wire_format, &response).await;
+    }
+    
+    // Status queries only read the server's state and cache, and always reply
+    if let RequestType::QueryStatus = &request.request_type {
+        let first_missing = match load_or_get_config(
+            &config_cache,
+            &request.config_path,
+            &server_config.allowed_config_paths,
+        ).await {
+            Ok(general_config) => first_missing_index(
+                &general_config,
+                request.cache_dir.as_deref(),
+                &request.text,
+                &VoiceOverrides {
+                    profile: request.profile.clone(),
+                    streaming: request.streaming,
+                    voice_id: request.voice_id.clone(),
+                    ..VoiceOverrides::default()
+                },
+                &voice_manager,
+            ).await,
+            Err(e) => Err(e),
+        };
+        let response = match first_missing {
+            Ok(first_missing_index) => {
+                let status = ServerStatus {
+                    concurrency: server_config.max_concurrent_tts,
+                    available_permits: semaphore.available_permits(),
+                    autoscale: server_config.autoscale_concurrency,
+                    first_missing_index,
+                    ..voice_manager.lock().await.status()
+                };
+                VoiceResponse {
+                    success: true,
+                    message: serde_json::to_string(&status)?,
+                    cache_path: None,
+                    error_kind: None,
+                }
+            }
+            Err(e) => VoiceResponse {
+                success: false,
+                message: e.to_string(),
+                cache_path: None,
+                error_kind: Some(ErrorKind::Config),
+            },
+        };
+        return send_response(&mut socket, wire_format, &response).await;
+    }
+    
+    // Evicting only touches the cache, so it doesn't wait for a generation permit
+    if let RequestType::EvictCache { text } = &request.request_type {
+        let response = match load_or_get_config(
+            &config_cache,
+            &request.config_path,
+            &server_config.allowed_config_paths,
+        ).await {
+            Ok(general_config) => {
+                let removed = evict_cache_entry(
+                    &general_config,
+                    request.cache_dir.as_deref(),
+                    text,
+                    &VoiceOverrides {
+                        profile: request.profile.clone(),
+                        streaming: request.streaming,
+                        voice_id: request.voice_id.clone(),
+                        ..VoiceOverrides::default()
+                    },
+                ).await;
+                let message = if removed.is_empty() {
+                    "No cached voice found".to_string()
+                } else {
+                    format!("Removed {} cached files", removed.len())
+                };
+                VoiceResponse {
+                    success: !removed.is_empty(),
+                    message,
+                    cache_path: removed.into_iter().next(),
+                    error_kind: None,
+                }
+            }
+            Err(e) => VoiceResponse {
+                success: false,
+                message: e.to_string(),
+                cache_path: None,
+                error_kind: Some(ErrorKind::Config),
+            },
+        };
+        return send_response(&mut socket, wire_format, &response).await;
+    }
+    
+    log_message(&format!("Received request for text: {}", request.text));
+    
+    // Acquire a permit from the semaphore to limit concurrent voice generations,
+    // held until this request's generation finishes wherever it runs
+    let permit = semaphore.clone().acquire_owned().await?;
+    
+    // Load config if not already cached, telling a waiting client why it failed
+    let general_config = match load_or_get_config(
+        &config_cache,
+        &request.config_path,
+        &server_config.allowed_config_paths,
+    ).await {
+        Ok(general_config) => general_config,
+        Err(e) if request.wait => {
+            let response = VoiceResponse {
+                success: false,
+                message: e.to_string(),
+                cache_path: None,
+                error_kind: Some(ErrorKind::Config),
+            };
+            send_response(&mut socket, wire_format, &response).await?;
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+    
+    // Drop retried requests that were already accepted recently, unless the caller waits on a reply
+    if !request.wait
+        && !request.stream
+        && let Some(key) = &request.idempotency_key
+        && general_config.idempotency_ttl_secs > 0
+    {
+        let ttl = Duration::from_secs(general_config.idempotency_ttl_secs);
+        let previous = {
+            let mut manager = voice_manager.lock().await;
+            manager.check_recent_request(key, ttl)
+        };
+        if let Some(age) = previous {
+            log_message(&format!(
+                "Duplicate request {} seen {:.1}s ago, skipping",
+                key, age.as_secs_f32()
+            ));
+            acknowledge(&mut socket, wire_format, "duplicate", &job_cache_path(&general_config, &VoiceJob::from(request))).await;
+            return Ok(());
+        }
+    }
+    
+    // Generate on this task while holding the permit, so the response goes back on the same connection
+    if request.stream {
+        return stream_voice(&mut socket, wire_format, provider, &general_config, VoiceJob::from(request), voice_manager).await;
+    }
+    if request.wait {
+        let result = process_voice_request(
+            provider,
+            &general_config,
+            VoiceJob::from(request),
+            voice_manager,
+        ).await;
+        return send_response(&mut socket, wire_format, &voice_response(result)).await;
+    }
+    
+    // Confirm the request before any work starts; a client that isn't reading just misses it
+    let job = VoiceJob::from(request);
+    let cache_path = job_cache_path(&general_config, &job);
+    acknowledge(&mut socket, wire_format, "accepted", &cache_path).await;
+    cache_path?;
+    
+    // Hand the request to the worker pool, waiting for room in the queue
+    if let Some(work_queue) = work_queue {
+        work_queue.send(QueuedJob { general_config, job, permit }).await
+            .map_err(|_| anyhow::anyhow!("Worker pool has shut down"))?;
+        return Ok(());
+    }
+    
+    // Process the request in a separate task
+    tokio::spawn(async move {
+        let result = process_voice_request(
+            provider,
+            &general_config,
+            job,
+            voice_manager,
+        ).await;
+        drop(permit);
+        log_voice_outcome(result);
+    });
+    
+    Ok(())
+}
+
+// The reply to a client waiting on a generation
+fn voice_response(result: Result<GeneratedVoice>) -> VoiceResponse {
+    match result {
+        Ok(voice) => VoiceResponse {
+            success: true,
+            message: if voice.placeholder {
+                "placeholder"
+            } else if voice.draft {
+                "draft"
+            } else {
+                "generated"
+            }.to_string(),
+            cache_path: Some(voice.path),
+            error_kind: None,
+        },
+        Err(e) => {
+            log_level(LogLevel::Error, &format!("Error processing voice request: {}", e));
+            VoiceResponse {
+                success: false,
+                message: e.to_string(),
+                cache_path: None,
+                error_kind: Some(error_kind(&e)),
+            }
+        }
+    }
+}
+
+// Size of the audio frames a cached voice is sent to a --stream client in
+const STREAM_FRAME_BYTES: usize = 64 * 1024;
+
+// Send a voice to a --stream client while it is generated: each chunk as the provider
+// delivers it, then an end frame with the outcome. When the cached voice turns out
+// different from what was forwarded (a provider that doesn't stream, transcoding, channel
+// conversion, a retry, a joined line), a restart frame is sent and the whole file follows.
+// A client that hangs up early only stops the forwarding, the voice is still cached.
+async fn stream_voice(
+    socket: &mut Connection,
+    wire_format: WireFormat,
+    provider: Arc<dyn TtsProvider>,
+    general_config: &GeneralConfig,
+    mut job: VoiceJob,
+    voice_manager: Arc<Mutex<VoiceManager>>,
+) -> Result<()> {
+    let (audio_sink, mut chunks) = mpsc::unbounded_channel();
+    job.audio_sink = Some(audio_sink);
+    let generation = process_voice_request(provider, general_config, job, voice_manager);
+    tokio::pin!(generation);
+
+    let mut forwarded = Vec::new();
+    let mut connected = true;
+    let result = loop {
+        tokio::select! {
+            result = &mut generation => break result,
+            Some(chunk) = chunks.recv() => {
+                connected = connected && send_stream_frame(socket, wire_format, &StreamFrame::Audio(chunk.clone())).await;
+                forwarded.extend_from_slice(&chunk);
+            }
+        }
+    };
+    while let Ok(chunk) = chunks.try_recv() {
+        connected = connected && send_stream_frame(socket, wire_format, &StreamFrame::Audio(chunk.clone())).await;
+        forwarded.extend_from_slice(&chunk);
+    }
+    if !connected {
+        log_message("Streaming client disconnected, the voice was still cached");
+        return Ok(());
+    }
+
+    let result = match result {
+        Ok(voice) => match fs::read(&voice.path).await {
+            Ok(audio) => {
+                if audio != forwarded {
+                    if !forwarded.is_empty() {
+                        log_message(&format!("Streamed audio differs from the cached voice, resending {}", voice.path.display()));
+                        send_stream_frame(socket, wire_format, &StreamFrame::Restart).await;
+                    }
+                    for chunk in audio.chunks(STREAM_FRAME_BYTES) {
+                        send_stream_frame(socket, wire_format, &StreamFrame::Audio(chunk.to_vec())).await;
+                    }
+                }
+                Ok(voice)
+            }
+            Err(e) => Err(anyhow::Error::new(e).context(format!("Failed to read {}", voice.path.display()))),
+        },
+        Err(e) => Err(e),
+    };
+    send_stream_frame(socket, wire_format, &StreamFrame::End(voice_response(result))).await;
+    Ok(())
+}
+
+// Send one length-prefixed stream frame, returning whether the client is still there
+async fn send_stream_frame(socket: &mut Connection, wire_format: WireFormat, frame: &StreamFrame) -> bool {
+    let Ok(data) = frame.encode(wire_format) else {
+        return false;
+    };
+    socket.write_all(&(data.len() as u32).to_le_bytes()).await.is_ok()
+        && socket.write_all(&data).await.is_ok()
+}
+
+// Tell a fire-and-forget client where its voice will be, ignoring clients that already hung up
+async fn acknowledge(socket: &mut Connection, wire_format: WireFormat, message: &str, cache_path: &Result<PathBuf>) {
+    let response = match cache_path {
+        Ok(cache_path) => VoiceResponse {
+            success: true,
+            message: message.to_string(),
+            cache_path: Some(cache_path.clone()),
+            error_kind: None,
+        },
+        Err(e) => VoiceResponse {
+            success: false,
+            message: e.to_string(),
+            cache_path: None,
+            error_kind: Some(error_kind(e)),
+        },
+    };
+    let _ = send_response(socket, wire_format, &response).await;
+}
+
+// A generation waiting in the worker pool's queue
+struct QueuedJob {
+    general_config: GeneralConfig,
+    job: VoiceJob,
+    // The generation slot taken when the request arrived, released once it is processed
+    permit: OwnedSemaphorePermit,
+}
+
+// Start a fixed pool of workers draining a bounded queue of generations
+fn spawn_worker_pool(
+    worker_count: usize,
+    provider: Arc<dyn TtsProvider>,
+    voice_manager: Arc<Mutex<VoiceManager>>,
+) -> mpsc::Sender<QueuedJob> {
+    let (sender, receiver) = mpsc::channel::<QueuedJob>(worker_count);
+    let receiver = Arc::new(Mutex::new(receiver));
+    
+    for _ in 0..worker_count {
+        let receiver = receiver.clone();
+        let provider = provider.clone();
+        let voice_manager = voice_manager.clone();
+        
+        tokio::spawn(async move {
+            loop {
+                // Only hold the receiver lock while waiting, not while generating
+                let next = receiver.lock().await.recv().await;
+                let Some(queued) = next else {
+                    break;
+                };
+                
+                let result = process_voice_request(
+                    provider.clone(),
+                    &queued.general_config,
+                    queued.job,
+                    voice_manager.clone(),
+                ).await;
+                drop(queued.permit);
+                log_voice_outcome(result);
+            }
+        });
+    }
+    
+    sender
+}
+
+// Log how a background generation ended, since nobody is waiting on it
+fn log_voice_outcome(result: Result<GeneratedVoice>) {
+    match result {
+        Ok(voice) if voice.placeholder => {
+            log_message(&format!("Served silent placeholder: {}", voice.path.display()));
+        }
+        Ok(_) => {}
+        Err(e) => {
+            log_level(LogLevel::Error, &format!("Error processing voice request: {}", e));
+        }
+    }
+}
+
+// Write a length-prefixed JSON response back to a waiting client
+async fn send_response(socket: &mut Connection, wire_format: WireFormat, response: &VoiceResponse) -> Result<()> {
+    let response_data = wire_format.encode(response)
+        .context("Failed to serialize response")?;
+    
+    socket.write_all(&(response_data.len() as u32).to_le_bytes()).await
+        .context("Failed to send response length")?;
+    socket.write_all(&response_data).await
+        .context("Failed to send response data")?;
+    Ok(())
+}
+
+// Delete a text's cached voice and silent placeholder from every cache directory,
+// returning the files that were removed
+async fn evict_cache_entry(
+    general_config: &GeneralConfig,
+    cache_dir: Option<&Path>,
+    text: &str,
+    overrides: &VoiceOverrides,
+) -> Vec<PathBuf> {
+    let speech_text = general_config.text_pipeline.apply(text);
+    let voice_filename = generate_cache_filename(&overrides.cache_text(&speech_text, general_config), general_config);
+    let cache_dirs = match cache_dir {
+        Some(dir) => vec![dir.to_path_buf()],
+        None => general_config.cache_dir.paths(),
+    };
+    
+    let mut removed = Vec::new();
+    for cache_dir in &cache_dirs {
+        let placeholder = format!("{}.placeholder.wav", voice_hash(&voice_filename));
+        let draft = format!("{}.draft.wav", voice_hash(&voice_filename));
+        let kept_source = kept_source_path(&cache_dir.join(&voice_filename));
+        for path in [cache_dir.join(&voice_filename), cache_dir.join(placeholder), cache_dir.join(draft), kept_source] {
+            match fs::remove_file(&path).await {
+                Ok(()) => {
+                    log_message(&format!("Evicted cached voice: {}", path.display()));
+                    removed.push(path);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    log_level(LogLevel::Warn, &format!("Failed to evict {}: {}", path.display(), e));
+                }
+            }
+        }
+        if removed.iter().any(|path| path == &cache_dir.join(&voice_filename)) {
+            unindex_voices(cache_dir, &[cache_dir.join(&voice_filename)]).await;
+        }
+    }
+    removed
+}
+
+// Position of the first text list line, from start_text on (the top of the list when it is
+// empty), whose voice isn't in any cache directory yet, so a launcher can show how far the
+// voices are ready. None without a text list or once every line is cached.
+async fn first_missing_index(
+    general_config: &GeneralConfig,
+    cache_dir: Option<&Path>,
+    start_text: &str,
+    overrides: &VoiceOverrides,
+    voice_manager: &Arc<Mutex<VoiceManager>>,
+) -> Result<Option<usize>> {
+    let text_list_path = &general_config.text_list_path;
+    if text_list_path.is_empty() || !Path::new(text_list_path).exists() {
+        return Ok(None);
+    }
+    let cache_dirs = match cache_dir {
+        Some(dir) => vec![dir.to_path_buf()],
+        None => general_config.cache_dir.paths(),
+    };
+    
+    let (text_list, start_position) = {
+        let mut manager = voice_manager.lock().await;
+        let text_list = manager.get_text_list(text_list_path, &general_config.text_list_encoding).await?.clone();
+        let start_position = if start_text.is_empty() {
+            0
+        } else {
+            manager
+                .text_position(text_list_path, start_text, general_config.text_pipeline.normalization())
+                .context(format!("Text is not in the text list: {}", start_text))?
+        };
+        (text_list, start_position)
+    };
+    
+    Ok(text_list
+        .iter()
+        .enumerate()
+        .skip(start_position)
+        .filter(|(_, text)| !text.trim().is_empty())
+        .find(|(_, text)| {
+            let speech_text = general_config.text_pipeline.apply(text);
+            let voice_filename = generate_cache_filename(&overrides.cache_text(&speech_text, general_config), general_config);
+            find_cached_file(&cache_dirs, &voice_filename).is_none()
+        })
+        .map(|(line, _)| line))
+}
+
+// Function to process a voice request, returning the cached voice
+async fn process_voice_request(
+    provider: Arc<dyn TtsProvider>,
+    general_config: &GeneralConfig,
+    job: VoiceJob,
+    voice_manager: Arc<Mutex<VoiceManager>>,
+) -> Result<GeneratedVoice> {
+    let text = job.text.clone();
+    let started = Instant::now();
+    let result = match split_request_text(general_config, &text) {
+        Some(pieces) => resolve_joined_voice(provider, general_config, job, pieces, voice_manager).await,
+        None => resolve_voice(provider, general_config, job, voice_manager).await,
+    };
+    record_access(general_config, &text, &result, started.elapsed()).await;
+    let voice = result?;
+    
+    // Feed streaming consumers every real voice served, in request order
+    if !voice.placeholder && !general_config.ring_buffer_dir.is_empty() {
+        let ring_dir = Path::new(&general_config.ring_buffer_dir);
+        if let Err(e) = ring::publish(ring_dir, general_config.ring_buffer_size, &voice.path, &text).await {
+            log_level(LogLevel::Warn, &format!("Failed to publish voice to ring buffer: {}", e));
+        }
+    }
+    
+    Ok(voice)
+}
+
+// Write the access log line for a finished request
+async fn record_access(general_config: &GeneralConfig, text: &str, result: &Result<GeneratedVoice>, latency: Duration) {
+    let (cache_hit, provider, status, bytes) = match result {
+        Ok(voice) => {
+            let provider = if voice.cached {
+                "cache"
+            } else if voice.draft {
+                general_config.draft_provider.as_str()
+            } else {
+                PROVIDER_NAME.get().map_or("-", String::as_str)
+            };
+            let status = if voice.placeholder {
+                "placeholder".to_string()
+            } else if voice.draft {
+                "draft".to_string()
+            } else {
+                "ok".to_string()
+            };
+            let bytes = fs::metadata(&voice.path).await.ok().map(|metadata| metadata.len());
+            (Some(voice.cached), provider, status, bytes)
+        }
+        Err(e) => (None, PROVIDER_NAME.get().map_or("-", String::as_str), format!("error:{}", error_kind(e)), None),
+    };
+
+    access_log::record(&access_log::AccessEntry {
+        text,
+        cache_hit,
+        provider,
+        status: &status,
+        latency,
+        bytes,
+    });
+}
+
+// Decode the one-off reference clip if the request carries one
+fn decode_ref_audio(job: &VoiceJob) -> Result<Option<Vec<u8>>> {
+    match &job.ref_audio_base64 {
+        Some(encoded) => Ok(Some(
+            base64::engine::general_purpose::STANDARD
+                .decode(encoded)
+                .context("Failed to decode reference audio")?,
+        )),
+        None => Ok(None),
+    }
+}
+
+// Cache filename for a job, hashed from its normalized text so equivalent requests share an entry
+fn job_voice_filename(general_config: &GeneralConfig, job: &VoiceJob, ref_audio: Option<&[u8]>) -> String {
+    let speech_text = general_config.text_pipeline.apply(&job.text);
+    let key_text = VoiceOverrides::for_job(job).cache_text(&speech_text, general_config);
+    match ref_audio {
+        Some(ref_audio) => generate_ref_cache_filename(&key_text, ref_audio, general_config),
+        None => generate_cache_filename(&key_text, general_config),
+    }
+}
+
+// Use cache directories from config if not provided in request
+fn job_cache_dirs(general_config: &GeneralConfig, job: &VoiceJob) -> Result<Vec<PathBuf>> {
+    let cache_dirs = match &job.cache_dir {
+        Some(dir) => vec![dir.clone()],
+        None => general_config.cache_dir.paths(),
+    };
+    if cache_dirs.is_empty() {
+        return Err(anyhow::anyhow!("No cache directory specified"));
+    }
+    Ok(cache_dirs)
+}
+
+// Where a job's voice is cached, or will be once generated
+fn job_cache_path(general_config: &GeneralConfig, job: &VoiceJob) -> Result<PathBuf> {
+    let ref_audio = decode_ref_audio(job)?;
+    let voice_filename = job_voice_filename(general_config, job, ref_audio.as_deref());
+    let cache_dirs = job_cache_dirs(general_config, job)?;
+    if let Some(cached_path) = find_cached_file(&cache_dirs, &voice_filename) {
+        return Ok(cached_path);
+    }
+    let cache_dir = first_writable_dir(&cache_dirs)
+        .context("No writable cache directory")?;
+    Ok(cache_dir.join(voice_filename))
+}
+
+// Split a request holding several utterances on request_delimiter, or None for a single utterance
+fn split_request_text(general_config: &GeneralConfig, text: &str) -> Option<Vec<String>> {
+    if general_config.request_delimiter.is_empty() {
+        return None;
+    }
+    let pieces: Vec<String> = text
+        .split(general_config.request_delimiter.as_str())
+        .map(str::trim)
+        .filter(|piece| !piece.is_empty())
+        .map(str::to_string)
+        .collect();
+    (pieces.len() > 1).then_some(pieces)
+}
+
+// Resolve each utterance of a delimited request as its own voice, then cache them
+// joined under the full text's filename, which is what the client looks up
+async fn resolve_joined_voice(
+    provider: Arc<dyn TtsProvider>,
+    general_config: &GeneralConfig,
+    job: VoiceJob,
+    pieces: Vec<String>,
+    voice_manager: Arc<Mutex<VoiceManager>>,
+) -> Result<GeneratedVoice> {
+    if general_config.output_format != "wav" {
+        anyhow::bail!("request_delimiter joins utterances as WAV and needs output_format = \"wav\"");
+    }
+    let ref_audio = decode_ref_audio(&job)?;
+    let voice_filename = job_voice_filename(general_config, &job, ref_audio.as_deref());
+    let cache_dirs = job_cache_dirs(general_config, &job)?;
+
+    if let Some(cached_path) = find_cached_file(&cache_dirs, &voice_filename) {
+        log_level(LogLevel::Debug, &format!("Voice exists in cache: {}", cached_path.display()));
+        stats::STATS.record_cache_hit();
+        spawn_prefetch(&provider, general_config, &cache_dirs, &job, &voice_manager);
+        return Ok(GeneratedVoice { path: cached_path, placeholder: false, draft: false, cached: true });
+    }
+
+    log_message(&format!("Request holds {} utterances, generating each separately", pieces.len()));
+    let mut wavs = Vec::with_capacity(pieces.len());
+    let mut placeholder = false;
+    let mut draft = false;
+    for piece in pieces {
+        let piece_job = VoiceJob {
+            text: piece,
+            cache_dir: job.cache_dir.clone(),
+            ref_audio_base64: job.ref_audio_base64.clone(),
+            next_texts: Vec::new(),
+            profile: job.profile.clone(),
+            streaming: job.streaming,
+            voice_id: job.voice_id.clone(),
+            audio_sink: None,
+        };
+        let voice = resolve_voice(provider.clone(), general_config, piece_job, voice_manager.clone()).await?;
+        placeholder |= voice.placeholder;
+        draft |= voice.draft;
+        wavs.push(fs::read(&voice.path).await.context("Failed to read utterance voice")?);
+    }
+    let joined = wav::concat(&wavs, general_config.request_delimiter_gap_ms)
+        .context("Failed to join utterance voices")?;
+
+    // Silence stand-ins and drafts must not be served as the real joined voice later,
+    // so the pieces are joined again once they have been regenerated or upgraded
+    let cache_dir = first_writable_dir(&cache_dirs)
+        .context("No writable cache directory")?;
+    let path = if placeholder {
+        cache_dir.join(format!("{}.placeholder.wav", voice_hash(&voice_filename)))
+    } else if draft {
+        cache_dir.join(format!("{}.draft.wav", voice_hash(&voice_filename)))
+    } else {
+        cache_dir.join(&voice_filename)
+    };
+    // Readers of the cache must never find a half-joined voice
+    write_file_atomically(&path, &joined)
+        .await
+        .context(format!("Failed to write joined voice {}", path.display()))?;
+    log_level(LogLevel::Debug, &format!("Joined utterances into {}", path.display()));
+    if !placeholder && !draft {
+        index_voice(&path, &job.text, &VoiceOverrides::for_job(&job), general_config).await;
+    }
+
+    spawn_prefetch(&provider, general_config, &cache_dirs, &job, &voice_manager);
+    Ok(GeneratedVoice { path, placeholder, draft, cached: false })
+}
+
+// Find the voice for a job in the cache, generating it if needed
+async fn resolve_voice(
+    provider: Arc<dyn TtsProvider>,
+    general_config: &GeneralConfig,
+    job: VoiceJob,
+    voice_manager: Arc<Mutex<VoiceManager>>,
+) -> Result<GeneratedVoice> {
+    let ref_audio = decode_ref_audio(&job)?;
+    let speech_text = general_config.text_pipeline.apply(&job.text);
+    let voice_filename = job_voice_filename(general_config, &job, ref_audio.as_deref());
+    let cache_dirs = job_cache_dirs(general_config, &job)?;
+
+    // A line re-read this run was already prefetched around, so only its file is needed
+    if general_config.skip_redelivery
+        && let Some(delivered_path) = voice_manager.lock().await.delivered_path(&voice_filename)
+    {
+        log_level(LogLevel::Debug, &format!("Voice already delivered this run: {}", delivered_path.display()));
+        stats::STATS.record_cache_hit();
+        return Ok(GeneratedVoice { path: delivered_path, placeholder: false, draft: false, cached: true });
+    }
+
+    // Check if the requested voice already exists in any cache directory
+    if let Some(cached_path) = find_cached_file(&cache_dirs, &voice_filename) {
+        // The voice exists in cache - client will handle copying it
+        log_level(LogLevel::Debug, &format!("Voice exists in cache: {}", cached_path.display()));
+        stats::STATS.record_cache_hit();
+        if general_config.skip_redelivery {
+            voice_manager.lock().await.mark_delivered(&voice_filename, &cached_path);
+        }
+        
+        spawn_prefetch(&provider, general_config, &cache_dirs, &job, &voice_manager);
+        
+        return Ok(GeneratedVoice { path: cached_path, placeholder: false, draft: false, cached: true });
+    }
+
+    // New voices are written to the first writable cache directory
+    let cache_dir = first_writable_dir(&cache_dirs)
+        .context("No writable cache directory")?;
+    let cached_path = cache_dir.join(&voice_filename);
+
+    // Track this generation in memory, unless the voice is already being generated by another
+    // request or a prefetch: then wait for that one and serve what it cached. If it failed
+    // there is nothing cached, and this request makes its own attempt.
+    let cache_path_str = cache_dir.to_string_lossy().to_string();
+    let voice_id = voice_id(&voice_filename);
+    let (generation_id, cancel_token) = loop {
+        let mut manager = voice_manager.lock().await;
+        if !manager.is_in_flight(&voice_filename) {
+            manager.mark_in_progress(&cache_path_str, voice_id);
+            break manager.register_cancel_token(&voice_filename);
+        }
+        // Created under the lock, so the wakeup can't slip in before it
+        let waiters = manager.generation_finished(&voice_filename);
+        let finished = waiters.notified();
+        drop(manager);
+        log_message(&format!("Voice is already being generated, waiting for it: {}", voice_filename));
+        finished.await;
+        if let Some(cached_path) = find_cached_file(&cache_dirs, &voice_filename) {
+            stats::STATS.record_cache_hit();
+            return Ok(GeneratedVoice { path: cached_path, placeholder: false, draft: false, cached: true });
+        }
+    };
+    stats::STATS.record_cache_miss();
+
+    // Write the uploaded reference clip somewhere the provider can read it
+    let ref_audio_file = match &ref_audio {
+        Some(ref_audio) => Some(write_temp_ref_audio(ref_audio).await?),
+        None => None,
+    };
+    let overrides = VoiceOverrides {
+        ref_audio_path: ref_audio_file
+            .as_ref()
+            .map(|path| path.to_string_lossy().to_string()),
+        audio_sink: job.audio_sink.clone(),
+        ..VoiceOverrides::for_job(&job)
+    };
+
+    // Generate speech directly to cache file, first with the draft provider if there is one
+    let draft_provider = DRAFT_PROVIDER.get().map(|draft| draft.current());
+    let result = match &draft_provider {
+        Some(draft_provider) => match generate_cancellable(
+            draft_provider,
+            &speech_text,
+            &cached_path,
+            &overrides,
+            cancel_token.clone(),
+            general_config,
+        ).await {
+            // Deliver the draft now and let the main provider replace it in the background
+            Ok(()) => {
+                log_message(&format!("Draft voice ready, upgrading in the background: {}", cached_path.display()));
+                index_voice(&cached_path, &job.text, &overrides, general_config).await;
+                // The client streaming this line is served the draft, not the upgrade
+                let upgrade = DraftUpgrade {
+                    speech_text,
+                    cached_path: cached_path.clone(),
+                    overrides: VoiceOverrides { audio_sink: None, ..overrides },
+                    ref_audio_file,
+                    voice_filename,
+                    cache_path_str,
+                    voice_id,
+                    generation_id,
+                    cancel_token,
+                };
+                tokio::spawn(upgrade_draft(provider.clone(), general_config.clone(), voice_manager.clone(), upgrade));
+                spawn_prefetch(&provider, general_config, &cache_dirs, &job, &voice_manager);
+                return Ok(GeneratedVoice { path: cached_path, placeholder: false, draft: true, cached: false });
+            }
+            Err(e) if cancel_token.is_cancelled() => Err(e),
+            // A failing draft provider mustn't fail the request while the main one may work
+            Err(e) => {
+                log_level(LogLevel::Warn, &format!("Draft provider failed, generating with the main provider: {}", e));
+                generate_cancellable(
+                    &provider,
+                    &speech_text,
+                    &cached_path,
+                    &overrides,
+                    cancel_token.clone(),
+                    general_config,
+                ).await
+            }
+        },
+        None => generate_cancellable(
+            &provider,
+            &speech_text,
+            &cached_path,
+            &overrides,
+            cancel_token.clone(),
+            general_config,
+        ).await,
+    };
+
+    if let Some(ref_audio_file) = &ref_audio_file {
+        let _ = fs::remove_file(ref_audio_file).await;
+    }
+    match result {
+        Ok(_) => {
+            log_message(&format!("Successfully generated voice to cache: {}", cached_path.display()));
+            index_voice(&cached_path, &job.text, &overrides, general_config).await;
+            if general_config.detect_duplicate_audio {
+                check_duplicate_audio(&voice_manager, &speech_text, &cached_path).await;
+            }
+            
+            // Mark as completed
+            {
+                let mut manager = voice_manager.lock().await;
+                manager.mark_completed(&cache_path_str, voice_id);
+                manager.remove_cancel_token(&voice_filename, generation_id);
+                if general_config.skip_redelivery {
+                    manager.mark_delivered(&voice_filename, &cached_path);
+                }
+            }
+
+            // Trim the cache in the background, the caller shouldn't wait for the scan
+            if general_config.max_cache_bytes > 0 {
+                tokio::spawn(enforce_cache_limit(
+                    cache_dir.clone(),
+                    general_config.max_cache_bytes,
+                    voice_manager.clone(),
+                    cached_path.clone(),
+                ));
+            }
+            
+            spawn_prefetch(&provider, general_config, &cache_dirs, &job, &voice_manager);
+        },
+        Err(e) => {
+            // Mark generation as failed
+            {
+                let mut manager = voice_manager.lock().await;
+                manager.mark_completed(&cache_path_str, voice_id);
+                manager.remove_cancel_token(&voice_filename, generation_id);
+            }
+            
+            // Keep dialog moving with a short silence instead of nothing
+            if general_config.silence_fallback {
+                log_level(LogLevel::Warn, &format!("Generation failed, falling back to silence: {}", e));
+                let placeholder_path = write_silence_placeholder(
+                    &cache_dir,
+                    &voice_filename,
+                    general_config.silence_duration_ms,
+                ).await?;
+                return Ok(GeneratedVoice { path: placeholder_path, placeholder: true, draft: false, cached: false });
+            }
+            
+            return Err(e);
+        }
+    }
+    
+    Ok(GeneratedVoice { path: cached_path, placeholder: false, draft: false, cached: false })
+}
+
+// A draft voice waiting to be regenerated by the main provider
+struct DraftUpgrade {
+    speech_text: String,
+    cached_path: PathBuf,
+    overrides: VoiceOverrides,
+    // Uploaded reference clip, removed once the upgrade is done with it
+    ref_audio_file: Option<PathBuf>,
+    voice_filename: String,
+    cache_path_str: String,
+    voice_id: usize,
+    generation_id: u64,
+    cancel_token: CancellationToken,
+}
+
+// Regenerate a draft with the main provider and swap it into the cache file. The new
+// voice is written beside the draft and renamed over it, so readers get one or the other whole.
+async fn upgrade_draft(
+    provider: Arc<dyn TtsProvider>,
+    general_config: GeneralConfig,
+    voice_manager: Arc<Mutex<VoiceManager>>,
+    upgrade: DraftUpgrade,
+) {
+    let mut upgrade_name = upgrade.cached_path.as_os_str().to_os_string();
+    upgrade_name.push(".upgrade");
+    let upgrade_path = PathBuf::from(upgrade_name);
+
+    // A scene of drafts must not start its upgrades all at once
+    let permit = match GENERATION_SEMAPHORE.get() {
+        Some(semaphore) => Some(semaphore.clone().acquire_owned().await),
+        None => None,
+    };
+    let generated = match permit {
+        Some(Err(_)) => Err(anyhow::anyhow!("Server is shutting down")),
+        _ => generate_cancellable(
+            &provider,
+            &upgrade.speech_text,
+            &upgrade_path,
+            &upgrade.overrides,
+            upgrade.cancel_token,
+            &general_config,
+        ).await,
+    };
+    drop(permit);
+    let result = match generated {
+        Ok(()) => fs::rename(&upgrade_path, &upgrade.cached_path)
+            .await
+            .context("Failed to replace draft voice"),
+        Err(e) => Err(e),
+    };
+
+    if let Some(ref_audio_file) = &upgrade.ref_audio_file {
+        let _ = fs::remove_file(ref_audio_file).await;
+    }
+    match result {
+        Ok(()) => {
+            log_message(&format!("Upgraded draft voice: {}", upgrade.cached_path.display()));
+            if general_config.detect_duplicate_audio {
+                check_duplicate_audio(&voice_manager, &upgrade.speech_text, &upgrade.cached_path).await;
+            }
+        }
+        Err(e) => {
+            // Drop the draft too, so the line is generated properly the next time it's requested
+            let _ = fs::remove_file(&upgrade_path).await;
+            let _ = fs::remove_file(&upgrade.cached_path).await;
+            log_level(LogLevel::Error, &format!(
+                "Failed to upgrade draft voice {}, removed it: {}",
+                upgrade.cached_path.display(), e
+            ));
+        }
+    }
+
+    let mut manager = voice_manager.lock().await;
+    manager.mark_completed(&upgrade.cache_path_str, upgrade.voice_id);
+    manager.remove_cancel_token(&upgrade.voice_filename, upgrade.generation_id);
+}
+
+// Warn when a new voice sounds like the previous one despite different text,
+// a GPT-SoVITS failure mode that is otherwise easy to miss
+async fn check_duplicate_audio(voice_manager: &Arc<Mutex<VoiceManager>>, text: &str, voice_path: &Path) {
+    let Ok(audio) = fs::read(voice_path).await else {
+        return;
+    };
+    let Some(fingerprint) = wav::fingerprint(&audio) else {
+        return;
+    };
+    
+    let previous_text = voice_manager.lock().await.record_fingerprint(text, fingerprint);
+    if let Some(previous_text) = previous_text {
+        log_level(LogLevel::Warn, &format!(
+            "near-identical audio generated for different texts: \"{}\" and \"{}\"",
+            previous_text, text
+        ));
+    }
+}
+
+// Record what a newly written voice says in its cache directory's index
+async fn index_voice(path: &Path, text: &str, overrides: &VoiceOverrides, general_config: &GeneralConfig) {
+    let (Some(cache_dir), Some(filename)) = (path.parent(), path.file_name()) else {
+        return;
+    };
+    let filename = filename.to_string_lossy().to_string();
+    let entry = CacheIndexEntry {
+        text: text.to_string(),
+        voice_id: overrides.voice_id.clone(),
+        profile: overrides.profile.clone(),
+        params_hash: general_config.voice_params_hash.clone(),
+    };
+    if let Err(e) = update_cache_index(cache_dir, |index| {
+        index.insert(filename, entry);
+    }).await {
+        log_level(LogLevel::Warn, &format!("Failed to update cache index for {}: {}", path.display(), e));
+    }
+}
+
+// Drop deleted voices from their cache directory's index
+async fn unindex_voices(cache_dir: &Path, paths: &[PathBuf]) {
+    if paths.is_empty() {
+        return;
+    }
+    if let Err(e) = update_cache_index(cache_dir, |index| {
+        for path in paths {
+            if let Some(filename) = path.file_name() {
+                index.remove(filename.to_string_lossy().as_ref());
+            }
+        }
+    }).await {
+        log_level(LogLevel::Warn, &format!("Failed to update cache index in {}: {}", cache_dir.display(), e));
+    }
+}
+
+// Delete the least recently used voices in a cache directory until its voice files fit
+// in max_bytes again. Recency is the later of each file's access and modification
+// time; voices still being generated and the one just written are never deleted.
+// A source WAV kept by keep_source_wav counts and goes together with its voice.
+async fn enforce_cache_limit(
+    cache_dir: PathBuf,
+    max_bytes: u64,
+    voice_manager: Arc<Mutex<VoiceManager>>,
+    just_written: PathBuf,
+) {
+    let mut entries = match fs::read_dir(&cache_dir).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            log_level(LogLevel::Warn, &format!("Failed to scan cache {} for eviction: {}", cache_dir.display(), e));
+            return;
+        }
+    };
+
+    let mut voices = Vec::new();
+    let mut sources = HashMap::new();
+    let mut total_bytes = 0;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        let is_voice = path
+            .extension()
+            .is_some_and(|extension| OUTPUT_FORMATS.iter().any(|format| extension == *format));
+        if !is_voice {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata().await else {
+            continue;
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+        let modified = metadata.modified().ok();
+        let used = metadata.accessed().ok().max(modified);
+        total_bytes += metadata.len();
+        if path.to_string_lossy().ends_with(".src.wav") {
+            sources.insert(path, (used, metadata.len()));
+        } else {
+            voices.push((used, metadata.len(), path, None));
+        }
+    }
+    if total_bytes <= max_bytes {
+        return;
+    }
+
+    // Only a plain <hash>.<format> voice owns a kept source, not its placeholder or draft
+    for (_, size, path, source) in &mut voices {
+        let is_cached_voice = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().matches('.').count() == 1);
+        let source_path = kept_source_path(path);
+        if is_cached_voice && let Some((_, source_size)) = sources.remove(&source_path) {
+            *size += source_size;
+            *source = Some(source_path);
+        }
+    }
+    voices.extend(sources.into_iter().map(|(path, (used, size))| (used, size, path, None)));
+
+    // Pick the victims under the lock, then delete them without holding it
+    voices.sort();
+    let mut victims = Vec::new();
+    let mut remaining_bytes = total_bytes;
+    let manager = voice_manager.lock().await;
+    for (_, size, path, source) in voices {
+        if remaining_bytes <= max_bytes {
+            break;
+        }
+        if path == just_written || manager.is_generating_file(&path) {
+            continue;
+        }
+        remaining_bytes -= size;
+        victims.push((size, path, source));
+    }
+    drop(manager);
+
+    let mut evicted = Vec::new();
+    for (size, path, source) in victims {
+        match fs::remove_file(&path).await {
+            Ok(()) => {
+                if let Some(source) = source
+                    && let Err(e) = fs::remove_file(&source).await
+                {
+                    log_level(LogLevel::Warn, &format!("Failed to evict source WAV {}: {}", source.display(), e));
+                }
+                total_bytes -= size;
+                evicted.push(path);
+            }
+            Err(e) => log_level(LogLevel::Warn, &format!("Failed to evict cached voice {}: {}", path.display(), e)),
+        }
+    }
+    log_message(&format!(
+        "Evicted {} voices from {}, cache now holds {} bytes (max_cache_bytes = {})",
+        evicted.len(), cache_dir.display(), total_bytes, max_bytes
+    ));
+    unindex_voices(&cache_dir, &evicted).await;
+}
+
+// Write a silent WAV next to where the real voice would go. It uses its own
+// name so the real voice is still generated the next time the line is requested,
+// and is renamed into place so a client copying it never reads a partial file.
+async fn write_silence_placeholder(cache_dir: &Path, voice_filename: &str, duration_ms: u64) -> Result<PathBuf> {
+    let path = cache_dir.join(format!("{}.placeholder.wav", voice_hash(voice_filename)));
+    write_file_atomically(&path, &wav::silent_wav(duration_ms))
+        .await
+        .context("Failed to write silence placeholder")?;
+    Ok(path)
+}
+
+// Kick off background prefetching once a request has been served
+fn spawn_prefetch(
+    provider: &Arc<dyn TtsProvider>,
+    general_config: &GeneralConfig,
+    cache_dirs: &[PathBuf],
+    job: &VoiceJob,
+    voice_manager: &Arc<Mutex<VoiceManager>>,
+) {
+    let provider = provider.clone();
+    let general_config = general_config.clone();
+    let cache_dirs = cache_dirs.to_vec();
+    let voice_manager = voice_manager.clone();
+    let overrides = VoiceOverrides::for_job(job);
+
+    // The engine knows what comes next across branches, so its hints replace the text-list lookahead
+    if !job.next_texts.is_empty() {
+        let next_texts = job.next_texts.clone();
+        tokio::spawn(async move {
+            if let Err(e) = prefetch_next_texts(
+                provider,
+                next_texts,
+                cache_dirs,
+                &general_config,
+                voice_manager,
+                overrides,
+            ).await {
+                log_level(LogLevel::Error, &format!("Prefetch error: {}", e));
+            }
+        });
+        return;
+    }
+
+    // Check if we should initiate prefetching
+    if general_config.text_list_path.is_empty() {
+        return;
+    }
+    let text_list_path = PathBuf::from(&general_config.text_list_path);
+    if !text_list_path.exists() {
+        return;
+    }
+
+    // Start background prefetch task
+    let text = job.text.clone();
+    tokio::spawn(async move {
+        if let Err(e) = try_prefetch_voices(
+            provider,
+            &text_list_path,
+            &cache_dirs,
+            &general_config,
+            &text,
+            voice_manager,
+            overrides,
+        ).await {
+            log_level(LogLevel::Error, &format!("Prefetch error: {}", e));
+        }
+    });
+}
+
+// Function to prefetch the lines the engine says may come next
+async fn prefetch_next_texts(
+    provider: Arc<dyn TtsProvider>,
+    next_texts: Vec<String>,
+    cache_dirs: Vec<PathBuf>,
+    general_config: &GeneralConfig,
+    voice_manager: Arc<Mutex<VoiceManager>>,
+    overrides: VoiceOverrides,
+) -> Result<()> {
+    // New voices always go to the first writable cache directory
+    let cache_dir = first_writable_dir(&cache_dirs)
+        .context("No writable cache directory")?;
+    let cache_path_str = cache_dir.to_string_lossy().to_string();
+
+    log_level(LogLevel::Debug, &format!("Prefetching {} hinted next lines", next_texts.len()));
+
+    let mut generated_count = 0;
+    for text in &next_texts {
+        if text.trim().is_empty() {
+            continue;
+        }
+
+        let speech_text = general_config.text_pipeline.apply(text);
+        let voice_filename = generate_cache_filename(&overrides.cache_text(&speech_text, general_config), general_config);
+        let output_path = cache_dir.join(&voice_filename);
+        let voice_id = voice_id(&voice_filename);
+
+        // Skip lines already cached or being generated
+        if find_cached_file(&cache_dirs, &voice_filename).is_some() {
+            log_level(LogLevel::Debug, &format!("Skipping existing voice for hinted line: {}", text));
+            continue;
+        }
+
+        // Take a generation slot like any other generation, before claiming the line
+        let permit = match GENERATION_SEMAPHORE.get() {
+            Some(semaphore) => match semaphore.clone().acquire_owned().await {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    log_message("Server is shutting down, stopping prefetch");
+                    break;
+                }
+            },
+            None => None,
+        };
+        let (generation_id, cancel_token) = {
+            let mut manager = voice_manager.lock().await;
+            if manager.shutting_down {
+                log_message("Server is shutting down, stopping prefetch");
+                break;
+            }
+            if manager.is_generating(&cache_path_str, voice_id) || manager.is_in_flight(&voice_filename) {
+                log_level(LogLevel::Debug, &format!("Skipping in-progress voice for hinted line: {}", text));
+                continue;
+            }
+            manager.mark_in_progress(&cache_path_str, voice_id);
+            manager.register_cancel_token(&voice_filename)
+        };
+
+        log_level(LogLevel::Debug, &format!("Pre-generating voice for hinted line: {}", text));
+        let result = generate_cancellable(
+            &provider,
+            &speech_text,
+            &output_path,
+            &overrides,
+            cancel_token,
+            general_config,
+        ).await;
+        drop(permit);
+        match result {
+            Ok(_) => {
+                log_message(&format!("Successfully pre-generated voice for hinted line: {}", text));
+                index_voice(&output_path, text, &overrides, general_config).await;
+                if general_config.detect_duplicate_audio {
+                    check_duplicate_audio(&voice_manager, &speech_text, &output_path).await;
+                }
+                generated_count += 1;
+            }
+            Err(e) => {
+                log_level(LogLevel::Error, &format!("Failed to pre-generate voice for hinted line {}: {}", text, e));
+            }
+        }
+
+        {
+            let mut manager = voice_manager.lock().await;
+            manager.mark_completed(&cache_path_str, voice_id);
+            manager.remove_cancel_token(&voice_filename, generation_id);
+        }
+    }
+
+    log_message(&format!("Hinted pre-generation completed. Generated {} new voices.", generated_count));
+    Ok(())
+}
+
+// Write an uploaded reference clip to a unique temporary file
+async fn write_temp_ref_audio(ref_audio: &[u8]) -> Result<PathBuf> {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+    let path = std::env::temp_dir().join(format!(
+        "krkr-tts-ref-{}-{}.wav",
+        std::process::id(),
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&path, ref_audio)
+        .await
+        .context("Failed to write reference audio")?;
+    Ok(path)
+}
+
+// Function to attempt to prefetch voices from a text list
+async fn try_prefetch_voices(
+    provider: Arc<dyn TtsProvider>,
+    text_list_path: &Path,
+    cache_dirs: &[PathBuf],
+    general_config: &GeneralConfig,
+    current_text: &str,
+    voice_manager: Arc<Mutex<VoiceManager>>,
+    overrides: VoiceOverrides,
+) -> Result<()> {
+    if !text_list_path.exists() {
+        return Ok(());
+    }
+    
+    log_level(LogLevel::Debug, &format!("Found text list: {}", text_list_path.display()));
+    
+    // Find the position of the current text in the list
+    let (current_position, text_list_len) = {
+        let mut manager = voice_manager.lock().await;
+        let text_list_path_str = text_list_path.to_string_lossy().to_string();
+        let text_list_len = manager.get_text_list(&text_list_path_str, &general_config.text_list_encoding).await?.len();
+        let current_position = manager
+            .text_position(&text_list_path_str, current_text, general_config.text_pipeline.normalization());
+        (current_position, text_list_len)
+    };
+    
+    // Start prefetching from the next position, if the list goes on past the current line
+    let mut start_position = match (current_position, prefetch_start(current_position, text_list_len)) {
+        (_, Some(start_position)) => start_position,
+        (None, None) => {
+            log_level(LogLevel::Debug, "Current text is not in the text list, nothing to prefetch");
+            return Ok(());
+        }
+        (Some(_), None) => {
+            log_level(LogLevel::Debug, "Current text is the last line of the text list, nothing to prefetch");
+            return Ok(());
+        }
+    };
+    
+    // While a prefetch for this list runs, later triggers only move where it continues from
+    let prefetch_key = (
+        text_list_path.to_string_lossy().to_string(),
+        cache_dirs.to_vec(),
+        overrides.cache_text("", general_config),
+    );
+    if !voice_manager.lock().await.claim_prefetch(&prefetch_key, start_position) {
+        log_level(LogLevel::Debug, &format!("Prefetch already running, continuing it from position {}", start_position));
+        return Ok(());
+    }
+    
+    loop {
+        log_level(LogLevel::Debug, &format!("Starting prefetch from position {}", start_position));
+        
+        // Prefetch the next specified number of voices
+        if start_position < text_list_len {
+            if let Err(e) = prefetch_voices(
+                provider.clone(),
+                text_list_path.to_path_buf(),
+                cache_dirs.to_vec(),
+                general_config,
+                start_position,
+                voice_manager.clone(),
+                overrides.clone(),
+            ).await {
+                log_level(LogLevel::Error, &format!("Prefetch error: {}", e));
+            }
+        } else {
+            log_level(LogLevel::Debug, "No more voices to prefetch (end of text list)");
+        }
+        
+        match voice_manager.lock().await.finish_prefetch(&prefetch_key, start_position) {
+            Some(next_position) => start_position = next_position,
+            None => break,
+        }
+    }
+    
+    Ok(())
+}
+
+// First line to prefetch after the line at `current_position`, or None when the current
+// text isn't in the list or no line follows it
+fn prefetch_start(current_position: Option<usize>, text_list_len: usize) -> Option<usize> {
+    current_position
+        .map(|position| position.saturating_add(1))
+        .filter(|&start| start < text_list_len)
+}
+
+// Generate every non-empty line of a text list that isn't cached yet, for --warm.
+// Lines run through the shared generation semaphore with no delay between them,
+// and the number of lines that still failed after their retries is returned.
+async fn warm_text_list(
+    provider: Arc<dyn TtsProvider>,
+    text_list_path: &Path,
+    general_config: &GeneralConfig,
+    voice_manager: Arc<Mutex<VoiceManager>>,
+    semaphore: Arc<Semaphore>,
+) -> Result<usize> {
+    let cache_dirs = general_config.cache_dir.paths();
+    let cache_dir = first_writable_dir(&cache_dirs)
+        .context("No writable cache directory")?;
+    let text_list_path_str = text_list_path.to_string_lossy().to_string();
+    let text_list = voice_manager
+        .lock()
+        .await
+        .get_text_list(&text_list_path_str, &general_config.text_list_encoding)
+        .await?
+        .clone();
+    let overrides = VoiceOverrides::default();
+
+    // Collect the lines still missing, each voice once however often its line repeats
+    let mut seen = HashSet::new();
+    let mut pending = Vec::new();
+    for (line, text) in text_list.iter().enumerate() {
+        if text.trim().is_empty() {
+            continue;
+        }
+        let speech_text = general_config.text_pipeline.apply(text);
+        let voice_filename = generate_cache_filename(&overrides.cache_text(&speech_text, general_config), general_config);
+        if find_cached_file(&cache_dirs, &voice_filename).is_some() || !seen.insert(voice_filename.clone()) {
+            continue;
+        }
+        pending.push((line, text, speech_text, voice_filename));
+    }
+    log_message(&format!(
+        "Warming {}: {} of {} lines not cached yet",
+        text_list_path.display(), pending.len(), text_list.len()
+    ));
+
+    let total = pending.len();
+    let mut done = 0;
+    let mut failed = 0;
+    let mut running = FuturesUnordered::new();
+    let mut pending = pending.into_iter();
+    loop {
+        while running.len() < general_config.max_concurrent_tts.max(1) {
+            let Some((line, text, speech_text, voice_filename)) = pending.next() else {
+                break;
+            };
+            let output_path = cache_dir.join(&voice_filename);
+            let (generation_id, cancel_token) = {
+                let mut manager = voice_manager.lock().await;
+                if manager.shutting_down {
+                    pending = Vec::new().into_iter();
+                    break;
+                }
+                manager.mark_in_progress(&text_list_path_str, line);
+                manager.register_cancel_token(&voice_filename)
+            };
+
+            let provider = &provider;
+            let overrides = &overrides;
+            let voice_manager = &voice_manager;
+            let semaphore = &semaphore;
+            let text_list_path_str = &text_list_path_str;
+            running.push(async move {
+                let result = match semaphore.clone().acquire_owned().await {
+                    Ok(_permit) => generate_cancellable(
+                        provider,
+                        &speech_text,
+                        &output_path,
+                        overrides,
+                        cancel_token,
+                        general_config,
+                    ).await,
+                    Err(_) => Err(anyhow::anyhow!("Server is shutting down")),
+                };
+                if result.is_ok() {
+                    index_voice(&output_path, text, overrides, general_config).await;
+                }
+                {
+                    let mut manager = voice_manager.lock().await;
+                    manager.mark_completed(text_list_path_str, line);
+                    manager.remove_cancel_token(&voice_filename, generation_id);
+                }
+                (line, text, result)
+            });
+        }
+
+        let Some((line, text, result)) = running.next().await else {
+            break;
+        };
+        done += 1;
+        match result {
+            Ok(()) => log_message(&format!("[{}/{}] Generated line {}: {}", done, total, line, text)),
+            Err(e) => {
+                failed += 1;
+                log_level(LogLevel::Error, &format!("[{}/{}] Failed to generate line {}: {}", done, total, line, e));
+            }
+        }
+    }
+
+    log_message(&format!("Warming completed: {} generated, {} failed", total - failed, failed));
+    Ok(failed)
+}
+
+// Function to load configurations or retrieve from cache
+async fn load_or_get_config(
+    config_cache: &Arc<Mutex<HashMap<PathBuf, GeneralConfig>>>,
+    config_path: &PathBuf,
+    allowed_config_paths: &[PathBuf],
+) -> Result<GeneralConfig> {
+    let mut cache = config_cache.lock().await;
+    
+    if let Some(config) = cache.get(config_path) {
+        return Ok(config.clone());
+    }
+    
+    // Stdin belongs to the server process, a request can't send its config that way
+    if config_path == Path::new("-") {
+        anyhow::bail!("Configuration from stdin is only available to the server's own config");
+    }
+    
+    // Cached configs were already allowed, anything new must be on the allowlist
+    if !allowed_config_paths.is_empty() && !is_allowed_config_path(config_path, allowed_config_paths) {
+        anyhow::bail!("Config path is not in allowed_config_paths: {}", config_path.display());
+    }
+    
+    // Load configuration
+    log_message(&format!("Loading configuration from: {}", config_path.display()));
+    let config = build_config(config_path)?;
+
+    // Extract general config
+    let general_config = load_general_config(&config)?;
+    
+    // Cache the config
+    cache.insert(config_path.clone(), general_config.clone());
+    
+    Ok(general_config)
+}
+
+// Compare resolved paths so `..` segments and symlinks can't escape the allowlist
+fn is_allowed_config_path(config_path: &Path, allowed_config_paths: &[PathBuf]) -> bool {
+    let Ok(config_path) = std::fs::canonicalize(config_path) else {
+        return false;
+    };
+    allowed_config_paths
+        .iter()
+        .filter_map(|allowed| std::fs::canonicalize(allowed).ok())
+        .any(|allowed| allowed == config_path)
+}
+
+// Everything a config reload replaces, and what it needs to rebuild the provider
+struct ConfigReloader {
+    args: Args,
+    provider: Arc<ReloadableProvider>,
+    // Name of the draft provider the server started with, if any
+    draft_provider_name: String,
+    config_cache: Arc<Mutex<HashMap<PathBuf, GeneralConfig>>>,
+    http_client: Client,
+    // Resolved config in effect, to log what a reload changes. Held for the whole
+    // reload, so two reloads never interleave.
+    resolved: Mutex<serde_json::Value>,
+}
+
+impl ConfigReloader {
+    // Re-read the server's config file, swap in a provider built from it and forget every
+    // cached request config, returning the fields that changed
+    async fn reload(&self) -> Result<Vec<String>> {
+        let mut resolved = self.resolved.lock().await;
+        if self.args.config == Path::new("-") {
+            anyhow::bail!("Configuration read from stdin can't be reloaded");
+        }
+        
+        let config = build_config(&self.args.config)?;
+        let mut general_config = load_general_config(&config)?;
+        apply_args(&mut general_config, &self.args);
+        general_config.validate()?;
+        let name = provider_name(&config, &general_config)?;
+        if let Some(current) = PROVIDER_NAME.get()
+            && current != name
+        {
+            anyhow::bail!("Switching the provider from {} to {} needs a restart", current, name);
+        }
+        if general_config.draft_provider != self.draft_provider_name {
+            anyhow::bail!(
+                "Changing the draft provider from \"{}\" to \"{}\" needs a restart",
+                self.draft_provider_name, general_config.draft_provider
+            );
+        }
+        let provider = create_provider(name, &config, &general_config, self.http_client.clone()).await?;
+        let draft_provider = match DRAFT_PROVIDER.get() {
+            Some(_) => Some(create_provider(&general_config.draft_provider, &config, &general_config, self.http_client.clone()).await?),
+            None => None,
+        };
+        
+        let reloaded = resolved_config(&config, general_config.clone())?;
+        let mut changes = Vec::new();
+        diff_config("", &resolved, &reloaded, &mut changes);
+        
+        self.provider.replace(provider);
+        if let (Some(draft), Some(draft_provider)) = (DRAFT_PROVIDER.get(), draft_provider) {
+            draft.replace(draft_provider);
+        }
+        set_log_level(general_config.log_level);
+        {
+            let mut config_cache = self.config_cache.lock().await;
+            config_cache.clear();
+            config_cache.insert(self.args.config.clone(), general_config);
+        }
+        *resolved = reloaded;
+        Ok(changes)
+    }
+}
+
+// Reload the server's config, logging what changed
+async fn reload_config() -> Result<Vec<String>> {
+    let reloader = CONFIG_RELOADER.get().context("Config reloading is not available")?;
+    log_message(&format!("Reloading configuration from: {}", reloader.args.config.display()));
+    match reloader.reload().await {
+        Ok(changes) => {
+            log_message(&format!("Configuration reloaded, {} fields changed", changes.len()));
+            for change in &changes {
+                log_message(&format!("  {}", change));
+            }
+            Ok(changes)
+        }
+        Err(e) => {
+            log_level(LogLevel::Error, &format!("Failed to reload configuration, keeping the old one: {}", e));
+            Err(e)
+        }
+    }
+}
+
+// Collect "field: old -> new" for every value that differs between two resolved configs.
+// Credentials are only reported as changed, without their values.
+fn diff_config(path: &str, old: &serde_json::Value, new: &serde_json::Value, changes: &mut Vec<String>) {
+    use serde_json::Value;
+    match (old, new) {
+        (Value::Object(old_fields), Value::Object(new_fields)) => {
+            let keys: std::collections::BTreeSet<&String> = old_fields.keys().chain(new_fields.keys()).collect();
+            for key in keys {
+                let field = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                diff_config(
+                    &field,
+                    old_fields.get(key).unwrap_or(&Value::Null),
+                    new_fields.get(key).unwrap_or(&Value::Null),
+                    changes,
+                );
+            }
+        }
+        _ if old != new => {
+            let field = path.rsplit('.').next().unwrap_or(path);
+            if is_secret_field(field) {
+                changes.push(format!("{}: changed", path));
+            } else {
+                changes.push(format!("{}: {} -> {}", path, old, new));
             }
-        },
-        Err(_) => {
-            log_message("Timeout while reading request data");
-            return Err(anyhow::anyhow!("Timeout while reading request data"));
         }
+        _ => {}
     }
-    
-    // Deserialize request
-    let request: VoiceRequest = match serde_json::from_slice(&request_data) {
-        Ok(req) => req,
+}
+
+// Re-read the config whenever the process gets SIGHUP
+#[cfg(unix)]
+fn spawn_reload_on_sighup() {
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(signal) => signal,
         Err(e) => {
-            log_message(&format!("Error deserializing request: {}", e));
-            return Err(anyhow::anyhow!("Failed to deserialize request"));
+            log_level(LogLevel::Warn, &format!("Failed to listen for SIGHUP: {}", e));
+            return;
         }
     };
-    
-    log_message(&format!("Received request for text: {}", request.text));
-    
-    // Acquire a permit from the semaphore to limit concurrent voice generations
-    let _permit = semaphore.acquire().await?;
-    
-    // Load config if not already cached
-    let general_config = load_or_get_config(&config_cache, &request.config_path).await?;
-    
-    // Calculate a unique identifier for the text
-    let voice_filename = generate_cache_filename(&request.text);
-    
-    // Process the request in a separate task
     tokio::spawn(async move {
-        if let Err(e) = process_voice_request(
-            provider,
-            &general_config,
-            request.text,
-            request.cache_dir,
-            &voice_filename,
-            voice_manager,
-        ).await {
-            log_message(&format!("Error processing voice request: {}", e));
+        while hangup.recv().await.is_some() {
+            log_message("Received SIGHUP");
+            let _ = reload_config().await;
         }
     });
-    
-    // We don't need to send a response since the client is likely already gone
-    
-    Ok(())
 }
 
-// Function to process a voice request
-async fn process_voice_request(
-    provider: Arc<dyn TtsProvider>,
-    general_config: &GeneralConfig,
-    text: String,
-    cache_dir: Option<PathBuf>,
-    voice_filename: &str,
-    voice_manager: Arc<Mutex<VoiceManager>>,
-) -> Result<()> {
-    // Use cache directory from config if not provided in request
-    let cache_dir = if let Some(ref dir) = cache_dir {
-        dir.clone()
-    } else if !general_config.cache_dir.is_empty() {
-        PathBuf::from(&general_config.cache_dir)
+// HTTP client for the providers, following redirects only when follow_redirects is set
+fn build_http_client(general_config: &GeneralConfig) -> Result<Client> {
+    let redirect_policy = if general_config.follow_redirects {
+        reqwest::redirect::Policy::limited(10)
     } else {
-        return Err(anyhow::anyhow!("No cache directory specified"));
+        reqwest::redirect::Policy::none()
     };
-    
-    // Create cache directory if it doesn't exist
-    fs::create_dir_all(&cache_dir)
-        .await
-        .context("Failed to create cache directory")?;
+    Client::builder()
+        .redirect(redirect_policy)
+        .build()
+        .context("Failed to build HTTP client")
+}
 
-    let cached_path = cache_dir.join(voice_filename);
+// Which provider to run: general.provider, else tts.engine, or remote when [tts_remote] is present
+fn provider_name<'a>(config: &Config, general_config: &'a GeneralConfig) -> Result<&'a str> {
+    match general_config.provider.as_str() {
+        "" => match config.get_string("tts.engine") {
+            Ok(engine) => match engine.as_str() {
+                "voicevox" => Ok("voicevox"),
+                "gptsovits" | "gpt_sovits" => Ok("gpt_sovits"),
+                engine => anyhow::bail!("Unknown tts.engine: {} (expected voicevox or gptsovits)", engine),
+            },
+            Err(config::ConfigError::NotFound(_)) => match config.get::<RemoteKrkrConfig>("tts_remote") {
+                Ok(_) => Ok("remote"),
+                Err(config::ConfigError::NotFound(_)) => Ok("gpt_sovits"),
+                Err(e) => Err(e).context("Failed to parse remote krkr-tts configuration"),
+            },
+            Err(e) => Err(e).context("Failed to read tts.engine"),
+        },
+        name @ ("gpt_sovits" | "remote" | "google" | "voicevox") => Ok(name),
+        name => anyhow::bail!("Unknown provider: {} (expected gpt_sovits, remote, google or voicevox)", name),
+    }
+}
 
-    // Check if the requested voice already exists in cache
-    if cached_path.exists() {
-        // The voice exists in cache - client will handle copying it
-        log_message(&format!("Voice exists in cache: {}", cached_path.display()));
-        
-        // Check if we should initiate prefetching
-        if !general_config.text_list_path.is_empty() {
-            let text_list_path = PathBuf::from(&general_config.text_list_path);
-            if text_list_path.exists() {
-                // Start background prefetch task
-                let voice_manager_clone = voice_manager.clone();
-                let prefetch_count = general_config.prefetch_count;
-                let provider_clone = provider.clone();
-                let cache_dir_clone = cache_dir.clone();
-                let text_clone = text.clone();
-                
-                tokio::spawn(async move {
-                    if let Err(e) = try_prefetch_voices(
-                        provider_clone, 
-                        &text_list_path, 
-                        &cache_dir_clone, 
-                        prefetch_count,
-                        &text_clone,
-                        voice_manager_clone,
-                    ).await {
-                        log_message(&format!("Prefetch error: {}", e));
-                    }
-                });
-            }
+// Create the provider with the given name from its config section
+async fn create_provider(
+    name: &str,
+    config: &Config,
+    general_config: &GeneralConfig,
+    http_client: Client,
+) -> Result<Arc<dyn TtsProvider>> {
+    Ok(match name {
+        "remote" => Arc::new(RemoteKrkrProvider::new(load_remote_config(config)?, general_config)?),
+        "google" => Arc::new(GoogleTtsProvider::new(load_google_tts_config(config)?, http_client)?),
+        "voicevox" => Arc::new(VoiceVoxProvider::new(load_voicevox_config(config)?, http_client)),
+        "gpt_sovits" => {
+            let tts_config = load_gpt_sovits_config(config)?;
+            let profiles = load_gpt_sovits_profiles(config, &tts_config)?;
+            let voices = load_gpt_sovits_voices(config)?;
+            let provider = GptSoVitsProvider::new(
+                tts_config,
+                profiles,
+                voices,
+                http_client,
+                general_config,
+            )?;
+            provider.download_remote_refs().await?;
+            Arc::new(provider)
         }
-        
-        return Ok(());
-    }
+        name => anyhow::bail!("Unknown provider: {} (expected gpt_sovits, remote, google or voicevox)", name),
+    })
+}
 
-    // Track this generation in memory
-    let cache_path_str = cache_dir.to_string_lossy().to_string();
-    // Convert voice_filename to a numerical identifier for the in-memory tracking
-    let voice_id = voice_filename.as_bytes().iter().map(|&b| b as usize).sum::<usize>();
-    {
-        let mut manager = voice_manager.lock().await;
-        manager.mark_in_progress(&cache_path_str, voice_id);
-    }
+// Read the [tts_remote] section
+fn load_remote_config(config: &Config) -> Result<RemoteKrkrConfig> {
+    config
+        .get("tts_remote")
+        .context("Failed to parse remote krkr-tts configuration")
+}
 
-    // Generate speech directly to cache file
-    match provider.generate_speech(&text, &cached_path).await {
-        Ok(_) => {
-            log_message(&format!("Successfully generated voice to cache: {}", cached_path.display()));
-            
-            // Mark as completed
-            {
-                let mut manager = voice_manager.lock().await;
-                manager.mark_completed(&cache_path_str, voice_id);
-            }
-            
-            // Check if we should initiate prefetching
-            if !general_config.text_list_path.is_empty() {
-                let text_list_path = PathBuf::from(&general_config.text_list_path);
-                if text_list_path.exists() {
-                    // Start background prefetch task
-                    let voice_manager_clone = voice_manager.clone();
-                    let prefetch_count = general_config.prefetch_count;
-                    let provider_clone = provider.clone();
-                    let cache_dir_clone = cache_dir.clone();
-                    let text_clone = text.clone();
-                    
-                    tokio::spawn(async move {
-                        if let Err(e) = try_prefetch_voices(
-                            provider_clone, 
-                            &text_list_path, 
-                            &cache_dir_clone, 
-                            prefetch_count,
-                            &text_clone,
-                            voice_manager_clone,
-                        ).await {
-                            log_message(&format!("Prefetch error: {}", e));
-                        }
-                    });
-                }
-            }
-        },
-        Err(e) => {
-            // Mark generation as failed
-            {
-                let mut manager = voice_manager.lock().await;
-                manager.mark_completed(&cache_path_str, voice_id);
-            }
-            
-            return Err(e);
+// Read the [tts_voicevox] section, also accepted as [voicevox] (the former wins when both
+// are there), which may be left out to use the defaults
+fn load_voicevox_config(config: &Config) -> Result<VoiceVoxConfig> {
+    for section in ["tts_voicevox", "voicevox"] {
+        match config.get(section) {
+            Ok(voicevox_config) => return Ok(voicevox_config),
+            Err(config::ConfigError::NotFound(_)) => {}
+            Err(e) => return Err(e).context("Failed to parse VOICEVOX configuration"),
         }
     }
-    
-    Ok(())
+    Ok(VoiceVoxConfig::default())
 }
 
-// Function to attempt to prefetch voices from a text list
-async fn try_prefetch_voices(
-    provider: Arc<dyn TtsProvider>,
-    text_list_path: &PathBuf,
-    cache_dir: &PathBuf,
-    prefetch_count: usize,
-    current_text: &str,
-    voice_manager: Arc<Mutex<VoiceManager>>,
-) -> Result<()> {
-    if !text_list_path.exists() {
-        return Ok(());
+// Read the [tts_google] section
+fn load_google_tts_config(config: &Config) -> Result<GoogleTtsConfig> {
+    config
+        .get("tts_google")
+        .context("Failed to parse Google TTS configuration")
+}
+
+// Read the [tts] section and convert it to what the GPT-SoVITS API expects
+fn load_gpt_sovits_config(config: &Config) -> Result<GptSoVitsConfig> {
+    let mut tts_config: GptSoVitsConfig = config
+        .get("tts")
+        .context("Failed to parse GPT-SoVITS configuration")?;
+    
+    // Resolve prompt text from a transcript file if configured
+    tts_config.load_prompt_text_file()?;
+    
+    // Otherwise an empty prompt_text comes from the transcript next to the reference clip
+    if tts_config.prompt_text.is_empty() && !tts_config.load_ref_transcript()? {
+        log_message("prompt_text is empty and no reference transcript was found, sending no prompt text");
     }
     
-    log_message(&format!("Found text list: {}", text_list_path.display()));
+    // Refuse to start on settings every request would trip over, listing them all
+    tts_config.validate("[tts]")?;
     
-    // Find the current text in the list
-    let text_list = {
-        let mut manager = voice_manager.lock().await;
-        let text_list_path_str = text_list_path.to_string_lossy().to_string();
-        manager.get_text_list(&text_list_path_str).await?.clone()
+    // Convert text_split_method from config to API value
+    let api_value = TextSplitMethod::convert(&tts_config.text_split_method)
+        .context("Invalid text split method in config")?;
+    log_level(LogLevel::Debug, &format!("Converting text split method from config: {} to API value: {}", 
+        tts_config.text_split_method, api_value));
+    tts_config.text_split_method = api_value.to_string();
+    
+    Ok(tts_config)
+}
+
+// Overlay each [[profiles]] entry onto the base [tts] config, keyed by its name
+fn load_gpt_sovits_profiles(config: &Config, base: &GptSoVitsConfig) -> Result<HashMap<String, GptSoVitsConfig>> {
+    let entries: Vec<serde_json::Map<String, serde_json::Value>> = match config.get("profiles") {
+        Ok(entries) => entries,
+        Err(config::ConfigError::NotFound(_)) => return Ok(HashMap::new()),
+        Err(e) => return Err(e).context("Failed to parse [[profiles]]"),
     };
     
-    // Find the position of the current text in the list
-    let mut current_position = text_list.len();
-    for (i, text) in text_list.iter().enumerate() {
-        if text == current_text {
-            current_position = i;
-            break;
+    let mut profiles = HashMap::new();
+    for mut entry in entries {
+        let name = match entry.remove("name") {
+            Some(serde_json::Value::String(name)) => name,
+            _ => anyhow::bail!("Every [[profiles]] entry needs a name"),
+        };
+        
+        // The base prompt text belongs to the base reference clip, so a profile with its own
+        // clip but no prompt text of its own takes that clip's transcript if there is one
+        let own_reference = entry.contains_key("ref_audio_path")
+            && !entry.contains_key("prompt_text")
+            && !entry.contains_key("prompt_text_file");
+        
+        let mut merged = serde_json::to_value(base)?;
+        if let Some(fields) = merged.as_object_mut() {
+            fields.extend(entry);
+        }
+        let mut profile: GptSoVitsConfig = serde_json::from_value(merged)
+            .context(format!("Invalid parameters in profile {}", name))?;
+        profile.load_prompt_text_file()?;
+        if own_reference {
+            profile.load_ref_transcript()?;
         }
+        
+        // A bad split method in one preset shouldn't take the server down
+        profile.text_split_method = TextSplitMethod::convert_or(&profile.text_split_method, &base.text_split_method);
+        profile.validate(&format!("[[profiles]] {}", name))?;
+        
+        log_message(&format!("Loaded parameter profile: {}", name));
+        profiles.insert(name, profile);
     }
     
-    // Start prefetching from the next position
-    let start_position = current_position + 1;
-    log_message(&format!("Starting prefetch from position {}", start_position));
+    Ok(profiles)
+}
+
+// Read the [voices.<id>] character voices. One with its own clip but no prompt text
+// takes the transcript next to that clip, as the base prompt belongs to another clip.
+fn load_gpt_sovits_voices(config: &Config) -> Result<HashMap<String, VoiceConfig>> {
+    let mut voices: HashMap<String, VoiceConfig> = match config.get("voices") {
+        Ok(voices) => voices,
+        Err(config::ConfigError::NotFound(_)) => return Ok(HashMap::new()),
+        Err(e) => return Err(e).context("Failed to parse [voices]"),
+    };
     
-    // Prefetch the next specified number of voices
-    if start_position < text_list.len() {
-        prefetch_voices(
-            provider,
-            text_list_path.clone(),
-            cache_dir.clone(),
-            prefetch_count,
-            start_position,
-            voice_manager.clone()
-        ).await?;
-    } else {
-        log_message("No more voices to prefetch (end of text list)");
+    for (voice_id, voice) in &mut voices {
+        if let Some(ref_audio_path) = &voice.ref_audio_path
+            && voice.prompt_text.is_none()
+        {
+            let path = format!("{}.txt", ref_audio_path);
+            if !ref_audio_path.contains("://") && Path::new(&path).exists() {
+                let transcript = std::fs::read_to_string(&path)
+                    .context(format!("Failed to read reference transcript: {}", path))?;
+                voice.prompt_text = Some(transcript.trim().to_string());
+            } else {
+                log_level(LogLevel::Warn, &format!("voice {} has its own ref_audio_path but no prompt_text", voice_id));
+            }
+        }
+        log_message(&format!("Loaded voice: {}", voice_id));
     }
     
-    Ok(())
+    Ok(voices)
 }
 
-// Function to load configurations or retrieve from cache
-async fn load_or_get_config(
-    config_cache: &Arc<Mutex<HashMap<PathBuf, GeneralConfig>>>,
-    config_path: &PathBuf,
-) -> Result<GeneralConfig> {
-    let mut cache = config_cache.lock().await;
-    
-    if let Some(config) = cache.get(config_path) {
-        return Ok(config.clone());
+// Command line overrides that also hold for a reloaded config
+fn apply_args(general_config: &mut GeneralConfig, args: &Args) {
+    if let Some(bind) = &args.bind {
+        general_config.bind_address = bind.clone();
     }
-    
-    // Load configuration
-    log_message(&format!("Loading configuration from: {}", config_path.display()));
-    let config = Config::builder()
-        .add_source(ConfigFile::from(config_path.clone()))
-        .build()
-        .context("Failed to load configuration")?;
+    if args.verbose {
+        general_config.log_level = LogLevel::Debug;
+    }
+}
 
-    // Extract general config
-    let general_config: GeneralConfig = config
-        .get("general")
-        .context("Failed to parse general configuration")?;
-    
-    // Cache the config
-    cache.insert(config_path.clone(), general_config.clone());
+// Print the effective configuration after file, environment and command line layering
+fn print_config(config: &Config, mut general_config: GeneralConfig, args: &Args) -> Result<()> {
+    if let Some(log) = &args.log {
+        general_config.log_file = log.to_string_lossy().to_string();
+    }
+    if let Some(port) = args.port {
+        general_config.server_port = port;
+    }
+    if let Some(concurrency) = args.concurrency {
+        general_config.max_concurrent_tts = concurrency;
+    }
     
-    Ok(general_config)
+    let mut resolved = resolved_config(config, general_config)?;
+    redact_secrets(&mut resolved);
+    println!("{}", serde_json::to_string_pretty(&resolved)?);
+    Ok(())
+}
+
+// Whether a config field holds a credential that must never be printed. Matched by
+// name, so credentials added to a provider section later are covered too.
+fn is_secret_field(name: &str) -> bool {
+    const SECRET_SUFFIXES: &[&str] = &["key", "token", "password", "secret", "credentials"];
+    SECRET_SUFFIXES.iter().any(|suffix| {
+        name == *suffix || name.strip_suffix(suffix).is_some_and(|prefix| prefix.ends_with('_'))
+    })
+}
+
+// Replace the value of every set credential field with a placeholder, at any depth
+fn redact_secrets(value: &mut serde_json::Value) {
+    use serde_json::Value;
+    match value {
+        Value::Object(fields) => {
+            for (key, field) in fields.iter_mut() {
+                if is_secret_field(key) {
+                    if field.as_str().is_some_and(|secret| !secret.is_empty()) {
+                        *field = Value::String("<redacted>".to_string());
+                    }
+                } else {
+                    redact_secrets(field);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_secrets),
+        _ => {}
+    }
+}
+
+// The general settings and the active provider's sections, with defaults filled in
+fn resolved_config(config: &Config, general_config: GeneralConfig) -> Result<serde_json::Value> {
+    Ok(match provider_name(config, &general_config)? {
+        "remote" => serde_json::json!({
+            "general": general_config,
+            "tts_remote": load_remote_config(config)?,
+        }),
+        "google" => serde_json::json!({
+            "general": general_config,
+            "tts_google": load_google_tts_config(config)?,
+        }),
+        "voicevox" => serde_json::json!({
+            "general": general_config,
+            "tts_voicevox": load_voicevox_config(config)?,
+        }),
+        _ => {
+            let tts_config = load_gpt_sovits_config(config)?;
+            let profiles = load_gpt_sovits_profiles(config, &tts_config)?;
+            serde_json::json!({
+                "general": general_config,
+                "tts": tts_config,
+                "profiles": profiles,
+                "voices": load_gpt_sovits_voices(config)?,
+            })
+        }
+    })
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let result = run().await;
+    
+    // Lines still queued for the log file would be lost on exit
+    flush_logger();
+    result
+}
+
+async fn run() -> Result<()> {
     // Parse command line arguments
     let args = Args::parse();
     
     // Load configuration
-    let config = Config::builder()
-        .add_source(ConfigFile::from(args.config.clone()))
-        .build()
-        .context("Failed to load configuration")?;
+    let config = build_config(&args.config)?;
 
     // Read general configuration
-    let general_config: GeneralConfig = config
-        .get("general")
-        .context("Failed to parse general configuration")?;
+    let mut general_config = load_general_config(&config)?;
+    apply_args(&mut general_config, &args);
+    general_config.validate()?;
+    set_log_level(general_config.log_level);
+    
+    // Show what the layered sources resolve to without starting anything
+    if args.print_config {
+        disable_console_logging();
+        return print_config(&config, general_config, &args);
+    }
     
     // Set up logger if specified
     let log_path = args.log.clone().or_else(|| {
@@ -647,79 +3775,377 @@ async fn main() -> Result<()> {
     });
     
     if let Some(log_path) = &log_path {
-        init_logger(log_path)?;
+        init_logger(log_path, general_config.max_log_bytes, general_config.log_keep_files)?;
+    }
+    
+    if !general_config.access_log.is_empty() {
+        access_log::init(Path::new(&general_config.access_log))?;
     }
     
     log_message("Starting krkr-tts server");
     
-    // Initialize TTS provider
-    let tts_config: GptSoVitsConfig = config
-        .get("tts")
-        .context("Failed to parse GPT-SoVITS configuration")?;
+    if transcode::needs_transcoding(&general_config.output_format) {
+        transcode::check_ffmpeg(&general_config.output_format).await?;
+        log_message(&format!("Caching voices as {} through ffmpeg", general_config.output_format));
+    }
     
-    // Convert text_split_method from config to API value
-    let tts_config = {
-        let mut config = tts_config;
-        if let Some(method) = TextSplitMethod::from_api_value(&config.text_split_method) {
-            log_message(&format!("Converting text split method from config: {} to API value: {}", 
-                config.text_split_method, method.to_api_value()));
-            config.text_split_method = method.to_api_value().to_string();
-        } else {
-            log_message("Invalid text split method in config");
-            anyhow::bail!("Invalid text split method in config: {}", config.text_split_method);
-        }
-        config
-    };
+    // Refuse to listen beyond this machine unless the config says so explicitly
+    let bind_ip = bind_ip(&general_config)?;
+    if !bind_ip.is_loopback() && !general_config.allow_public_bind {
+        anyhow::bail!(
+            "bind_address {} is reachable from other machines; set allow_public_bind = true if that is intended",
+            bind_ip
+        );
+    }
+    
+    // Build one HTTP client for all providers so they share a connection pool
+    let http_client = build_http_client(&general_config)?;
     
-    // Create the TTS provider once at startup
-    let provider = Arc::new(GptSoVitsProvider::new(tts_config)) as Arc<dyn TtsProvider>;
+    // Create the TTS provider at startup, replaced in place when the config is reloaded
+    let name = provider_name(&config, &general_config)?;
+    let reloadable_provider = Arc::new(ReloadableProvider::new(
+        create_provider(name, &config, &general_config, http_client.clone()).await?,
+    ));
+    let provider: Arc<dyn TtsProvider> = reloadable_provider.clone();
+    let _ = PROVIDER_NAME.set(name.to_string());
+    
+    // A fast provider answers cache misses first when configured
+    if !general_config.draft_provider.is_empty() {
+        log_message(&format!("Using {} as the draft provider", general_config.draft_provider));
+        let draft_provider = create_provider(&general_config.draft_provider, &config, &general_config, http_client.clone()).await?;
+        let _ = DRAFT_PROVIDER.set(Arc::new(ReloadableProvider::new(draft_provider)));
+    }
     
     // Determine port
     let port = args.port.unwrap_or(general_config.server_port);
-    let address = format!("127.0.0.1:{}", port);
+    let address = SocketAddr::new(bind_ip, port);
     
-    // Create a TCP listener
-    let listener = TcpListener::bind(&address).await
-        .context(format!("Failed to bind to {}", address))?;
+    // Create a config cache to avoid repeatedly parsing config files, seeded with
+    // the server's own config since it can't be re-read when it came from stdin
+    let config_cache = Arc::new(Mutex::new(HashMap::from([
+        (args.config.clone(), general_config.clone()),
+    ])));
     
-    log_message(&format!("Server listening on {}", address));
-    
-    // Create a config cache to avoid repeatedly parsing config files
-    let config_cache = Arc::new(Mutex::new(HashMap::new()));
+    // SIGHUP or a ReloadConfig request re-reads the config without restarting
+    let _ = CONFIG_RELOADER.set(ConfigReloader {
+        args: args.clone(),
+        provider: reloadable_provider,
+        draft_provider_name: general_config.draft_provider.clone(),
+        config_cache: config_cache.clone(),
+        http_client,
+        resolved: Mutex::new(resolved_config(&config, general_config.clone())?),
+    });
+    #[cfg(unix)]
+    spawn_reload_on_sighup();
     
     // Create voice manager
     let voice_manager = Arc::new(Mutex::new(VoiceManager::new()));
     
+    // Read configured text lists now rather than on the first request that needs them
+    for text_list_path in &general_config.preload_text_lists {
+        let mut manager = voice_manager.lock().await;
+        let line_count = manager.get_text_list(text_list_path, &general_config.text_list_encoding).await?.len();
+        log_message(&format!("Preloaded {} lines from text list {}", line_count, text_list_path));
+    }
+    
     // Determine concurrency
     let concurrency = args.concurrency
-        .unwrap_or_else(|| general_config.max_concurrent_tts);
+        .unwrap_or(general_config.max_concurrent_tts);
     
     // Create a semaphore to limit concurrent TTS operations
     let semaphore = Arc::new(Semaphore::new(concurrency));
+    let _ = GENERATION_SEMAPHORE.set(semaphore.clone());
+    general_config.max_concurrent_tts = concurrency;
     
     log_message(&format!("Server configured with concurrency: {}", concurrency));
     
-    // Accept connections
+    if general_config.autoscale_concurrency {
+        autoscale::spawn(semaphore.clone(), concurrency, &general_config);
+    }
+    
+    // Fill the cache from a whole text list instead of serving clients
+    if let Some(text_list_path) = &args.warm {
+        let warm = warm_text_list(provider, text_list_path, &general_config, voice_manager.clone(), semaphore.clone());
+        tokio::pin!(warm);
+        let failed = tokio::select! {
+            failed = &mut warm => failed?,
+            _ = shutdown_signal() => {
+                // Start no more lines and abort the running ones, letting them remove their partial files
+                semaphore.close();
+                {
+                    let mut manager = voice_manager.lock().await;
+                    manager.shutting_down = true;
+                    manager.cancel_all();
+                }
+                let _ = warm.await;
+                anyhow::bail!("Warming interrupted");
+            }
+        };
+        if failed > 0 {
+            anyhow::bail!("{} lines failed to generate", failed);
+        }
+        return Ok(());
+    }
+    
+    // Listen for clients over TCP or the local socket
+    let mut listener = Listener::bind(&general_config, address).await?;
+    
+    // Start the optional HTTP front-end alongside the TCP protocol
+    if let Some(http_port) = general_config.http_port {
+        let state = http_api::HttpState {
+            provider: provider.clone(),
+            config_cache: config_cache.clone(),
+            semaphore: semaphore.clone(),
+            voice_manager: voice_manager.clone(),
+            config_path: args.config.clone(),
+        };
+        let http_address = SocketAddr::new(bind_ip, http_port).to_string();
+        tokio::spawn(async move {
+            if let Err(e) = http_api::serve(&http_address, state).await {
+                log_level(LogLevel::Error, &format!("HTTP server error: {}", e));
+            }
+        });
+    }
+    
+    // Route background generations through a bounded worker pool if configured
+    let work_queue = if general_config.worker_tasks > 0 {
+        log_message(&format!("Starting {} generation workers", general_config.worker_tasks));
+        Some(spawn_worker_pool(general_config.worker_tasks, provider.clone(), voice_manager.clone()))
+    } else {
+        None
+    };
+    
+    // Settings from the server's own config that apply before a request names its config
+    let server_config = Arc::new(general_config);
+    
+    // Accept connections until asked to stop
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
     loop {
-        match listener.accept().await {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => break,
+        };
+        match accepted {
             Ok((socket, addr)) => {
-                log_message(&format!("New connection from: {}", addr));
+                log_level(LogLevel::Debug, &format!("New connection from: {}", addr));
                 
                 let config_cache = config_cache.clone();
                 let semaphore = semaphore.clone();
                 let voice_manager = voice_manager.clone();
                 let provider = provider.clone();
+                let server_config = server_config.clone();
+                let work_queue = work_queue.clone();
                 
                 // Spawn a new task to handle this client
                 tokio::spawn(async move {
-                    if let Err(e) = handle_client(socket, config_cache, provider, semaphore, voice_manager).await {
-                        log_message(&format!("Error handling client {}: {}", addr, e));
+                    if let Err(e) = handle_client(socket, config_cache, provider, semaphore, voice_manager, server_config, work_queue).await {
+                        log_level(LogLevel::Error, &format!("Error handling client {}: {}", addr, e));
                     }
                 });
             }
             Err(e) => {
-                log_message(&format!("Error accepting connection: {}", e));
+                log_level(LogLevel::Error, &format!("Error accepting connection: {}", e));
+            }
+        }
+    }
+    
+    drop(listener);
+    drain_generations(&voice_manager, &semaphore, Duration::from_secs(server_config.shutdown_timeout_secs)).await;
+    log_message("Server stopped");
+    Ok(())
+}
+
+// Resolve once the process is asked to stop: Ctrl+C, or SIGTERM on Unix
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            log_level(LogLevel::Warn, &format!("Failed to listen for Ctrl+C: {}", e));
+            std::future::pending::<()>().await;
+        }
+    };
+    
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                log_level(LogLevel::Warn, &format!("Failed to listen for SIGTERM: {}", e));
+                std::future::pending::<()>().await;
             }
         }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    
+    tokio::select! {
+        _ = ctrl_c => log_message("Received Ctrl+C, shutting down"),
+        _ = terminate => log_message("Received SIGTERM, shutting down"),
+    }
+}
+
+// Time cancelled generations get to remove their partial files before the process exits
+const SHUTDOWN_CANCEL_GRACE: Duration = Duration::from_millis(500);
+
+// Stop new generations and wait up to `timeout` for running ones to finish writing
+// their voices, cancelling whatever is still running after that
+async fn drain_generations(voice_manager: &Arc<Mutex<VoiceManager>>, semaphore: &Semaphore, timeout: Duration) {
+    semaphore.close();
+    let in_flight = {
+        let mut manager = voice_manager.lock().await;
+        manager.shutting_down = true;
+        manager.in_flight_count()
+    };
+    if in_flight == 0 {
+        return;
+    }
+    
+    log_message(&format!(
+        "Waiting up to {}s for {} running generations to finish",
+        timeout.as_secs(), in_flight
+    ));
+    let drained = tokio::time::timeout(timeout, async {
+        while voice_manager.lock().await.in_flight_count() > 0 {
+            sleep(Duration::from_millis(100)).await;
+        }
+    }).await;
+    
+    if drained.is_err() {
+        let cancelled = voice_manager.lock().await.cancel_all();
+        log_message(&format!("Shutdown timeout reached, cancelled {} generations", cancelled));
+        sleep(SHUTDOWN_CANCEL_GRACE).await;
+    }
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line_overrides_inline_config_and_file() {
+        let path = std::env::temp_dir().join(format!("krkr-tts-test-{}-args.toml", std::process::id()));
+        std::fs::write(&path, "[general]\nbind_address = \"127.0.0.2\"\nlog_level = \"warn\"\n").unwrap();
+        let config = build_config_from(
+            &path,
+            Some("[general]\nbind_address = \"127.0.0.3\"\n".to_string()),
+            "".as_bytes(),
+        ).unwrap();
+        let mut general_config = load_general_config(&config).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(general_config.bind_address, "127.0.0.3");
+
+        let args = Args::parse_from(["krkr-tts-server", "--bind", "127.0.0.4", "--verbose"]);
+        apply_args(&mut general_config, &args);
+        assert_eq!(general_config.bind_address, "127.0.0.4");
+        assert_eq!(general_config.log_level, LogLevel::Debug);
+    }
+
+    // Serve a TTS endpoint at /tts that echoes the request body, and redirects to it from
+    // /temporary (307, which keeps the body) and /found (302, which switches to GET)
+    async fn spawn_redirecting_server() -> SocketAddr {
+        use axum::http::{header, StatusCode};
+        use axum::routing::{get, post};
+
+        let app = axum::Router::new()
+            .route("/tts", post(|body: String| async move { body }).get(|| async { "get" }))
+            .route("/temporary", post(|| async { (StatusCode::TEMPORARY_REDIRECT, [(header::LOCATION, "/tts")]) }))
+            .route("/found", post(|| async { (StatusCode::FOUND, [(header::LOCATION, "/tts")]) }))
+            .route("/loop", get(|| async { (StatusCode::FOUND, [(header::LOCATION, "/loop")]) }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        address
+    }
+
+    fn http_client(follow_redirects: bool) -> Client {
+        build_http_client(&GeneralConfig { follow_redirects, ..GeneralConfig::default() }).unwrap()
+    }
+
+    #[tokio::test]
+    async fn follows_redirects_keeping_the_body_on_307() {
+        let address = spawn_redirecting_server().await;
+        let response = http_client(true)
+            .post(format!("http://{}/temporary", address))
+            .body("text")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), "text");
+    }
+
+    #[tokio::test]
+    async fn follows_302_as_get() {
+        let address = spawn_redirecting_server().await;
+        let response = http_client(true)
+            .post(format!("http://{}/found", address))
+            .body("text")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "get");
     }
-} 
\ No newline at end of file
+
+    #[tokio::test]
+    async fn redirect_loops_fail() {
+        let address = spawn_redirecting_server().await;
+        let result = http_client(true).get(format!("http://{}/loop", address)).send().await;
+        assert!(result.is_err_and(|e| e.is_redirect()));
+    }
+
+    #[tokio::test]
+    async fn redirects_are_returned_when_not_followed() {
+        let address = spawn_redirecting_server().await;
+        let response = http_client(false)
+            .post(format!("http://{}/temporary", address))
+            .body("text")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::TEMPORARY_REDIRECT);
+    }
+
+    #[test]
+    fn prefetch_starts_after_the_first_line() {
+        assert_eq!(prefetch_start(Some(0), 3), Some(1));
+    }
+
+    #[test]
+    fn nothing_to_prefetch_after_the_last_line() {
+        assert_eq!(prefetch_start(Some(2), 3), None);
+        assert_eq!(prefetch_start(Some(usize::MAX), 3), None);
+    }
+
+    #[test]
+    fn nothing_to_prefetch_for_a_line_not_in_the_list() {
+        assert_eq!(prefetch_start(None, 3), None);
+    }
+
+    #[test]
+    fn source_wav_is_kept_beside_the_voice() {
+        let dir = Path::new("cache");
+        assert_eq!(kept_source_path(&dir.join("abc.opus")), dir.join("abc.src.wav"));
+        assert_eq!(kept_source_path(&dir.join("abc.opus.upgrade")), dir.join("abc.src.wav"));
+    }
+
+    #[tokio::test]
+    async fn kept_source_wav_is_evicted_with_its_voice() {
+        let cache_dir = std::env::temp_dir().join(format!("krkr-tts-test-{}-evict", std::process::id()));
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        std::fs::write(cache_dir.join("old.opus"), [0; 10]).unwrap();
+        std::fs::write(cache_dir.join("old.src.wav"), [0; 100]).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        std::fs::write(cache_dir.join("new.opus"), [0; 10]).unwrap();
+
+        let voice_manager = Arc::new(Mutex::new(VoiceManager::new()));
+        enforce_cache_limit(cache_dir.clone(), 115, voice_manager, cache_dir.join("new.opus")).await;
+        let remaining = (
+            cache_dir.join("old.opus").exists(),
+            cache_dir.join("old.src.wav").exists(),
+            cache_dir.join("new.opus").exists(),
+        );
+        std::fs::remove_dir_all(&cache_dir).unwrap();
+        assert_eq!(remaining, (false, false, true));
+    }
+}
@@ -0,0 +1,104 @@
+// Runtime statistics for tuning: cache hit rate and generation latency
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Number of recent generations kept for latency percentiles
+const LATENCY_WINDOW: usize = 1000;
+
+lazy_static::lazy_static! {
+    pub static ref STATS: Stats = Stats::new();
+}
+
+pub struct Stats {
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    // Generations timed since startup, so callers can tell which samples are new
+    latencies_recorded: AtomicU64,
+    // Most recent generation latencies in milliseconds, oldest first
+    latencies_ms: Mutex<VecDeque<u64>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatsSnapshot {
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub cache_hit_rate: Option<f64>,
+    pub latency_samples: usize,
+    pub latency_p50_ms: Option<u64>,
+    pub latency_p90_ms: Option<u64>,
+    pub latency_p99_ms: Option<u64>,
+    pub latency_max_ms: Option<u64>,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Self {
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            latencies_recorded: AtomicU64::new(0),
+            latencies_ms: Mutex::new(VecDeque::with_capacity(LATENCY_WINDOW)),
+        }
+    }
+
+    pub fn record_cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_latency(&self, latency: Duration) {
+        let mut latencies = self.latencies_ms.lock().unwrap();
+        if latencies.len() == LATENCY_WINDOW {
+            latencies.pop_front();
+        }
+        latencies.push_back(latency.as_millis() as u64);
+        self.latencies_recorded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn latencies_recorded(&self) -> u64 {
+        self.latencies_recorded.load(Ordering::Relaxed)
+    }
+
+    // p90 over the newest `count` latencies (capped at the window), None without samples
+    pub fn recent_latency_p90(&self, count: usize) -> Option<u64> {
+        let latencies = self.latencies_ms.lock().unwrap();
+        let skip = latencies.len().saturating_sub(count);
+        let mut recent: Vec<u64> = latencies.iter().skip(skip).copied().collect();
+        recent.sort_unstable();
+        percentile(&recent, 90)
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let cache_hits = self.cache_hits.load(Ordering::Relaxed);
+        let cache_misses = self.cache_misses.load(Ordering::Relaxed);
+        let lookups = cache_hits + cache_misses;
+
+        let mut latencies: Vec<u64> = self.latencies_ms.lock().unwrap().iter().copied().collect();
+        latencies.sort_unstable();
+
+        StatsSnapshot {
+            cache_hits,
+            cache_misses,
+            cache_hit_rate: (lookups > 0).then(|| cache_hits as f64 / lookups as f64),
+            latency_samples: latencies.len(),
+            latency_p50_ms: percentile(&latencies, 50),
+            latency_p90_ms: percentile(&latencies, 90),
+            latency_p99_ms: percentile(&latencies, 99),
+            latency_max_ms: latencies.last().copied(),
+        }
+    }
+}
+
+// Nearest-rank percentile of sorted samples
+fn percentile(sorted: &[u64], p: usize) -> Option<u64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p * sorted.len()).div_ceil(100).max(1);
+    sorted.get(rank - 1).copied()
+}
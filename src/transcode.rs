@@ -0,0 +1,63 @@
+// Compressed cache formats: generated WAVs re-encoded by an external ffmpeg
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::process::Stdio;
+use tokio::process::Command;
+
+// Encoder arguments for each format besides WAV, which needs no transcoding
+fn encoder_args(format: &str) -> Option<&'static [&'static str]> {
+    match format {
+        "ogg" => Some(&["-c:a", "libvorbis", "-q:a", "4", "-f", "ogg"]),
+        "opus" => Some(&["-c:a", "libopus", "-b:a", "64k", "-f", "opus"]),
+        "mp3" => Some(&["-c:a", "libmp3lame", "-q:a", "4", "-f", "mp3"]),
+        _ => None,
+    }
+}
+
+// Whether voices in this output_format have to go through ffmpeg
+pub fn needs_transcoding(format: &str) -> bool {
+    encoder_args(format).is_some()
+}
+
+// Make sure ffmpeg can be run, so a compressed output_format fails at startup rather than per line
+pub async fn check_ffmpeg(format: &str) -> Result<()> {
+    let status = Command::new("ffmpeg")
+        .arg("-version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await;
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        _ => anyhow::bail!(
+            "output_format = \"{}\" needs ffmpeg, which was not found on PATH; install it or set output_format = \"wav\"",
+            format
+        ),
+    }
+}
+
+// Encode `source` (any audio ffmpeg can read) into `destination` in the given format
+pub async fn transcode(source: &Path, destination: &Path, format: &str) -> Result<()> {
+    let encoder_args = encoder_args(format)
+        .context(format!("No transcoding needed for output_format {}", format))?;
+
+    let output = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
+        .arg(source)
+        .args(encoder_args)
+        .arg(destination)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .context("Failed to run ffmpeg (is it installed?)")?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "ffmpeg failed to convert {} to {}: {}",
+            source.display(),
+            format,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
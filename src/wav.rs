@@ -0,0 +1,257 @@
+// Minimal WAV helpers for audio the server produces itself
+
+// Sample rate of generated placeholder audio (GPT-SoVITS v2 outputs 32kHz)
+const PLACEHOLDER_SAMPLE_RATE: u32 = 32000;
+
+// Build a canonical 44-byte PCM WAV header
+pub fn pcm_header(channels: u16, sample_rate: u32, bits_per_sample: u16, data_len: u32) -> Vec<u8> {
+    let block_align = channels * bits_per_sample / 8;
+    let byte_rate = sample_rate * block_align as u32;
+
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(36 + data_len).to_le_bytes());
+    header.extend_from_slice(b"WAVE");
+    header.extend_from_slice(b"fmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&channels.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&byte_rate.to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&bits_per_sample.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_len.to_le_bytes());
+    header
+}
+
+// Build a mono 16-bit WAV containing `duration_ms` of silence
+pub fn silent_wav(duration_ms: u64) -> Vec<u8> {
+    let samples = PLACEHOLDER_SAMPLE_RATE as u64 * duration_ms / 1000;
+    let data_len = (samples * 2) as u32;
+
+    let mut wav = pcm_header(1, PLACEHOLDER_SAMPLE_RATE, 16, data_len);
+    wav.resize(wav.len() + data_len as usize, 0);
+    wav
+}
+
+// Number of energy buckets in an audio fingerprint
+const FINGERPRINT_BUCKETS: usize = 32;
+
+// Cheap perceptual fingerprint of a 16-bit PCM WAV: its length plus a coarse energy envelope
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fingerprint {
+    samples: usize,
+    // Per-bucket RMS energy scaled to 0..=15 relative to the loudest bucket
+    envelope: Vec<u8>,
+}
+
+impl Fingerprint {
+    // Same length within 2% and no envelope bucket differing by more than one step
+    pub fn is_near(&self, other: &Fingerprint) -> bool {
+        let longer = self.samples.max(other.samples);
+        let shorter = self.samples.min(other.samples);
+        shorter * 50 >= longer * 49
+            && self.envelope.len() == other.envelope.len()
+            && self.envelope.iter().zip(&other.envelope).all(|(a, b)| a.abs_diff(*b) <= 1)
+    }
+}
+
+// Sample format of a PCM WAV
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PcmFormat {
+    channels: u16,
+    sample_rate: u32,
+    bits_per_sample: u16,
+}
+
+// Walk a RIFF/WAVE file's chunks for its sample format and audio data
+fn parse_pcm(wav: &[u8]) -> Option<(PcmFormat, &[u8])> {
+    if wav.len() < 12 || &wav[0..4] != b"RIFF" || &wav[8..12] != b"WAVE" {
+        return None;
+    }
+
+    let mut format = None;
+    let mut offset = 12;
+    while offset + 8 <= wav.len() {
+        let id = &wav[offset..offset + 4];
+        let len = u32::from_le_bytes(wav[offset + 4..offset + 8].try_into().ok()?) as usize;
+        let body = &wav[offset + 8..];
+        // Streamed WAVs may declare a bogus length, so clamp to what's there
+        let body = &body[..len.min(body.len())];
+        match id {
+            b"fmt " if body.len() >= 16 => {
+                format = Some(PcmFormat {
+                    channels: u16::from_le_bytes([body[2], body[3]]),
+                    sample_rate: u32::from_le_bytes([body[4], body[5], body[6], body[7]]),
+                    bits_per_sample: u16::from_le_bytes([body[14], body[15]]),
+                });
+            }
+            b"data" => return Some((format?, body)),
+            _ => {}
+        }
+        offset += 8 + len + len % 2;
+    }
+    None
+}
+
+// Join WAVs of the same format into one, with `gap_ms` of silence between each
+pub fn concat(wavs: &[Vec<u8>], gap_ms: u64) -> anyhow::Result<Vec<u8>> {
+    let mut format = None;
+    let mut data = Vec::new();
+    for (index, wav) in wavs.iter().enumerate() {
+        let (piece_format, piece_data) = parse_pcm(wav)
+            .ok_or_else(|| anyhow::anyhow!("Piece {} is not a PCM WAV", index + 1))?;
+        let format = *format.get_or_insert(piece_format);
+        if piece_format != format {
+            anyhow::bail!("Piece {} has a different sample format than the first", index + 1);
+        }
+
+        if index > 0 {
+            let block_align = (format.channels * format.bits_per_sample / 8) as u64;
+            let gap_len = format.sample_rate as u64 * gap_ms / 1000 * block_align;
+            data.resize(data.len() + gap_len as usize, 0);
+        }
+        data.extend_from_slice(piece_data);
+    }
+
+    let format = format.ok_or_else(|| anyhow::anyhow!("Nothing to join"))?;
+    let mut joined = pcm_header(format.channels, format.sample_rate, format.bits_per_sample, data.len() as u32);
+    joined.extend_from_slice(&data);
+    Ok(joined)
+}
+
+// Convert a 16-bit PCM WAV between mono and stereo, averaging the channels when downmixing
+// and duplicating the one channel when upmixing; None if it already has `channels`
+pub fn convert_channels(wav: &[u8], channels: u16) -> anyhow::Result<Option<Vec<u8>>> {
+    let (format, data) = parse_pcm(wav).ok_or_else(|| anyhow::anyhow!("Not a PCM WAV"))?;
+    if format.channels == channels {
+        return Ok(None);
+    }
+    if format.bits_per_sample != 16 {
+        anyhow::bail!("Only 16-bit WAVs can change channels, got {}-bit", format.bits_per_sample);
+    }
+
+    let samples = data
+        .chunks_exact(2)
+        .map(|sample| i16::from_le_bytes([sample[0], sample[1]]));
+    let converted: Vec<u8> = match (format.channels, channels) {
+        (2, 1) => samples
+            .collect::<Vec<_>>()
+            .chunks_exact(2)
+            .flat_map(|frame| (((frame[0] as i32 + frame[1] as i32) / 2) as i16).to_le_bytes())
+            .collect(),
+        (1, 2) => samples
+            .flat_map(|sample| {
+                let bytes = sample.to_le_bytes();
+                [bytes[0], bytes[1], bytes[0], bytes[1]]
+            })
+            .collect(),
+        (from, to) => anyhow::bail!("Can't convert {} channels to {}", from, to),
+    };
+
+    let mut wav = pcm_header(channels, format.sample_rate, 16, converted.len() as u32);
+    wav.extend_from_slice(&converted);
+    Ok(Some(wav))
+}
+
+// Cut a PCM WAV to start `start_ms` in, on the first whole frame at or after that time,
+// or None if it isn't a PCM WAV. Starting past the end leaves no audio.
+pub fn seek(wav: &[u8], start_ms: u64) -> Option<Vec<u8>> {
+    let (format, data) = parse_pcm(wav)?;
+    let block_align = (format.channels * format.bits_per_sample / 8).max(1) as u64;
+    let start_frame = (format.sample_rate as u64 * start_ms).div_ceil(1000);
+    let offset = start_frame.saturating_mul(block_align).min(data.len() as u64) as usize;
+    let data = &data[offset..];
+
+    let mut seeked = pcm_header(format.channels, format.sample_rate, format.bits_per_sample, data.len() as u32);
+    seeked.extend_from_slice(data);
+    Some(seeked)
+}
+
+// Rebuild the header of a PCM WAV cut off mid-stream around the whole frames that arrived,
+// or None if it isn't a PCM WAV
+pub fn finalize_partial(wav: &[u8]) -> Option<Vec<u8>> {
+    let (format, data) = parse_pcm(wav)?;
+    let block_align = (format.channels * format.bits_per_sample / 8).max(1) as usize;
+    let data = &data[..data.len() - data.len() % block_align];
+
+    let mut finalized = pcm_header(format.channels, format.sample_rate, format.bits_per_sample, data.len() as u32);
+    finalized.extend_from_slice(data);
+    Some(finalized)
+}
+
+// Fingerprint a WAV file's bytes, or None if it isn't 16-bit PCM with enough audio
+pub fn fingerprint(wav: &[u8]) -> Option<Fingerprint> {
+    let (format, data) = parse_pcm(wav)?;
+    if format.bits_per_sample != 16 {
+        return None;
+    }
+    let samples: Vec<i16> = data
+        .chunks_exact(2)
+        .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+        .collect();
+    if samples.len() < FINGERPRINT_BUCKETS {
+        return None;
+    }
+
+    let bucket_len = samples.len() / FINGERPRINT_BUCKETS;
+    let energies: Vec<f64> = samples
+        .chunks(bucket_len)
+        .take(FINGERPRINT_BUCKETS)
+        .map(|bucket| {
+            let sum: f64 = bucket.iter().map(|&s| (s as f64) * (s as f64)).sum();
+            (sum / bucket.len() as f64).sqrt()
+        })
+        .collect();
+    let loudest = energies.iter().cloned().fold(0.0, f64::max).max(1.0);
+
+    Some(Fingerprint {
+        samples: samples.len(),
+        envelope: energies.iter().map(|energy| (energy / loudest * 15.0).round() as u8).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 16-bit PCM WAV holding `samples`, interleaved when there are several channels
+    fn wav_of(channels: u16, samples: &[i16]) -> Vec<u8> {
+        let data: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        let mut wav = pcm_header(channels, 16000, 16, data.len() as u32);
+        wav.extend_from_slice(&data);
+        wav
+    }
+
+    #[test]
+    fn stereo_to_mono_averages_the_channels() {
+        let stereo = wav_of(2, &[100, 300, -100, -300, i16::MAX, i16::MAX, i16::MIN, i16::MAX]);
+        let mono = convert_channels(&stereo, 1).unwrap().unwrap();
+        assert_eq!(mono, wav_of(1, &[200, -200, i16::MAX, 0]));
+
+        let (format, _) = parse_pcm(&mono).unwrap();
+        assert_eq!(format, PcmFormat { channels: 1, sample_rate: 16000, bits_per_sample: 16 });
+    }
+
+    #[test]
+    fn mono_to_stereo_duplicates_the_channel() {
+        let mono = wav_of(1, &[1, -2, 3]);
+        let stereo = convert_channels(&mono, 2).unwrap().unwrap();
+        assert_eq!(stereo, wav_of(2, &[1, 1, -2, -2, 3, 3]));
+    }
+
+    #[test]
+    fn matching_channels_need_no_conversion() {
+        assert!(convert_channels(&wav_of(1, &[1, 2]), 1).unwrap().is_none());
+        assert!(convert_channels(&wav_of(2, &[1, 2]), 2).unwrap().is_none());
+    }
+
+    #[test]
+    fn only_16_bit_pcm_is_converted() {
+        let mut eight_bit = pcm_header(1, 16000, 8, 2);
+        eight_bit.extend_from_slice(&[128, 128]);
+        assert!(convert_channels(&eight_bit, 2).is_err());
+        assert!(convert_channels(b"not a wav", 2).is_err());
+    }
+}